@@ -0,0 +1,237 @@
+use crate::cpu::MEMORY_SIZE;
+use std::collections::HashMap;
+
+// Assembler for the mnemonics the disassembler prints, so its listings can be
+// assembled again. Numbers are hex like in disassembly, with an optional 0x or
+// $ prefix. Labels end with ':' and can be used wherever an address or byte is
+// expected, ';' starts a comment.
+//
+//   start:
+//       LD V0, 5        ; x
+//       LD I, sprite
+//       DRW V0, V1, 5
+//       JP start
+//   sprite:
+//       DB F0, 90, 90, 90, F0
+
+// Binary for source, labels count from address
+pub fn assemble(source: &str, address: u16) -> Result<Vec<u8>, String> {
+    let lines = parse_lines(source)?;
+
+    // First pass finds label addresses
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut offset = address as usize;
+    for line in &lines {
+        if let Some(label) = &line.label {
+            if labels.insert(label.to_lowercase(), offset as u16).is_some() {
+                return Err(format!("line {}: label '{}' defined twice", line.number, label));
+            }
+        }
+        offset += line.size();
+    }
+    if offset > MEMORY_SIZE {
+        return Err(format!("Program ends at {:X}, past the end of memory", offset));
+    }
+
+    let mut binary = Vec::new();
+    for line in &lines {
+        let bytes = encode(line, &labels).map_err(|e| format!("line {}: {}", line.number, e))?;
+        binary.extend(bytes);
+    }
+    return Ok(binary);
+}
+
+struct Line {
+    number: usize,
+    label: Option<String>,
+    mnemonic: Option<String>,
+    operands: Vec<String>,
+}
+
+impl Line {
+    fn size(&self) -> usize {
+        match self.mnemonic.as_deref() {
+            None => 0,
+            Some("DB") => self.operands.len(),
+            Some(_) => 2,
+        }
+    }
+}
+
+fn parse_lines(source: &str) -> Result<Vec<Line>, String> {
+    let mut lines = Vec::new();
+    for (idx, text) in source.lines().enumerate() {
+        let mut text = text.split(';').next().unwrap_or("").trim();
+        let mut label = None;
+        if let Some((name, rest)) = text.split_once(':') {
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                return Err(format!("line {}: invalid label '{}'", idx + 1, name));
+            }
+            label = Some(name.to_string());
+            text = rest.trim();
+        }
+        let (mnemonic, operands) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic, operands.split(',').map(|o| o.trim().to_uppercase()).collect()),
+            None => (text, Vec::new()),
+        };
+        lines.push(Line {
+            number: idx + 1,
+            label,
+            mnemonic: Some(mnemonic.to_uppercase()).filter(|m| !m.is_empty()),
+            operands,
+        });
+    }
+    return Ok(lines);
+}
+
+enum Operand {
+    Register(u16),
+    Value(u16),
+    Name(&'static str), // I, [I], DT, ST, K, F, B, AUDIO, PITCH
+}
+
+fn operand(text: &str, labels: &HashMap<String, u16>) -> Result<Operand, String> {
+    const NAMES: [&str; 9] = ["I", "[I]", "DT", "ST", "K", "F", "B", "AUDIO", "PITCH"];
+    if let Some(name) = NAMES.iter().find(|name| **name == text) {
+        return Ok(Operand::Name(name));
+    }
+    if let Some(reg) = text.strip_prefix('V').filter(|r| r.len() == 1) {
+        if let Ok(reg) = u16::from_str_radix(reg, 16) {
+            return Ok(Operand::Register(reg));
+        }
+    }
+    if let Some(address) = labels.get(&text.to_lowercase()) {
+        return Ok(Operand::Value(*address));
+    }
+    let digits = text.trim_start_matches("0X").trim_start_matches('$');
+    return u16::from_str_radix(digits, 16).map(Operand::Value).map_err(|_| format!("unknown operand '{}'", text));
+}
+
+fn encode(line: &Line, labels: &HashMap<String, u16>) -> Result<Vec<u8>, String> {
+    let mnemonic = match &line.mnemonic {
+        Some(mnemonic) => mnemonic.as_str(),
+        None => return Ok(Vec::new()),
+    };
+    let operands = line.operands.iter().map(|o| operand(o, labels)).collect::<Result<Vec<Operand>, String>>()?;
+    if mnemonic == "DB" {
+        return operands.iter().map(|o| match o {
+            Operand::Value(n) if *n <= 0xff => Ok(*n as u8),
+            Operand::Value(n) => Err(format!("{:X} does not fit in a byte", n)),
+            _ => Err("DB takes byte values".to_string()),
+        }).collect();
+    }
+
+    if let Some(ins) = instruction(mnemonic, &operands)? {
+        return Ok(vec![(ins >> 8) as u8, ins as u8]);
+    }
+    // B and F are also hex digits, as in DRW V0, V1, F
+    let operands: Vec<Operand> = operands.into_iter().map(|o| match o {
+        Operand::Name("B") => Operand::Value(0xB),
+        Operand::Name("F") => Operand::Value(0xF),
+        o => o,
+    }).collect();
+    if let Some(ins) = instruction(mnemonic, &operands)? {
+        return Ok(vec![(ins >> 8) as u8, ins as u8]);
+    }
+    return Err(format!("invalid instruction '{} {}'", mnemonic, line.operands.join(", ")).trim_end().to_string());
+}
+
+// Opcode for mnemonic and operands, None when no form matches
+fn instruction(mnemonic: &str, operands: &[Operand]) -> Result<Option<u16>, String> {
+    let value = |n: u16, max: u16| if n <= max { Ok(n) } else { Err(format!("{:X} does not fit in {} bits", n, 16 - max.leading_zeros())) };
+    use Operand::*;
+    let ins = match (mnemonic, operands) {
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("SYS", [Value(n)]) => value(*n, 0xfff)?,
+        ("JP", [Value(n)]) => 0x1000 | value(*n, 0xfff)?,
+        ("JP", [Register(0), Value(n)]) => 0xB000 | value(*n, 0xfff)?,
+        ("CALL", [Value(n)]) => 0x2000 | value(*n, 0xfff)?,
+        ("SE", [Register(x), Value(n)]) => 0x3000 | x << 8 | value(*n, 0xff)?,
+        ("SNE", [Register(x), Value(n)]) => 0x4000 | x << 8 | value(*n, 0xff)?,
+        ("SE", [Register(x), Register(y)]) => 0x5000 | x << 8 | y << 4,
+        ("LD", [Register(x), Value(n)]) => 0x6000 | x << 8 | value(*n, 0xff)?,
+        ("ADD", [Register(x), Value(n)]) => 0x7000 | x << 8 | value(*n, 0xff)?,
+        ("LD", [Register(x), Register(y)]) => 0x8000 | x << 8 | y << 4,
+        ("OR", [Register(x), Register(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [Register(x), Register(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [Register(x), Register(y)]) => 0x8003 | x << 8 | y << 4,
+        ("ADD", [Register(x), Register(y)]) => 0x8004 | x << 8 | y << 4,
+        ("SUB", [Register(x), Register(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SHR", [Register(x), Register(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SHR", [Register(x)]) => 0x8006 | x << 8 | x << 4,
+        ("SUBN", [Register(x), Register(y)]) => 0x8007 | x << 8 | y << 4,
+        ("SHL", [Register(x), Register(y)]) => 0x800E | x << 8 | y << 4,
+        ("SHL", [Register(x)]) => 0x800E | x << 8 | x << 4,
+        ("SNE", [Register(x), Register(y)]) => 0x9000 | x << 8 | y << 4,
+        ("LD", [Name("I"), Value(n)]) => 0xA000 | value(*n, 0xfff)?,
+        ("RND", [Register(x), Value(n)]) => 0xC000 | x << 8 | value(*n, 0xff)?,
+        ("DRW", [Register(x), Register(y), Value(n)]) => 0xD000 | x << 8 | y << 4 | value(*n, 0xf)?,
+        ("SKP", [Register(x)]) => 0xE09E | x << 8,
+        ("SKNP", [Register(x)]) => 0xE0A1 | x << 8,
+        ("LD", [Name("AUDIO"), Name("[I]")]) => 0xF002,
+        ("LD", [Register(x), Name("DT")]) => 0xF007 | x << 8,
+        ("LD", [Register(x), Name("K")]) => 0xF00A | x << 8,
+        ("LD", [Name("DT"), Register(x)]) => 0xF015 | x << 8,
+        ("LD", [Name("ST"), Register(x)]) => 0xF018 | x << 8,
+        ("ADD", [Name("I"), Register(x)]) => 0xF01E | x << 8,
+        ("LD", [Name("F"), Register(x)]) => 0xF029 | x << 8,
+        ("LD", [Name("B"), Register(x)]) => 0xF033 | x << 8,
+        ("LD", [Name("PITCH"), Register(x)]) => 0xF03A | x << 8,
+        ("LD", [Name("[I]"), Register(x)]) => 0xF055 | x << 8,
+        ("LD", [Register(x), Name("[I]")]) => 0xF065 | x << 8,
+        _ => return Ok(None),
+    };
+    return Ok(Some(ins));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler;
+
+    #[test]
+    fn every_instruction_round_trips() {
+        for ins in 0..=0xffffu16 {
+            let text = disassembler::decode(ins);
+            let binary = assemble(&text, 0x200).unwrap_or_else(|e| panic!("{:04X} {}: {}", ins, text, e));
+            // The disassembly of 5XYN and 9XYN leaves out N
+            if matches!(ins >> 12, 0x5 | 0x9) && ins & 0xf != 0 {
+                assert_ne!(binary, ins.to_be_bytes(), "{}", text);
+            } else {
+                assert_eq!(binary, ins.to_be_bytes(), "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn listing_with_labels() {
+        let source = "
+            start:
+                LD V0, B        ; B and F are numbers here
+                LD B, V0        ; and names here
+                LD F, V1
+                LD I, sprite
+                DRW V0, V1, F
+                JP V0, start
+                CALL sprite
+                JP start
+            sprite:
+                DB F0, 90, $FF, 0x1
+        ";
+        assert_eq!(assemble(source, 0x200).unwrap(), vec![
+            0x60, 0x0B, 0xF0, 0x33, 0xF1, 0x29, 0xA2, 0x10, 0xD0, 0x1F, 0xB2, 0x00, 0x22, 0x10, 0x12, 0x00,
+            0xF0, 0x90, 0xFF, 0x01,
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(assemble("JP nowhere", 0x200).unwrap_err().contains("line 1: unknown operand 'NOWHERE'"));
+        assert!(assemble("a:\na: CLS", 0x200).unwrap_err().contains("line 2: label 'a' defined twice"));
+        assert!(assemble("LD V0, 100", 0x200).unwrap_err().contains("does not fit"));
+        assert!(assemble("DB 1, 2", 0xFFF).unwrap_err().contains("past the end of memory"));
+        assert!(assemble("DB 1", 0xFFF).is_ok());
+    }
+}
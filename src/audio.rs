@@ -0,0 +1,402 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::process::{Child, Command, Stdio};
+
+// Sound output. The beeper is sampled once per timer tick while the sound
+// timer runs and the samples go to every sink: an audio player process, a WAV
+// file recording or nothing at all.
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+pub trait AudioSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+
+    fn finish(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+// 16 bit mono PCM file, sizes in the header are filled in by finish
+pub struct WavSink {
+    writer: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavSink {
+    pub fn create(path: &str) -> io::Result<WavSink> {
+        let mut sink = WavSink { writer: BufWriter::new(File::create(path)?), samples: 0 };
+        sink.write_header()?;
+        return Ok(sink);
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_size = self.samples * 2;
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(36 + data_size).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?; // Format chunk size
+        w.write_all(&1u16.to_le_bytes())?; // PCM
+        w.write_all(&1u16.to_le_bytes())?; // Channels
+        w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        w.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?; // Bytes per second
+        w.write_all(&2u16.to_le_bytes())?; // Bytes per frame
+        w.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        w.write_all(b"data")?;
+        w.write_all(&data_size.to_le_bytes())?;
+        return Ok(());
+    }
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        return Ok(());
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        return self.writer.flush();
+    }
+}
+
+// Raw samples piped to the first audio player found
+pub struct PlayerSink {
+    child: Child,
+}
+
+// Players reading signed 16 bit little endian mono from stdin
+const PLAYERS: [(&str, &[&str]); 2] = [
+    ("pacat", &["--playback", "--raw", "--format=s16le", "--channels=1", "--rate=44100", "--latency-msec=50"]),
+    ("aplay", &["-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-r", "44100"]),
+];
+
+impl PlayerSink {
+    pub fn open() -> Option<PlayerSink> {
+        for (program, args) in PLAYERS {
+            let child = Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+            if let Ok(mut child) = child {
+                // Exits right away when there is no device
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                if let Ok(None) = child.try_wait() {
+                    return Some(PlayerSink { child });
+                }
+            }
+        }
+        return None;
+    }
+}
+
+impl AudioSink for PlayerSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        return self.child.stdin.as_mut().unwrap().write_all(&bytes);
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        drop(self.child.stdin.take());
+        self.child.wait()?;
+        return Ok(());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Waveform {
+    Square,
+    Sine,
+}
+
+impl Waveform {
+    pub fn parse(name: &str) -> Option<Waveform> {
+        match name.to_lowercase().as_str() {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+}
+
+// Tone generator, phase carries over between calls so the wave is continuous
+pub struct Beeper {
+    pub waveform: Waveform,
+    pub frequency: f64, // Hz
+    pub volume: f64, // 0.0 - 1.0
+    phase: f64, // Fraction of period
+}
+
+impl Beeper {
+    pub fn new(waveform: Waveform, frequency: f64, volume: f64) -> Beeper {
+        Beeper { waveform, frequency, volume: volume.clamp(0.0, 1.0), phase: 0.0 }
+    }
+
+    // Append count samples, silence when off
+    pub fn generate(&mut self, on: bool, count: usize, out: &mut Vec<i16>) {
+        let amplitude = self.volume * i16::MAX as f64;
+        for _ in 0..count {
+            if !on {
+                out.push(0);
+                self.phase = 0.0;
+                continue;
+            }
+            let value = match self.waveform {
+                Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
+                Waveform::Sine => (self.phase * std::f64::consts::TAU).sin(),
+            };
+            out.push((value * amplitude) as i16);
+            self.phase = (self.phase + self.frequency / SAMPLE_RATE as f64).fract();
+        }
+    }
+}
+
+// XO-CHIP pattern bits per second at pitch, 4000 Hz at the default pitch of 64
+pub fn pattern_rate(pitch: u8) -> f64 {
+    return 4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0);
+}
+
+// Plays the 128 bit XO-CHIP pattern buffer most significant bit first, looping.
+// Set bits give the positive and clear bits the negative amplitude.
+pub struct PatternPlayer {
+    pub volume: f64, // 0.0 - 1.0
+    position: f64, // Bit position in pattern
+}
+
+impl PatternPlayer {
+    pub fn new(volume: f64) -> PatternPlayer {
+        PatternPlayer { volume: volume.clamp(0.0, 1.0), position: 0.0 }
+    }
+
+    // Append count samples, silence when off
+    pub fn generate(&mut self, pattern: &[u8; 16], pitch: u8, on: bool, count: usize, out: &mut Vec<i16>) {
+        let amplitude = (self.volume * i16::MAX as f64) as i16;
+        let step = pattern_rate(pitch) / SAMPLE_RATE as f64;
+        for _ in 0..count {
+            if !on {
+                out.push(0);
+                self.position = 0.0;
+                continue;
+            }
+            let bit = self.position as usize;
+            let set = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            out.push(if set { amplitude } else { -amplitude });
+            self.position = (self.position + step) % 128.0;
+        }
+    }
+}
+
+pub struct AudioOptions {
+    pub waveform: Waveform,
+    pub frequency: f64,
+    pub volume: f64,
+    pub wav: Option<String>, // Record everything played to this file
+    pub device: bool, // Try to play through an audio player
+}
+
+pub struct Audio {
+    beeper: Beeper,
+    pattern: PatternPlayer,
+    player: Option<PlayerSink>,
+    sinks: Vec<Box<dyn AudioSink>>,
+    rate: u32, // Timer ticks per second
+    tick_phase: u32, // Remainder of samples per tick, keeps sample count exact
+}
+
+impl Audio {
+    pub fn new(options: &AudioOptions, rate: u32) -> io::Result<Audio> {
+        let player = if options.device { PlayerSink::open() } else { None };
+        let mut sinks: Vec<Box<dyn AudioSink>> = Vec::new();
+        if let Some(path) = &options.wav {
+            sinks.push(Box::new(WavSink::create(path)?));
+        }
+        let beeper = Beeper::new(options.waveform, options.frequency, options.volume);
+        let pattern = PatternPlayer::new(options.volume);
+        return Ok(Audio { beeper, pattern, player, sinks, rate, tick_phase: 0 });
+    }
+
+    // Audio into a single sink without a device
+    #[cfg(test)]
+    pub fn with_sink(beeper: Beeper, sink: Box<dyn AudioSink>, rate: u32) -> Audio {
+        let pattern = PatternPlayer::new(beeper.volume);
+        return Audio { beeper, pattern, player: None, sinks: vec![sink], rate, tick_phase: 0 };
+    }
+
+    // Sound is heard, otherwise the caller should show or ring it
+    pub fn has_device(&self) -> bool {
+        return self.player.is_some();
+    }
+
+    // Samples of one timer tick, with or without the tone. The XO-CHIP
+    // pattern and pitch replace the beeper once a ROM has loaded a pattern.
+    pub fn tick(&mut self, on: bool, pattern: Option<([u8; 16], u8)>) {
+        if self.player.is_none() && self.sinks.is_empty() {
+            return;
+        }
+        self.tick_phase += SAMPLE_RATE;
+        let count = (self.tick_phase / self.rate) as usize;
+        self.tick_phase %= self.rate;
+
+        let mut samples = Vec::with_capacity(count);
+        match pattern {
+            Some((pattern, pitch)) => self.pattern.generate(&pattern, pitch, on, count, &mut samples),
+            None => self.beeper.generate(on, count, &mut samples),
+        }
+        // Player went away, fall back to no device
+        if let Some(player) = self.player.as_mut() {
+            if player.write(&samples).is_err() {
+                self.player = None;
+            }
+        }
+        self.sinks.retain_mut(|sink| sink.write(&samples).is_ok());
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(mut player) = self.player.take() {
+            player.finish()?;
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.finish()?;
+        }
+        self.sinks.clear();
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const HIGH: i16 = i16::MAX;
+
+    // Keeps samples where the test can read them
+    struct Recorder(Rc<RefCell<Vec<i16>>>);
+
+    impl AudioSink for Recorder {
+        fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+            self.0.borrow_mut().extend_from_slice(samples);
+            return Ok(());
+        }
+    }
+
+    fn generate_pattern(pattern: &[u8; 16], pitch: u8, count: usize) -> Vec<i16> {
+        let mut out = Vec::new();
+        PatternPlayer::new(1.0).generate(pattern, pitch, true, count, &mut out);
+        return out;
+    }
+
+    #[test]
+    fn pattern_rate_follows_pitch_formula() {
+        assert!((pattern_rate(64) - 4000.0).abs() < 1e-9);
+        assert!((pattern_rate(112) - 8000.0).abs() < 1e-9);
+        assert!((pattern_rate(16) - 2000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pattern_bits_at_default_pitch() {
+        // 4000 bits per second at 44100 Hz is 11.025 samples per bit
+        let mut pattern = [0; 16];
+        pattern[0] = 0b1010_0000;
+        let mut expected = vec![HIGH; 12];
+        expected.extend(vec![-HIGH; 11]);
+        expected.extend(vec![HIGH; 11]);
+        expected.extend(vec![-HIGH; 11]);
+        assert_eq!(generate_pattern(&pattern, 64, 45), expected);
+    }
+
+    #[test]
+    fn pattern_bytes_and_loop() {
+        let pattern = [0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+        let samples = generate_pattern(&pattern, 64, 1500);
+        // Byte 1 starts at bit 8, sample ceil(88.2), byte 2 at sample ceil(176.4)
+        assert!(samples[..89].iter().all(|s| *s == HIGH));
+        assert!(samples[89..177].iter().all(|s| *s == -HIGH));
+        assert_eq!(samples[177], HIGH);
+        // Pattern repeats after 128 bits, sample ceil(1411.2)
+        assert_eq!(samples[1411], -HIGH);
+        assert_eq!(samples[1412], HIGH);
+    }
+
+    #[test]
+    fn pattern_is_continuous_across_calls() {
+        let pattern = [0x3C, 0x99, 0x0F, 0xF0, 0x55, 0xAA, 0x01, 0x80, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
+        let whole = generate_pattern(&pattern, 90, 1470);
+        let mut player = PatternPlayer::new(1.0);
+        let mut parts = Vec::new();
+        player.generate(&pattern, 90, true, 735, &mut parts);
+        player.generate(&pattern, 90, true, 735, &mut parts);
+        assert_eq!(whole, parts);
+    }
+
+    #[test]
+    fn pattern_restarts_after_silence() {
+        let pattern = [0xF0; 16];
+        let mut player = PatternPlayer::new(1.0);
+        let mut out = Vec::new();
+        player.generate(&pattern, 64, true, 50, &mut out);
+        player.generate(&pattern, 64, false, 10, &mut out);
+        player.generate(&pattern, 64, true, 50, &mut out);
+        assert!(out[50..60].iter().all(|s| *s == 0));
+        assert_eq!(out[60..], out[..50]);
+    }
+
+    #[test]
+    fn square_beeper_period() {
+        // 441 Hz is 100 samples per period
+        let mut out = Vec::new();
+        Beeper::new(Waveform::Square, 441.0, 1.0).generate(true, 200, &mut out);
+        assert!(out[..50].iter().all(|s| *s == HIGH));
+        assert!(out[50..100].iter().all(|s| *s == -HIGH));
+        assert_eq!(out[100..], out[..100]);
+    }
+
+    #[test]
+    fn ticks_give_exact_sample_count() {
+        let samples = Rc::new(RefCell::new(Vec::new()));
+        let beeper = Beeper::new(Waveform::Square, 440.0, 1.0);
+        let mut audio = Audio::with_sink(beeper, Box::new(Recorder(samples.clone())), 7);
+        for _ in 0..7 {
+            audio.tick(true, None);
+        }
+        assert_eq!(samples.borrow().len(), SAMPLE_RATE as usize);
+    }
+
+    #[test]
+    fn tick_uses_pattern_when_loaded() {
+        let samples = Rc::new(RefCell::new(Vec::new()));
+        let beeper = Beeper::new(Waveform::Sine, 440.0, 1.0);
+        let mut audio = Audio::with_sink(beeper, Box::new(Recorder(samples.clone())), 60);
+        let mut pattern = [0; 16];
+        pattern[0] = 0b1010_0000;
+        audio.tick(true, Some((pattern, 64)));
+        assert_eq!(samples.borrow().len(), 735);
+        assert_eq!(samples.borrow()[..34], generate_pattern(&pattern, 64, 34));
+    }
+
+    #[test]
+    fn wav_header_sizes() {
+        let path = std::env::temp_dir().join(format!("chip_8_test_{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut sink = WavSink::create(path).unwrap();
+        sink.write(&[1, -1, 256]).unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(data.len(), 50);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 42);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), SAMPLE_RATE);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 6);
+        assert_eq!(&data[44..], &[1, 0, 0xff, 0xff, 0, 1]);
+    }
+}
@@ -0,0 +1,525 @@
+use crate::cpu::{Quirks, CLOCK_SPEED, HISTORY_LIMIT, TIMER_RATE};
+use crate::database::{Database, RomInfo};
+use crate::theme::{self, ThemeSpec};
+use crate::toml::{self, Value};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Emulator settings in layers, each one overriding values set by the ones
+// before it:
+//
+//   built in defaults
+//   global file        ~/.config/chip_8/config.toml
+//   ROM database       entry found for the ROM or octocart settings
+//   ROM file           <rom>.toml next to the ROM, with the bindings of
+//                      <rom>.keymap
+//   command line
+//
+// A layer that names a platform resets quirks and speed to the platform's,
+// a layer that names a theme drops palettes and theme colours set below it.
+// A theme file is read into its base theme and [theme] colours.
+//
+//   [machine]
+//   platform = "superchip"
+//   tick_rate = 30
+//
+//   [quirks]
+//   vblank = false
+//
+//   [display]
+//   palette = ["#000000", "#ffcc00"]
+//
+//   [theme]
+//   focus = "#ffd700"
+
+#[derive(Clone, Default, Debug)]
+pub struct QuirkSettings {
+    pub shift: Option<bool>,
+    pub memory_increment_by_x: Option<bool>,
+    pub memory_leave_i_unchanged: Option<bool>,
+    pub wrap: Option<bool>,
+    pub jump: Option<bool>,
+    pub vblank: Option<bool>,
+    pub logic: Option<bool>,
+}
+
+pub const QUIRK_NAMES: [&str; 7] = ["shift", "memory_increment_by_x", "memory_leave_i_unchanged", "wrap", "jump", "vblank", "logic"];
+
+impl QuirkSettings {
+    pub fn from_quirks(quirks: Quirks) -> QuirkSettings {
+        return QuirkSettings {
+            shift: Some(quirks.shift),
+            memory_increment_by_x: Some(quirks.memory_increment_by_x),
+            memory_leave_i_unchanged: Some(quirks.memory_leave_i_unchanged),
+            wrap: Some(quirks.wrap),
+            jump: Some(quirks.jump),
+            vblank: Some(quirks.vblank),
+            logic: Some(quirks.logic),
+        };
+    }
+
+    // Quirks with the set values replaced
+    pub fn apply(&self, base: Quirks) -> Quirks {
+        return Quirks {
+            shift: self.shift.unwrap_or(base.shift),
+            memory_increment_by_x: self.memory_increment_by_x.unwrap_or(base.memory_increment_by_x),
+            memory_leave_i_unchanged: self.memory_leave_i_unchanged.unwrap_or(base.memory_leave_i_unchanged),
+            wrap: self.wrap.unwrap_or(base.wrap),
+            jump: self.jump.unwrap_or(base.jump),
+            vblank: self.vblank.unwrap_or(base.vblank),
+            logic: self.logic.unwrap_or(base.logic),
+        };
+    }
+
+    fn field(&mut self, name: &str) -> Option<&mut Option<bool>> {
+        match name {
+            "shift" => Some(&mut self.shift),
+            "memory_increment_by_x" => Some(&mut self.memory_increment_by_x),
+            "memory_leave_i_unchanged" => Some(&mut self.memory_leave_i_unchanged),
+            "wrap" => Some(&mut self.wrap),
+            "jump" => Some(&mut self.jump),
+            "vblank" => Some(&mut self.vblank),
+            "logic" => Some(&mut self.logic),
+            _ => None,
+        }
+    }
+
+    // Comma separated quirk names, "-name" or "no-name" turns a quirk off
+    pub fn parse(spec: &str) -> Result<QuirkSettings, String> {
+        let mut quirks = QuirkSettings::default();
+        for name in spec.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let (name, on) = match name.strip_prefix('-').or(name.strip_prefix("no-")) {
+                Some(name) => (name, false),
+                None => (name, true),
+            };
+            let field = quirks.field(name)
+                .ok_or(format!("Unknown quirk '{}', expected one of: {}", name, QUIRK_NAMES.join(" ")))?;
+            *field = Some(on);
+        }
+        return Ok(quirks);
+    }
+
+    fn merge(&mut self, layer: &QuirkSettings) {
+        let mut layer = layer.clone();
+        for name in QUIRK_NAMES {
+            if let Some(value) = *layer.field(name).unwrap() {
+                *self.field(name).unwrap() = Some(value);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Config {
+    // [machine]
+    pub platform: Option<String>, // Database platform id
+    pub tick_rate: Option<u64>, // Instructions per frame
+    pub load_address: Option<String>,
+    pub history: Option<usize>, // Executed instructions kept for the debugger
+    // [quirks]
+    pub quirks: QuirkSettings,
+    // [display]
+    pub render: Option<String>,
+    pub graphics: Option<String>,
+    pub theme: Option<String>,
+    pub colors: Option<String>,
+    pub palette: Option<Vec<String>>, // Display colours over the theme's
+    pub persistence: Option<String>,
+    pub char_on: Option<char>, // Pixels in trace output
+    pub char_off: Option<char>,
+    // [theme]
+    pub theme_colors: Vec<(String, String)>, // Colours over the named theme
+    // [input]
+    pub keymap: Option<String>,
+    pub bindings: Option<String>, // Key map overrides such as "5=w, 8=s"
+    pub key_hold: Option<u64>,
+    // [audio]
+    pub waveform: Option<String>,
+    pub tone: Option<f64>,
+    pub volume: Option<f64>, // 0-100
+    pub mute: Option<bool>,
+    pub wav: Option<String>,
+}
+
+impl Config {
+    pub fn defaults() -> Config {
+        return Config {
+            platform: None,
+            tick_rate: Some(CLOCK_SPEED / TIMER_RATE),
+            load_address: Some("200".to_string()),
+            history: Some(HISTORY_LIMIT),
+            quirks: QuirkSettings::from_quirks(Quirks::default()),
+            render: Some("halfblock".to_string()),
+            graphics: Some("auto".to_string()),
+            theme: Some("default".to_string()),
+            colors: Some("auto".to_string()),
+            palette: None,
+            persistence: Some("off".to_string()),
+            char_on: Some('█'),
+            char_off: Some(' '),
+            theme_colors: Vec::new(),
+            keymap: Some("qwerty".to_string()),
+            bindings: None,
+            key_hold: Some(250),
+            waveform: Some("square".to_string()),
+            tone: Some(440.0),
+            volume: Some(25.0),
+            mute: Some(false),
+            wav: None,
+        };
+    }
+
+    // Layer with what the database or a cartridge knows about a ROM
+    pub fn from_rom(rom: &RomInfo) -> Config {
+        return Config {
+            platform: rom.platform.as_ref().map(|p| p.id.clone()),
+            tick_rate: Some(rom.tick_rate),
+            load_address: rom.start_address.map(|a| format!("{:X}", a)),
+            quirks: QuirkSettings::from_quirks(rom.quirks),
+            palette: Some(rom.colors.clone()).filter(|c| !c.is_empty()),
+            ..Config::default()
+        };
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for entry in toml::parse(text)? {
+            config.set(&entry.section, &entry.key, &entry.value)
+                .map_err(|e| format!("line {}: {}", entry.line, e))?;
+        }
+        return Ok(config);
+    }
+
+    // Settings in file, None when it does not exist
+    pub fn load(path: &str) -> Result<Option<Config>, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        };
+        return Config::parse(&text).map(Some).map_err(|e| format!("{}: {}", path, e));
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &Value) -> Result<(), String> {
+        let string = || match value {
+            Value::String(s) => Ok(s.clone()),
+            Value::Integer(n) => Ok(format!("{:X}", n)), // Addresses written as numbers
+            _ => Err(format!("[{}] {} must be a string, not {}", section, key, value.type_name())),
+        };
+        let text = || match value {
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(format!("[{}] {} must be a string, not {}", section, key, value.type_name())),
+        };
+        let integer = || match value {
+            Value::Integer(n) if *n >= 0 => Ok(*n as u64),
+            _ => Err(format!("[{}] {} must be a positive integer", section, key)),
+        };
+        let number = || match value {
+            Value::Integer(n) => Ok(*n as f64),
+            Value::Float(f) => Ok(*f),
+            _ => Err(format!("[{}] {} must be a number, not {}", section, key, value.type_name())),
+        };
+        let boolean = || match value {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(format!("[{}] {} must be true or false, not {}", section, key, value.type_name())),
+        };
+        let character = || {
+            let s = text()?;
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(c),
+                _ => Err(format!("[{}] {} must be a single character", section, key)),
+            }
+        };
+        match (section, key) {
+            ("machine", "platform") => self.platform = Some(text()?),
+            ("machine", "tick_rate") => self.tick_rate = Some(integer()?.max(1)),
+            ("machine", "load_address") => self.load_address = Some(string()?),
+            ("machine", "history") => self.history = Some((integer()? as usize).max(1)),
+            ("quirks", name) => match self.quirks.field(name) {
+                Some(field) => *field = Some(boolean()?),
+                None => return Err(format!("Unknown quirk '{}', expected one of: {}", name, QUIRK_NAMES.join(" "))),
+            },
+            ("display", "render") => self.render = Some(text()?),
+            ("display", "graphics") => self.graphics = Some(text()?),
+            ("display", "theme") => self.theme = Some(text()?),
+            ("display", "colors") => self.colors = Some(text()?),
+            ("display", "persistence") => self.persistence = Some(text()?),
+            ("display", "palette") => match value {
+                Value::Array(items) => {
+                    let colors = items.iter().map(|item| match item {
+                        Value::String(s) => Ok(s.clone()),
+                        _ => Err("[display] palette must be an array of colour strings".to_string()),
+                    }).collect::<Result<Vec<String>, String>>()?;
+                    self.palette = Some(colors);
+                }
+                _ => return Err("[display] palette must be an array of colour strings".to_string()),
+            },
+            ("display", "char_on") => self.char_on = Some(character()?),
+            ("display", "char_off") => self.char_off = Some(character()?),
+            ("theme", key) => {
+                let color = text()?;
+                theme::check_color(key, &color)?;
+                theme::set_color(&mut self.theme_colors, key, &color);
+            }
+            ("input", "keymap") => self.keymap = Some(text()?),
+            ("input", "bindings") => self.bindings = Some(text()?),
+            ("input", "key_hold") => self.key_hold = Some(integer()?),
+            ("audio", "waveform") => self.waveform = Some(text()?),
+            ("audio", "tone") => self.tone = Some(number()?),
+            ("audio", "volume") => self.volume = Some(number()?),
+            ("audio", "mute") => self.mute = Some(boolean()?),
+            ("audio", "wav") => self.wav = Some(text()?),
+            _ => return Err(format!("Unknown setting [{}] {}", section, key)),
+        }
+        return Ok(());
+    }
+
+    // Apply layer on top of these settings
+    pub fn merge(&mut self, layer: &Config, database: &Database) -> Result<(), String> {
+        fn pick<T: Clone>(value: &mut Option<T>, layer: &Option<T>) {
+            if layer.is_some() {
+                *value = layer.clone();
+            }
+        }
+        if let Some(id) = &layer.platform {
+            let platform = database.platform(id)
+                .ok_or(format!("Unknown platform '{}', expected one of: {}", id, database.platform_ids().join(" ")))?;
+            self.quirks = QuirkSettings::from_quirks(platform.quirks);
+            self.tick_rate = Some(platform.tick_rate);
+        }
+        // A theme file is read here, the result names a built-in theme
+        if let Some(name) = &layer.theme {
+            let (base, colors) = match ThemeSpec::builtin(name) {
+                Some(_) => (name.to_lowercase(), Vec::new()),
+                None => theme::load_file(name)?,
+            };
+            self.theme = Some(base);
+            self.theme_colors = colors;
+            self.palette = None;
+        }
+        pick(&mut self.platform, &layer.platform);
+        pick(&mut self.tick_rate, &layer.tick_rate);
+        pick(&mut self.load_address, &layer.load_address);
+        pick(&mut self.history, &layer.history);
+        self.quirks.merge(&layer.quirks);
+        pick(&mut self.render, &layer.render);
+        pick(&mut self.graphics, &layer.graphics);
+        pick(&mut self.colors, &layer.colors);
+        pick(&mut self.palette, &layer.palette);
+        pick(&mut self.persistence, &layer.persistence);
+        pick(&mut self.char_on, &layer.char_on);
+        pick(&mut self.char_off, &layer.char_off);
+        for (key, color) in &layer.theme_colors {
+            theme::set_color(&mut self.theme_colors, key, color);
+        }
+        pick(&mut self.keymap, &layer.keymap);
+        pick(&mut self.bindings, &layer.bindings);
+        pick(&mut self.key_hold, &layer.key_hold);
+        pick(&mut self.waveform, &layer.waveform);
+        pick(&mut self.tone, &layer.tone);
+        pick(&mut self.volume, &layer.volume);
+        pick(&mut self.mute, &layer.mute);
+        pick(&mut self.wav, &layer.wav);
+        return Ok(());
+    }
+
+    // Set values as a TOML file
+    pub fn to_toml(&self) -> String {
+        let string = |s: &Option<String>| s.clone().map(Value::String);
+        let integer = |n: Option<u64>| n.map(|n| Value::Integer(n as i64));
+        let boolean = |b: Option<bool>| b.map(Value::Bool);
+        let character = |c: Option<char>| c.map(|c| Value::String(c.to_string()));
+        let quirks = &self.quirks;
+        let sections = [
+            ("machine", vec![
+                ("platform", string(&self.platform)),
+                ("tick_rate", integer(self.tick_rate)),
+                ("load_address", string(&self.load_address)),
+                ("history", integer(self.history.map(|h| h as u64))),
+            ]),
+            ("quirks", vec![
+                ("shift", boolean(quirks.shift)),
+                ("memory_increment_by_x", boolean(quirks.memory_increment_by_x)),
+                ("memory_leave_i_unchanged", boolean(quirks.memory_leave_i_unchanged)),
+                ("wrap", boolean(quirks.wrap)),
+                ("jump", boolean(quirks.jump)),
+                ("vblank", boolean(quirks.vblank)),
+                ("logic", boolean(quirks.logic)),
+            ]),
+            ("display", vec![
+                ("render", string(&self.render)),
+                ("graphics", string(&self.graphics)),
+                ("theme", string(&self.theme)),
+                ("colors", string(&self.colors)),
+                ("palette", self.palette.as_ref().map(|p| Value::Array(p.iter().cloned().map(Value::String).collect()))),
+                ("persistence", string(&self.persistence)),
+                ("char_on", character(self.char_on)),
+                ("char_off", character(self.char_off)),
+            ]),
+            ("theme", self.theme_colors.iter().map(|(key, color)| (key.as_str(), Some(Value::String(color.clone())))).collect()),
+            ("input", vec![
+                ("keymap", string(&self.keymap)),
+                ("bindings", string(&self.bindings)),
+                ("key_hold", integer(self.key_hold)),
+            ]),
+            ("audio", vec![
+                ("waveform", string(&self.waveform)),
+                ("tone", self.tone.map(Value::Float)),
+                ("volume", self.volume.map(Value::Float)),
+                ("mute", boolean(self.mute)),
+                ("wav", string(&self.wav)),
+            ]),
+        ];
+        let mut out = String::new();
+        for (name, values) in sections {
+            out.push_str(&format!("[{}]\n", name));
+            for (key, value) in values {
+                match value {
+                    Some(value) => out.push_str(&format!("{} = {}\n", key, toml::format(&value))),
+                    None => out.push_str(&format!("# {} =\n", key)),
+                }
+            }
+            out.push('\n');
+        }
+        return out;
+    }
+}
+
+// Settings directory, $XDG_CONFIG_HOME/chip_8 or ~/.config/chip_8
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").ok()?).join(".config"),
+    };
+    return Some(base.join("chip_8"));
+}
+
+// Global settings file in the settings directory
+pub fn global_path() -> Option<PathBuf> {
+    return config_dir().map(|dir| dir.join("config.toml"));
+}
+
+// Per-ROM settings file next to the ROM
+pub fn rom_path(rom_path: &str) -> String {
+    return format!("{}.toml", rom_path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Settings from defaults and layers applied in order
+    fn layered(layers: &[&str]) -> Config {
+        let database = Database::bundled();
+        let mut config = Config::defaults();
+        for text in layers {
+            config.merge(&Config::parse(text).unwrap(), &database).unwrap();
+        }
+        return config;
+    }
+
+    #[test]
+    fn parse_sections() {
+        let config = Config::parse(
+            "[machine]\nplatform = \"superchip\"\nload_address = 0x600\n\n[quirks]\nvblank = false\n\n[display]\npalette = [\n  \"#000000\",\n  \"#ffcc00\",\n]\nchar_on = \"#\"\n\n[audio]\ntone = 220\n"
+        ).unwrap();
+        assert_eq!(config.platform.as_deref(), Some("superchip"));
+        assert_eq!(config.load_address.as_deref(), Some("600"));
+        assert_eq!(config.quirks.vblank, Some(false));
+        assert_eq!(config.quirks.shift, None);
+        assert_eq!(config.palette, Some(vec!["#000000".to_string(), "#ffcc00".to_string()]));
+        assert_eq!(config.char_on, Some('#'));
+        assert_eq!(config.tone, Some(220.0));
+        assert_eq!(config.tick_rate, None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| Config::parse(text).err().unwrap();
+        assert_eq!(error("[machine]\ntick_rate = \"fast\""), "line 2: [machine] tick_rate must be a positive integer");
+        assert_eq!(error("[quirks]\nwarp = true"), format!("line 2: Unknown quirk 'warp', expected one of: {}", QUIRK_NAMES.join(" ")));
+        assert_eq!(error("[display]\nsize = 2"), "line 2: Unknown setting [display] size");
+        assert_eq!(error("[display]\npalette = [1]"), "line 2: [display] palette must be an array of colour strings");
+        assert_eq!(error("[display]\nchar_on = \"ab\""), "line 2: [display] char_on must be a single character");
+    }
+
+    #[test]
+    fn layer_order() {
+        let global = "[machine]\ntick_rate = 10\n[quirks]\nwrap = true\n[display]\npalette = [\"#111111\", \"#222222\"]\n";
+        let rom = "[machine]\nplatform = \"chip48\"\n";
+        let rom_file = "[quirks]\nvblank = true\n[audio]\nvolume = 50\n";
+        let command_line = "[machine]\ntick_rate = 50\n";
+
+        // Later layers win, a platform resets quirks and speed set before it
+        let config = layered(&[global, rom, rom_file, command_line]);
+        assert_eq!(config.platform.as_deref(), Some("chip48"));
+        assert_eq!(config.tick_rate, Some(50));
+        assert_eq!(config.quirks.wrap, Some(false));
+        assert_eq!(config.quirks.shift, Some(true));
+        assert_eq!(config.quirks.vblank, Some(true));
+        assert_eq!(config.volume, Some(50.0));
+        assert_eq!(config.palette, Some(vec!["#111111".to_string(), "#222222".to_string()]));
+
+        let config = layered(&[global, rom]);
+        assert_eq!(config.tick_rate, Some(30));
+
+        // A theme drops palettes from lower layers only
+        let config = layered(&[global, "[display]\ntheme = \"amber\"\n"]);
+        assert_eq!(config.palette, None);
+        let config = layered(&["[display]\ntheme = \"amber\"\npalette = [\"#333333\"]\n"]);
+        assert_eq!(config.palette, Some(vec!["#333333".to_string()]));
+
+        let mut config = Config::defaults();
+        let error = config.merge(&Config::parse("[machine]\nplatform = \"pdp\"\n").unwrap(), &Database::bundled());
+        assert!(error.unwrap_err().starts_with("Unknown platform 'pdp'"));
+    }
+
+    #[test]
+    fn theme_files_and_colors() {
+        let path = std::env::temp_dir().join(format!("chip_8_test_{}.theme", std::process::id()));
+        fs::write(&path, "base = \"amber\"\n[theme]\nfocus = \"#ffd700\"\ncolor1 = \"yellow\"\n").unwrap();
+        let theme_layer = format!("[display]\ntheme = {:?}\n", path.to_string_lossy());
+
+        // The file becomes its base theme and colours, later [theme] colours win
+        let config = layered(&["[display]\npalette = [\"#111111\"]\n", &theme_layer, "[theme]\ncolor1 = \"#00ff00\"\n"]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.theme.as_deref(), Some("amber"));
+        assert_eq!(config.palette, None);
+        assert_eq!(config.theme_colors, vec![
+            ("focus".to_string(), "#ffd700".to_string()),
+            ("color1".to_string(), "#00ff00".to_string()),
+        ]);
+        let text = config.to_toml();
+        assert!(text.contains("\ntheme = \"amber\"\n"));
+        assert!(text.contains("\n[theme]\nfocus = \"#ffd700\"\ncolor1 = \"#00ff00\"\n"));
+        assert_eq!(layered(&[&text]).theme_colors, config.theme_colors);
+
+        // A theme named later drops them
+        assert!(layered(&[&text, "[display]\ntheme = \"green\"\n"]).theme_colors.is_empty());
+        assert!(Config::parse("[theme]\nborder = \"red\"\n").unwrap_err().contains("Unknown theme key 'border'"));
+        assert!(Config::parse("[theme]\nfocus = \"pink\"\n").unwrap_err().contains("Invalid colour"));
+        let mut config = Config::defaults();
+        let missing = config.merge(&Config::parse("[display]\ntheme = \"/nonexistent.theme\"\n").unwrap(), &Database::bundled());
+        assert!(missing.unwrap_err().starts_with("/nonexistent.theme: "));
+    }
+
+    #[test]
+    fn printed_config() {
+        let config = layered(&["[machine]\nplatform = \"xochip\"\n[display]\npalette = [\"#000000\", \"#ffffff\"]\nchar_off = \".\"\n"]);
+        let text = config.to_toml();
+        assert!(text.starts_with("[machine]\nplatform = \"xochip\"\ntick_rate = 100\nload_address = \"200\"\n"));
+        assert!(text.contains("\n[quirks]\nshift = false\n"));
+        assert!(text.contains("\nwrap = true\n"));
+        assert!(text.contains("\npalette = [\"#000000\", \"#ffffff\"]\n"));
+        assert!(text.contains("\nchar_off = \".\"\n"));
+        assert!(text.contains("\n# bindings =\n"));
+        assert!(text.contains("\n[audio]\nwaveform = \"square\"\ntone = 440.0\n"));
+
+        // Printed settings load back to the same settings
+        let reloaded = layered(&[&text]);
+        assert_eq!(reloaded.to_toml(), text);
+        assert_eq!(Config::parse(&Config::defaults().to_toml()).unwrap().to_toml(), Config::defaults().to_toml());
+    }
+}
@@ -3,18 +3,133 @@ use rand::rngs::ThreadRng;
 
 use std::collections::VecDeque;
 
-//const STACK_SIZE: usize = 64;
+const STACK_SIZE: usize = 16; // Call depth before stack overflow
 const DISPLAY_WIDTH: usize = 64;
 const DISPLAY_HEIGHT: usize = 32;
-const PROGRAM_START: usize = 0x200;
-const CHAR_ON: char = '█';
-const CHAR_OFF: char = ' ';
-const CLOCK_SPEED: u64 = 500; // Hz
-const HISTORY_LIMIT: usize = 500; // Hz
+pub const MEMORY_SIZE: usize = 4096;
+pub const PROGRAM_START: u16 = 0x200; // COSMAC VIP load address
+pub const ETI660_START: u16 = 0x600; // ETI-660 load address
+pub const CHAR_ON: char = '█';
+pub const CHAR_OFF: char = ' ';
+pub const CLOCK_SPEED: u64 = 500; // Hz
+pub const TIMER_RATE: u64 = 60; // Hz
+pub const HISTORY_LIMIT: usize = 500; // Executed instructions kept
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Fetch,
+    Read,
+    Write,
+}
+
+// Machine event caused by the last executed cycle
+#[derive(Clone, Copy, PartialEq)]
+pub enum CpuEvent {
+    Draw { collision: bool },
+    Clear,
+    KeyWait(u8),
+    SoundStart,
+    StackOverflow,
+}
+
+// Files placed in memory before the program starts: the ROM at its load
+// address, optionally a data blob at another address and memory images
+// written over both
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub address: u16,
+    pub data: Option<(Vec<u8>, u16)>,
+    pub patches: Vec<(u16, Vec<u8>)>, // Start address and bytes
+}
+
+impl Program {
+    // Check that everything fits in memory without overlapping
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rom.is_empty() {
+            return Err("ROM is empty".to_string());
+        }
+        check_fits("ROM", self.rom.len(), self.address)?;
+        if let Some((data, address)) = &self.data {
+            check_fits("Data", data.len(), *address)?;
+            let rom = self.address as usize..self.address as usize + self.rom.len();
+            let blob = *address as usize..*address as usize + data.len();
+            if blob.start < rom.end && rom.start < blob.end {
+                return Err(format!(
+                    "Data at {:03X}-{:03X} overlaps ROM at {:03X}-{:03X}",
+                    blob.start, blob.end - 1, rom.start, rom.end - 1
+                ));
+            }
+        }
+        for (address, bytes) in &self.patches {
+            check_fits("Image", bytes.len(), *address)?;
+        }
+        return Ok(());
+    }
+}
 
+fn check_fits(what: &str, len: usize, address: u16) -> Result<(), String> {
+    let space = MEMORY_SIZE.saturating_sub(address as usize);
+    if len > space {
+        return Err(format!("{} is {} bytes but only {} bytes fit in memory from {:03X}", what, len, space, address));
+    }
+    return Ok(());
+}
 
+// Load address from hex or a machine name: "vip" for 0x200, "eti660" for 0x600
+pub fn parse_load_address(input: &str) -> Result<u16, String> {
+    let s = input.trim().to_lowercase();
+    match s.as_str() {
+        "vip" => return Ok(PROGRAM_START),
+        "eti660" | "eti-660" => return Ok(ETI660_START),
+        _ => {}
+    }
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix('$')).unwrap_or(&s);
+    match u16::from_str_radix(hex, 16) {
+        Ok(address) if (address as usize) < MEMORY_SIZE => Ok(address),
+        _ => Err(format!("Invalid load address '{}', expected hex address below {:X}, vip or eti660", input, MEMORY_SIZE)),
+    }
+}
+
+// Behaviour that differs between CHIP-8 implementations, named as in the
+// chip-8-database platform definitions. Default is the behaviour this
+// emulator always had.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Quirks {
+    pub shift: bool, // 8XY6 and 8XYE shift VX in place instead of shifting VY into VX
+    pub memory_increment_by_x: bool, // FX55 and FX65 add X to I instead of X + 1
+    pub memory_leave_i_unchanged: bool, // FX55 and FX65 do not change I
+    pub wrap: bool, // Sprites wrap around the screen edges instead of being clipped
+    pub jump: bool, // BXNN jumps to XNN + VX instead of NNN + V0
+    pub vblank: bool, // Sprite draws wait for the next frame
+    pub logic: bool, // 8XY1, 8XY2 and 8XY3 reset VF
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+// Memory access made by the last executed cycle
+#[derive(Clone, Copy)]
+pub struct MemAccess {
+    pub address: u16,
+    pub kind: AccessKind,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
-    ram: [u8; 4096], // Main memory
+    ram: [u8; MEMORY_SIZE], // Main memory
     pub pc: u16, // Program counter
     pub ir: u16, // Index register
     pub sp: u8, // Stack pointer
@@ -25,7 +140,19 @@ pub struct CPU {
     pub vbuf: [u8; DISPLAY_WIDTH * DISPLAY_HEIGHT / 8], // Video buffer,
     rng: ThreadRng,
     debug: bool,
-    exec_history: VecDeque<u16>,
+    exec_history: VecDeque<(u16, u16)>, // (address, instruction)
+    mem_access: Vec<MemAccess>,
+    events: Vec<CpuEvent>,
+    keys: [bool; 16], // Hex keypad, true while held
+    pub key_wait: Option<u8>, // Register waiting for key press with FX0A
+    key_wait_key: Option<u8>, // Key pressed during FX0A, stored when released
+    pub key_poll: Option<u8>, // Key checked by last EX9E or EXA1
+    audio_pattern: Option<[u8; 16]>, // XO-CHIP 1-bit sample pattern, loaded by F002
+    pub pitch: u8, // XO-CHIP pattern playback pitch, set by FX3A
+    pub quirks: Quirks,
+    vblank_wait: bool, // Sprite drawn this frame, next draw waits with vblank quirk
+    pub history_limit: usize, // Length of exec_history
+    pub pixel_chars: (char, char), // Lit and unlit pixels in trace output
 }
 
 const _FONT_SET: [[u8; 5]; 16] = [
@@ -50,8 +177,8 @@ const _FONT_SET: [[u8; 5]; 16] = [
 impl Default for CPU {
     fn default() -> CPU {
         CPU {
-            ram: [0; 4096],
-            pc: PROGRAM_START as u16,
+            ram: [0; MEMORY_SIZE],
+            pc: PROGRAM_START,
             ir: 0,
             sp: 0,
             dt: 0,
@@ -62,6 +189,18 @@ impl Default for CPU {
             rng : rand::thread_rng(),
             debug: false,
             exec_history: VecDeque::new(),
+            mem_access: Vec::new(),
+            events: Vec::new(),
+            keys: [false; 16],
+            key_wait: None,
+            key_wait_key: None,
+            key_poll: None,
+            audio_pattern: None,
+            pitch: 64,
+            quirks: Quirks::default(),
+            vblank_wait: false,
+            history_limit: HISTORY_LIMIT,
+            pixel_chars: (CHAR_ON, CHAR_OFF),
         }
         // Preload sprites to 0x0000 - 0x01ff
     }
@@ -70,8 +209,8 @@ impl Default for CPU {
 impl CPU {
     pub fn new(debug: bool) -> CPU {
         CPU {
-            ram: [0; 4096],
-            pc: PROGRAM_START as u16,
+            ram: [0; MEMORY_SIZE],
+            pc: PROGRAM_START,
             ir: 0,
             sp: 0,
             dt: 0,
@@ -82,12 +221,25 @@ impl CPU {
             rng : rand::thread_rng(),
             debug,
             exec_history: VecDeque::new(),
+            mem_access: Vec::new(),
+            events: Vec::new(),
+            keys: [false; 16],
+            key_wait: None,
+            key_wait_key: None,
+            key_poll: None,
+            audio_pattern: None,
+            pitch: 64,
+            quirks: Quirks::default(),
+            vblank_wait: false,
+            history_limit: HISTORY_LIMIT,
+            pixel_chars: (CHAR_ON, CHAR_OFF),
         }
         // Preload sprites to 0x0000 - 0x01ff
     }
 
     fn clear_display(&mut self) {
-        self.vbuf =  [0; DISPLAY_WIDTH * DISPLAY_HEIGHT / 8]
+        self.vbuf =  [0; DISPLAY_WIDTH * DISPLAY_HEIGHT / 8];
+        self.events.push(CpuEvent::Clear);
     }
 
     // Return from sub routine
@@ -109,13 +261,17 @@ impl CPU {
 
     // Call subroutine at address
     fn call(&mut self, address: u16) {
-        self.sp += 1; // Increment stack pointer
+        if self.stack.len() >= STACK_SIZE {
+            self.events.push(CpuEvent::StackOverflow);
+        }
+        self.sp = self.sp.wrapping_add(1); // Increment stack pointer
         self.stack.push(self.pc); // Save current pc to stack
         self.pc = address; // Jump to address
         return;
     }
 
     // Skip if register is equal to value
+    #[allow(clippy::assign_op_pattern)]
     fn se(&mut self, reg:u8, value:u8) {
         if self.regs[reg as usize] == value {
             self.pc = self.pc + 2;
@@ -124,6 +280,7 @@ impl CPU {
     }
 
     // Skip if register not equal to value
+    #[allow(clippy::assign_op_pattern)]
     fn sne(&mut self, reg:u8, value:u8) {
         if self.regs[reg as usize] != value {
             self.pc = self.pc + 2;
@@ -132,6 +289,7 @@ impl CPU {
     }
 
     // Skip if registers are equal
+    #[allow(clippy::assign_op_pattern)]
     fn sre(&mut self, reg1:u8, reg2:u8) {
         if self.regs[reg1 as usize] == self.regs[reg2 as usize] {
             self.pc = self.pc + 2;
@@ -143,8 +301,9 @@ impl CPU {
         self.regs[reg as usize] = value;
     }
 
+    // Add value to register, VF is not changed
     fn addc(&mut self, reg: u8, value: u8) {
-        self.regs[reg as usize] = self.regs[reg as usize] + value
+        self.regs[reg as usize] = self.regs[reg as usize].wrapping_add(value)
     }
 
     fn assignreg(&mut self, reg1:u8, reg2:u8) {
@@ -152,15 +311,25 @@ impl CPU {
     }
 
     fn bitor(&mut self, reg1:u8, reg2:u8) {
-        self.regs[reg1 as usize] |= self.regs[reg2 as usize]
+        self.regs[reg1 as usize] |= self.regs[reg2 as usize];
+        self.reset_flag();
     }
 
     fn bitand(&mut self, reg1:u8, reg2:u8) {
-        self.regs[reg1 as usize] &= self.regs[reg2 as usize]
+        self.regs[reg1 as usize] &= self.regs[reg2 as usize];
+        self.reset_flag();
     }
 
     fn bitxor(&mut self, reg1:u8, reg2:u8) {
-        self.regs[reg1 as usize] ^= self.regs[reg2 as usize]
+        self.regs[reg1 as usize] ^= self.regs[reg2 as usize];
+        self.reset_flag();
+    }
+
+    // Logic instructions clear VF on the COSMAC VIP
+    fn reset_flag(&mut self) {
+        if self.quirks.logic {
+            self.regs[0x0f] = 0;
+        }
     }
 
     fn addreg(&mut self, reg1:u8, reg2:u8) {
@@ -169,29 +338,33 @@ impl CPU {
         self.regs[0x0f] = ((sum & 0xff00) > 0) as u8;
     }
 
+    // VF is 1 when there is no borrow
     fn subreg(&mut self, reg1:u8, reg2:u8) {
-        let sub: u16 = self.regs[reg1 as usize] as u16 - self.regs[reg2 as usize] as u16;
-        self.regs[reg1 as usize] = (sub & 0x00ff) as u8;
-        self.regs[0x0f] = (!(sub & 0xff00) > 0) as u8;
+        let (sub, borrow) = self.regs[reg1 as usize].overflowing_sub(self.regs[reg2 as usize]);
+        self.regs[reg1 as usize] = sub;
+        self.regs[0x0f] = !borrow as u8;
     }
 
     // Subtract with reversed order
     fn subregrev(&mut self, reg1:u8, reg2:u8) {
-        let sub: u16 = self.regs[reg2 as usize] as u16 - self.regs[reg1 as usize] as u16;
-        self.regs[reg1 as usize] = (sub & 0x00ff) as u8;
-        self.regs[0x0f] = (!(sub & 0xff00) > 0) as u8;
+        let (sub, borrow) = self.regs[reg2 as usize].overflowing_sub(self.regs[reg1 as usize]);
+        self.regs[reg1 as usize] = sub;
+        self.regs[0x0f] = !borrow as u8;
     }
 
-    fn rshiftreg(&mut self, reg: u8) {
+    fn rshiftreg(&mut self, reg1: u8, reg2: u8) {
+        // Original CHIP-8 shifts VY into VX
+        let value = if self.quirks.shift { self.regs[reg1 as usize] } else { self.regs[reg2 as usize] };
+        self.regs[reg1 as usize] = value >> 1;
         // Store lsb to F
-        self.regs[0x0f] = self.regs[reg as usize] & 0x01;
-        self.regs[reg as usize] >>= 1;
+        self.regs[0x0f] = value & 0x01;
     }
 
-    fn lshiftreg(&mut self, reg: u8) {
+    fn lshiftreg(&mut self, reg1: u8, reg2: u8) {
+        let value = if self.quirks.shift { self.regs[reg1 as usize] } else { self.regs[reg2 as usize] };
+        self.regs[reg1 as usize] = value << 1;
         // Store msb to F
-        self.regs[0x0f] = self.regs[reg as usize] & 0x80;
-        self.regs[reg as usize] <<= 1;
+        self.regs[0x0f] = value >> 7;
     }
 
     fn snereg(&mut self, reg1:u8, reg2:u8) {
@@ -205,7 +378,9 @@ impl CPU {
     }
 
     fn gotoreg(&mut self, address: u16) {
-        self.pc = self.regs[0] as u16 + address;
+        // CHIP-48 and SUPER-CHIP read BXNN as XNN + VX
+        let reg = if self.quirks.jump { address >> 8 & 0xf } else { 0 };
+        self.pc = self.regs[reg as usize] as u16 + address;
     }
 
     fn rand(&mut self, reg: u8, address: u16) {
@@ -214,68 +389,71 @@ impl CPU {
     }
 
     fn draw(&mut self, reg1: u8, reg2: u8, height: u8) {
-        let x_px = self.regs[reg1 as usize]; // starting pixel x
-        let y_px = self.regs[reg2 as usize]; // starting pixel y
-
-        // Wrap
-        let x_px = x_px % DISPLAY_WIDTH as u8;
-        let y_px = y_px % DISPLAY_HEIGHT as u8;
-
-        let vbuf_x:usize = (x_px / 8) as usize; // vbuf x element. vbuf y is same a pixel y
-        let offset_x = x_px % 8;
-
-        if vbuf_x >= DISPLAY_WIDTH {
-            // Shound never happen
-            return;
-        }
-
-
-        for line in 0..height {
-            if line as usize >= DISPLAY_HEIGHT {
+        // Like the COSMAC VIP wait for vertical blank, one draw per frame
+        if self.quirks.vblank {
+            if self.vblank_wait {
+                self.pc -= 2;
                 return;
             }
+            self.vblank_wait = true;
+        }
 
-            // Get starting line of sprite from ram
-            let sprite = self.ram[self.ir as usize + line as usize];
-
-            // First element
-            let element = vbuf_x + DISPLAY_WIDTH / 8 * (y_px + line) as usize;
-
-
-            let x = self.vbuf[element];
-            let bits1= x ^ sprite >> offset_x;
-            self.vbuf[element] = bits1;
+        // Starting position always wraps
+        let x_px = self.regs[reg1 as usize] as usize % DISPLAY_WIDTH;
+        let y_px = self.regs[reg2 as usize] as usize % DISPLAY_HEIGHT;
 
-            // Check if any bits were turned off with AND
-            if x & bits1 != x {
-                self.regs[0x0F] = 1;
+        let mut collision = false;
+        for line in 0..height as usize {
+            let y = y_px + line;
+            if y >= DISPLAY_HEIGHT && !self.quirks.wrap {
+                break;
             }
 
-            // Second element if screen not wrapping
-            if vbuf_x < DISPLAY_WIDTH && offset_x > 0{
-                let x = self.vbuf[element + 1];
-                let bits2= x ^ sprite << (8-offset_x);
-                self.vbuf[element + 1] = bits2;
+            // Get line of sprite from ram
+            let sprite = self.read_mem(self.ir as usize + line, AccessKind::Read);
 
-                // Check if any bits were turned off with AND
-                if x & bits2 != x {
-                    self.regs[0x0F] = 1;
+            for bit in 0..8 {
+                let x = x_px + bit;
+                if x >= DISPLAY_WIDTH && !self.quirks.wrap {
+                    break;
                 }
+                if sprite & (0x80 >> bit) == 0 {
+                    continue;
+                }
+                let (x, y) = (x % DISPLAY_WIDTH, y % DISPLAY_HEIGHT);
+                let element = x / 8 + DISPLAY_WIDTH / 8 * y;
+                let mask = 0x80 >> (x % 8);
+
+                // Pixel turned off
+                if self.vbuf[element] & mask != 0 {
+                    collision = true;
+                }
+                self.vbuf[element] ^= mask;
             }
         }
+        self.regs[0x0F] = collision as u8;
+        self.events.push(CpuEvent::Draw { collision });
         if self.debug {
             self.print_vbuf();
         }
     }
 
     // Skip if key is pressed with value in register
-    fn skp(&mut self, _reg: u8) {
-        return
+    fn skp(&mut self, reg: u8) {
+        let key = self.regs[reg as usize] & 0x0f;
+        self.key_poll = Some(key);
+        if self.keys[key as usize] {
+            self.pc += 2;
+        }
     }
 
     // Skip if key is not pressed with value in register
-    fn sknp(&mut self, _reg: u8) {
-        return
+    fn sknp(&mut self, reg: u8) {
+        let key = self.regs[reg as usize] & 0x0f;
+        self.key_poll = Some(key);
+        if !self.keys[key as usize] {
+            self.pc += 2;
+        }
     }
 
     // Read value of dt to register
@@ -290,18 +468,51 @@ impl CPU {
     }
 
     // Wait for keypress and store to reg
-    fn waitkp(&mut self, _reg: u8) {
-        return;
+    // Like the COSMAC VIP the key is stored once it has been pressed and released
+    fn waitkp(&mut self, reg: u8) {
+        match self.key_wait_key {
+            Some(key) if !self.keys[key as usize] => {
+                self.regs[reg as usize] = key;
+                self.key_wait = None;
+                self.key_wait_key = None;
+                return;
+            }
+            Some(_) => {}
+            None => self.key_wait_key = self.keys.iter().position(|k| *k).map(|k| k as u8),
+        }
+        // Repeat instruction until key is released
+        if self.key_wait.is_none() {
+            self.events.push(CpuEvent::KeyWait(reg));
+        }
+        self.key_wait = Some(reg);
+        self.pc -= 2;
     }
 
     // Set value of register to sound timer
     fn setst(&mut self, reg: u8) {
+        if self.st == 0 && self.regs[reg as usize] > 0 {
+            self.events.push(CpuEvent::SoundStart);
+        }
         self.st = self.regs[reg as usize];
     }
 
+    // XO-CHIP: load 16 byte audio pattern from memory at index register
+    fn loadaudio(&mut self) {
+        let mut pattern = [0; 16];
+        for (idx, byte) in pattern.iter_mut().enumerate() {
+            *byte = self.read_mem(self.ir as usize + idx, AccessKind::Read);
+        }
+        self.audio_pattern = Some(pattern);
+    }
+
+    // XO-CHIP: set pattern playback pitch from register
+    fn setpitch(&mut self, reg: u8) {
+        self.pitch = self.regs[reg as usize];
+    }
+
     // Add value of register to index register
     fn addi(&mut self, reg: u8) {
-        self.ir += self.regs[reg as usize] as u16;
+        self.ir = self.ir.wrapping_add(self.regs[reg as usize] as u16);
     }
 
     // Set location of sprite in private memory that matches value of register to index register
@@ -311,54 +522,92 @@ impl CPU {
 
     // Store BCD (Binary Coded Decimal) of value in register to three bytes starting from index register
     fn setbcd(&mut self, reg: u8) {
-        let s: String = self.regs[reg as usize].to_string();
-        self.ram[self.ir as usize] = s.chars().nth(0).unwrap_or('0') as u8;
-        self.ram[self.ir as usize + 1] = s.chars().nth(1).unwrap_or('0') as u8;
-        self.ram[self.ir as usize + 2] = s.chars().nth(2).unwrap_or('0') as u8;
+        let value = self.regs[reg as usize];
+        self.write_mem(self.ir as usize, value / 100);
+        self.write_mem(self.ir as usize + 1, value / 10 % 10);
+        self.write_mem(self.ir as usize + 2, value % 10);
     }
 
     // Store registers from 0 to register starting at address in index register
     fn regsstore(&mut self, reg: u8) {
         for r in 0..=reg {
-            self.ram[self.ir as usize + r as usize] = self.regs[r as usize];
+            self.write_mem(self.ir as usize + r as usize, self.regs[r as usize]);
         }
+        self.advance_index(reg);
     }
 
     // Load values starting from index register to registers from 0 to given register
     fn regsload(&mut self, reg: u8) {
         for r in 0..=reg {
-            self.regs[r as usize] = self.ram[self.ir as usize + r as usize];
+            self.regs[r as usize] = self.read_mem(self.ir as usize + r as usize, AccessKind::Read);
         }
+        self.advance_index(reg);
     }
 
+    // Index register after FX55 and FX65, original CHIP-8 leaves it past the last register
+    fn advance_index(&mut self, reg: u8) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        let count = if self.quirks.memory_increment_by_x { reg } else { reg + 1 };
+        self.ir = self.ir.wrapping_add(count as u16);
+    }
+
+
+    // Read byte from ram and record the access for watchpoints. Addresses past
+    // the end of memory wrap around, I can point anywhere.
+    fn read_mem(&mut self, address: usize, kind: AccessKind) -> u8 {
+        let address = address % MEMORY_SIZE;
+        let value = self.ram[address];
+        self.mem_access.push(MemAccess { address: address as u16, kind, old: value, new: value });
+        return value;
+    }
+
+    // Write byte to ram and record the access for watchpoints
+    fn write_mem(&mut self, address: usize, value: u8) {
+        let address = address % MEMORY_SIZE;
+        let old = self.ram[address];
+        self.ram[address] = value;
+        self.mem_access.push(MemAccess { address: address as u16, kind: AccessKind::Write, old, new: value });
+    }
+
+    // Program counter points at a whole instruction, the last one starts at FFE
+    pub fn pc_in_memory(&self) -> bool {
+        return (self.pc as usize) + 1 < self.ram.len();
+    }
 
+    #[allow(clippy::assign_op_pattern)]
     fn fetch(&mut self) -> u16 {
-        // Addressing out of bounds
-        if self.pc as usize + 2 >= self.ram.len() {
+        // Callers check pc_in_memory first
+        if !self.pc_in_memory() {
             panic!("PC out of bounds");
         }
-        let high: u16  = (self.ram[self.pc as usize] as u16) << 8;
-        let low: u16 = self.ram[self.pc as usize + 1] as u16;
+        // Fetch starts a new cycle
+        self.mem_access.clear();
+        self.events.clear();
+        let high: u16  = (self.read_mem(self.pc as usize, AccessKind::Fetch) as u16) << 8;
+        let low: u16 = self.read_mem(self.pc as usize + 1, AccessKind::Fetch) as u16;
         self.pc = self.pc + 2;
         return high | low;
     }
 
-    pub fn fetch_no_increment(&self) -> u16 {
-        if self.pc as usize + 2 >= self.ram.len() {
-            panic!("PC out of bounds");
+    // Next instruction, None when the program counter left memory
+    pub fn fetch_no_increment(&self) -> Option<u16> {
+        if !self.pc_in_memory() {
+            return None;
         }
         let high: u16  = (self.ram[self.pc as usize] as u16) << 8;
         let low: u16 = self.ram[self.pc as usize + 1] as u16;
-        return high | low;
+        return Some(high | low);
     }
 
     // TODO: Consider splitting u16 to 2 u8s before function call
     fn exec(&mut self, ins: u16) {
         // Push command to history
-        if self.exec_history.len() >= HISTORY_LIMIT {
+        while !self.exec_history.is_empty() && self.exec_history.len() >= self.history_limit {
             self.exec_history.pop_front();
         }
-        self.exec_history.push_back(ins);
+        self.exec_history.push_back((self.pc - 2, ins));
 
         // Decode command and execute matched
         match ins & 0xf000 {
@@ -371,32 +620,33 @@ impl CPU {
             0x2000 => self.call(ins & 0x0fff),
             0x3000 => self.se((ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8),
             0x4000 => self.sne((ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8),
-            0x5000 => self.sre((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
+            0x5000 => self.sre((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
             0x6000 => {self.setreg((ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8)}, // TODO: this is the correct way to mask!!!!!
             0x7000 => self.addc((ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8),
             0x8000 => match ins & 0x000f {
-                0x00 => self.assignreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-                0x01 => self.bitor((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-                0x02 => self.bitand((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-                0x03 => self.bitxor((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-                0x04 => self.addreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-                0x05 => self.subreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-                0x06 => self.rshiftreg((ins >> 8 & 0xf) as u8), // TODO: Handle ambiguity
-                0x07 => self.subregrev((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-                0x0E => self.lshiftreg((ins >> 8 & 0xf) as u8),
+                0x00 => self.assignreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x01 => self.bitor((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x02 => self.bitand((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x03 => self.bitxor((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x04 => self.addreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x05 => self.subreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x06 => self.rshiftreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x07 => self.subregrev((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+                0x0E => self.lshiftreg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
                 _ => return,
             }
-            0x9000 => self.snereg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
+            0x9000 => self.snereg((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
             0xA000 => self.seti(ins & 0x0fff),
             0xB000 => self.gotoreg(ins & 0x0fff),
             0xC000 => self.rand((ins >> 8 & 0xf) as u8, ins & 0x00ff),
             0xD000 => self.draw((ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8, (ins & 0x000f) as u8),
             0xE000 => match ins & 0x00ff {
-                0x9E => self.skp((ins & 0xf00 >> 8) as u8),
-                0xA1 => self.sknp((ins & 0xf00 >> 8) as u8),
+                0x9E => self.skp((ins >> 8 & 0xf) as u8),
+                0xA1 => self.sknp((ins >> 8 & 0xf) as u8),
                 _ => return,
             }
             0xF000 => match ins & 0x00ff {
+                0x02 if ins == 0xF002 => self.loadaudio(),
                 0x07 => self.getdt((ins >> 8 & 0x0f) as u8),
                 0x0A => self.waitkp((ins >> 8 & 0x0f) as u8),
                 0x15 => self.setdt((ins >> 8 & 0x0f) as u8),
@@ -404,6 +654,7 @@ impl CPU {
                 0x1E => self.addi((ins >> 8 & 0x0f) as u8),
                 0x29 => self.setisprite((ins >> 8 & 0x0f) as u8),
                 0x33 => self.setbcd((ins >> 8 & 0x0f) as u8),
+                0x3A => self.setpitch((ins >> 8 & 0x0f) as u8),
                 0x55 => self.regsstore((ins >> 8 & 0x0f) as u8),
                 0x65 => self.regsload((ins >> 8 & 0x0f) as u8),
                 _ => return,
@@ -413,8 +664,22 @@ impl CPU {
         }
     }
 
+    // Audio pattern and pitch once a ROM has loaded one, beeper otherwise
+    pub fn get_audio_pattern(&self) -> Option<([u8; 16], u8)> {
+        return self.audio_pattern.map(|pattern| (pattern, self.pitch));
+    }
+
+    // Count delay and sound timers down and start a new frame, called at TIMER_RATE
+    pub fn tick_timers(&mut self) {
+        self.vblank_wait = false;
+        // Keypad shows only keys polled since the last tick
+        self.key_poll = None;
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+    }
+
     pub fn next_cycle(&mut self) -> i32 {
-        if self.pc >= 4095 {
+        if !self.pc_in_memory() {
             return -1;
         }
         let instruction = self.fetch();
@@ -422,45 +687,47 @@ impl CPU {
         return 0;
     }
 
-    pub fn run(&mut self) -> i32 {
-        loop {
-            let instruction = self.fetch();
-            // Print current pc and instruction
-            if self.debug {
-                println!("PC: {:04X} INS: {:04X}", self.pc - 2, instruction);
-            }
-            self.exec(instruction);
-            if self.pc >= 4095 {
-                return -1;
-            }
-        }
-    }
-
     fn render_sprite_line(&self, sprite_line: &u8) -> String {
         let mut s: String = "".to_string();
         for px in [0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01] {
             match sprite_line & px {
-                0 => s.push(CHAR_OFF),
-                _px => s.push(CHAR_ON),
+                0 => s.push(self.pixel_chars.1),
+                _px => s.push(self.pixel_chars.0),
             }
         }
         return s;
     }
 
+    // Return addresses of active subroutine calls, innermost last
+    pub fn get_stack(&self) -> &[u16] {
+        return &self.stack;
+    }
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.keys[key as usize & 0x0f] = pressed;
+    }
+
+    pub fn get_keys(&self) -> [bool; 16] {
+        return self.keys;
+    }
+
+    pub fn set_register(&mut self, reg: u8, value: u8) {
+        self.regs[reg as usize] = value;
+    }
+
     pub fn get_registers(&self) -> [u8; 16] {
         return self.regs;
     }
 
-    pub fn print_registers(&mut self) {
-        println!("PC: {:04X}", self.pc);
-        println!("I: {:04X}", self.ir);
-        println!("SP: {:04X}", self.sp);
-        println!("DT: {:02X}", self.dt);
-        println!("ST: {:02X}", self.st);
-        println!("V0: {:02X} V1: {:02X} V2: {:02X} V3: {:02X}", self.regs[0], self.regs[1], self.regs[2], self.regs[3]);
-        println!("V4: {:02X} V5: {:02X} V6: {:02X} V7: {:02X}", self.regs[4], self.regs[5], self.regs[6], self.regs[7]);
-        println!("V8: {:02X} V9: {:02X} VA: {:02X} VB: {:02X}", self.regs[8], self.regs[9], self.regs[10], self.regs[11]);
-        println!("VC: {:02X} VD: {:02X} VE: {:02X} VF: {:02X}", self.regs[12], self.regs[13], self.regs[14], self.regs[15]);
+    // Display as one bool per pixel, row by row
+    pub fn get_pixels(&self) -> Vec<bool> {
+        let mut pixels = Vec::with_capacity(DISPLAY_WIDTH * DISPLAY_HEIGHT);
+        for y in 0..DISPLAY_HEIGHT {
+            for x in 0..DISPLAY_WIDTH {
+                pixels.push(self.read_vbuf(x as u8, y as u8));
+            }
+        }
+        return pixels;
     }
 
     pub fn read_vbuf(&self, x: u8, y: u8) -> bool {
@@ -476,6 +743,7 @@ impl CPU {
         // return bits & (0x80 >> bit) != 0;
     }
 
+    #[allow(clippy::print_with_newline)]
     pub fn print_vbuf(&mut self) {
         for (idx, sprite) in self.vbuf.iter().enumerate() {
             if idx * 8 % DISPLAY_WIDTH == 0 {
@@ -487,35 +755,180 @@ impl CPU {
         print!("");
     }
 
+    pub fn read_ram(&self, address: u16) -> u8 {
+        return self.ram[address as usize % self.ram.len()];
+    }
+
+    // Write byte without recording an access, used by debugger edits
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        let len = self.ram.len();
+        self.ram[address as usize % len] = value;
+    }
+
     pub fn get_memory(&self) -> Vec<u8> {
         return self.ram.clone().to_vec();
     }
 
-    pub fn print_memory(&mut self) {
-        for (idx, byte) in self.ram.iter().enumerate() {
-            if idx % 16 == 0 {
-                print!("\n{:04x} | ", idx);
-            }
-            print!("{:02x} ", byte);
-        }
-        print!("\n");
+    pub fn get_history(&self) -> VecDeque<(u16, u16)> {
+        return self.exec_history.clone();
     }
 
-    pub fn get_history(&self) -> VecDeque<u16> {
-        return self.exec_history.clone();
+    // Address and instruction executed last
+    pub fn last_executed(&self) -> Option<(u16, u16)> {
+        return self.exec_history.back().copied();
     }
 
-    pub fn load_bin(&mut self, binary: Vec<u8>, override_ram: bool) {
-        // Override is used to prevent writing over the preloaded ram from 0x00 to 0x1ff
-        if override_ram {
-            for (idx, byte) in binary.iter().enumerate() {
-                self.ram[idx] = *byte;
-            }
-        } else {
-            for (idx, byte) in binary[PROGRAM_START..binary.len()].iter().enumerate() {
-                self.ram[idx + PROGRAM_START] = *byte;
-            }
+    pub fn get_mem_accesses(&self) -> &[MemAccess] {
+        return &self.mem_access;
+    }
+
+    pub fn get_events(&self) -> &[CpuEvent] {
+        return &self.events;
+    }
+
+    // Copy program into memory and start executing at its load address
+    pub fn load_program(&mut self, program: &Program) -> Result<(), String> {
+        program.validate()?;
+        let start = program.address as usize;
+        self.ram[start..start + program.rom.len()].copy_from_slice(&program.rom);
+        if let Some((data, address)) = &program.data {
+            let start = *address as usize;
+            self.ram[start..start + data.len()].copy_from_slice(data);
         }
-        return;
+        for (address, bytes) in &program.patches {
+            let start = *address as usize;
+            self.ram[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        self.pc = program.address;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CPU after running instructions of rom loaded at 200
+    fn run(rom: &[u16], cycles: usize) -> CPU {
+        let mut cpu = CPU::new(false);
+        let rom = rom.iter().flat_map(|ins| ins.to_be_bytes()).collect();
+        cpu.load_program(&Program { rom, address: PROGRAM_START, data: None, patches: Vec::new() }).unwrap();
+        for _ in 0..cycles {
+            assert_eq!(cpu.next_cycle(), 0);
+        }
+        return cpu;
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        // V0 = FF + 2, V1 = 1 - 2 with borrow
+        let cpu = run(&[0x60FF, 0x7002, 0x6101, 0x6202, 0x8125], 5);
+        assert_eq!(&cpu.get_registers()[..2], &[0x01, 0xFF]);
+        assert_eq!(cpu.get_registers()[0xF], 0);
+        // V1 = 3 - 1 reversed, no borrow
+        let cpu = run(&[0x6101, 0x6203, 0x8127], 3);
+        assert_eq!((cpu.get_registers()[1], cpu.get_registers()[0xF]), (0x02, 1));
+        let cpu = run(&[0x6101, 0x6203, 0x8217], 3);
+        assert_eq!((cpu.get_registers()[2], cpu.get_registers()[0xF]), (0xFE, 0));
+
+        // Index register wraps at 16 bits
+        let mut cpu = run(&[0x60FF, 0xF01E], 0);
+        cpu.ir = 0xFFF0;
+        cpu.next_cycle();
+        cpu.next_cycle();
+        assert_eq!(cpu.ir, 0x00EF);
+    }
+
+    #[test]
+    fn memory_wraps() {
+        // FX55, FX33 and FX65 around the end of memory
+        let mut cpu = run(&[0x6001, 0x6102, 0x6203, 0xF255, 0x60FE, 0xF033, 0xF265], 0);
+        cpu.ir = 0x0FFE;
+        for _ in 0..4 {
+            cpu.next_cycle();
+        }
+        assert_eq!((cpu.read_ram(0xFFE), cpu.read_ram(0xFFF), cpu.read_ram(0x000)), (1, 2, 3));
+        cpu.next_cycle();
+        cpu.next_cycle();
+        assert_eq!((cpu.read_ram(0xFFE), cpu.read_ram(0xFFF), cpu.read_ram(0x000)), (2, 5, 4));
+        cpu.next_cycle();
+        assert_eq!(&cpu.get_registers()[..3], &[2, 5, 4]);
+    }
+
+    #[test]
+    fn bcd() {
+        let mut cpu = run(&[0x607B, 0xF033, 0x6009, 0xF033], 0);
+        cpu.ir = 0x300;
+        cpu.next_cycle();
+        cpu.next_cycle();
+        assert_eq!(&cpu.get_memory()[0x300..0x303], &[1, 2, 3]);
+        cpu.next_cycle();
+        cpu.next_cycle();
+        assert_eq!(&cpu.get_memory()[0x300..0x303], &[0, 0, 9]);
+    }
+
+    #[test]
+    fn register_operands() {
+        // Y is the third nibble for 5XY0, 8XY_ and 9XY0
+        let cpu = run(&[0x6105, 0x8010, 0x5010, 0x6207, 0x6307, 0x9230, 0x6409, 0x6509], 6);
+        assert_eq!(&cpu.get_registers()[..6], &[5, 5, 0, 7, 0, 9]);
+        let cpu = run(&[0x6105, 0x5010, 0x6009], 3);
+        assert_eq!(cpu.get_registers()[0], 9);
+    }
+
+    #[test]
+    fn shift_flag() {
+        // VF holds the bit shifted out as 0 or 1
+        let cpu = run(&[0x6081, 0x800E], 2);
+        assert_eq!((cpu.get_registers()[0], cpu.get_registers()[0xF]), (0x02, 1));
+        let cpu = run(&[0x6081, 0x8006], 2);
+        assert_eq!((cpu.get_registers()[0], cpu.get_registers()[0xF]), (0x40, 1));
+        let mut cpu = run(&[0x6040, 0x6181, 0x801E], 0);
+        cpu.quirks.shift = false;
+        for _ in 0..3 {
+            cpu.next_cycle();
+        }
+        assert_eq!((cpu.get_registers()[0], cpu.get_registers()[0xF]), (0x02, 1));
+    }
+
+    #[test]
+    fn draw_clips_and_wraps() {
+        // Sprite FF at x 62 drawn twice
+        let rom = [0x00E0, 0x603E, 0x6100, 0xA20E, 0xD011, 0xD011, 0x120C, 0xFF00];
+        let mut cpu = run(&rom, 5);
+        let row = |cpu: &CPU| (0..64).filter(|x| cpu.read_vbuf(*x, 0)).collect::<Vec<u8>>();
+        assert_eq!(row(&cpu), vec![62, 63]);
+        assert_eq!(cpu.get_registers()[0xF], 0);
+        cpu.next_cycle();
+        assert!(row(&cpu).is_empty());
+        assert_eq!(cpu.get_registers()[0xF], 1);
+
+        let mut cpu = run(&rom, 0);
+        cpu.quirks.wrap = true;
+        for _ in 0..5 {
+            cpu.next_cycle();
+        }
+        assert_eq!(row(&cpu), vec![0, 1, 2, 3, 4, 5, 62, 63]);
+
+        // Start position wraps even when clipping
+        let cpu = run(&[0x00E0, 0x6042, 0x6120, 0xA20E, 0xD011, 0x120A, 0x0000, 0x8000], 5);
+        assert_eq!(row(&cpu), vec![2]);
+    }
+
+    #[test]
+    fn deep_calls() {
+        // Calling itself overflows the stack but keeps running
+        let cpu = run(&[0x2200], 300);
+        assert_eq!(cpu.get_stack().len(), 300);
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn key_poll_cleared_on_tick() {
+        // V0 = 5, skip if key 5 is pressed
+        let mut cpu = run(&[0x6005, 0xE09E], 2);
+        assert_eq!(cpu.key_poll, Some(5));
+        cpu.tick_timers();
+        assert_eq!(cpu.key_poll, None);
     }
 }
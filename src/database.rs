@@ -0,0 +1,284 @@
+use crate::config;
+use crate::cpu::{Quirks, CLOCK_SPEED, TIMER_RATE};
+use crate::json::Json;
+use std::fs;
+use std::path::Path;
+
+// ROM database in the chip-8-database format. ROMs are found by the SHA-1
+// of the file:
+//
+//   sha1-hashes.json   {"<sha1>": <index into programs.json>}
+//   programs.json      [{"title", "authors", "roms": {"<sha1>": {...}}}]
+//   platforms.json     [{"id", "name", "defaultTickrate", "quirks"}]
+//
+// A copy is built into the binary from database/. Only platforms.json is
+// filled in there; programs.json and sha1-hashes.json are empty until the
+// upstream files (github.com/chip-8/chip-8-database) are copied over
+// them. A full checkout of the upstream database directory is used instead
+// when it is given on the command line or installed as
+// ~/.config/chip_8/database.
+
+const PLATFORMS: &str = include_str!("../database/platforms.json");
+const PROGRAMS: &str = include_str!("../database/programs.json");
+const HASHES: &str = include_str!("../database/sha1-hashes.json");
+
+pub const DATABASE_FILES: [&str; 3] = ["platforms.json", "programs.json", "sha1-hashes.json"];
+
+#[derive(Clone, Debug)]
+pub struct Platform {
+    pub id: String,
+    pub name: String,
+    pub tick_rate: u64, // Instructions per frame
+    pub quirks: Quirks,
+}
+
+// Settings found for a ROM
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    pub platform: Option<Platform>, // None when the database names an unknown platform
+    pub quirks: Quirks,
+    pub tick_rate: u64,
+    pub start_address: Option<u16>,
+    pub keys: Vec<(String, u8)>, // Input name such as "up" or "a" and keypad key
+    pub colors: Vec<String>, // "#rrggbb" for each pixel plane value, background first
+}
+
+impl RomInfo {
+    // "<title> by <authors>" for status lines
+    pub fn label(&self) -> String {
+        if self.authors.is_empty() {
+            return self.title.clone();
+        }
+        return format!("{} by {}", self.title, self.authors.join(", "));
+    }
+}
+
+pub struct Database {
+    platforms: Vec<Platform>,
+    programs: Json,
+    hashes: Json,
+}
+
+impl Database {
+    pub fn bundled() -> Database {
+        return Database::parse(PLATFORMS, PROGRAMS, HASHES).expect("Bundled ROM database is invalid");
+    }
+
+    // Database from a directory holding the three JSON files
+    pub fn load(dir: &str) -> Result<Database, String> {
+        let mut texts = Vec::new();
+        for name in DATABASE_FILES {
+            let path = Path::new(dir).join(name);
+            texts.push(fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+        return Database::parse(&texts[0], &texts[1], &texts[2]);
+    }
+
+    // Database in dir, else an installed copy, else the bundled one
+    pub fn find(dir: Option<&str>) -> Result<Database, String> {
+        if let Some(dir) = dir {
+            return Database::load(dir);
+        }
+        let installed = config::config_dir().map(|dir| dir.join("database"))
+            .filter(|dir| DATABASE_FILES.iter().all(|name| dir.join(name).is_file()));
+        return match installed {
+            Some(dir) => Database::load(&dir.to_string_lossy()),
+            None => Ok(Database::bundled()),
+        };
+    }
+
+    fn parse(platforms: &str, programs: &str, hashes: &str) -> Result<Database, String> {
+        let platforms = Json::parse(platforms).map_err(|e| format!("platforms.json: {}", e))?;
+        let platforms = platforms.as_array().iter().filter_map(|platform| {
+            let id = platform.get("id")?.as_str()?.to_string();
+            let name = platform.get("name").and_then(Json::as_str).unwrap_or(&id).to_string();
+            let tick_rate = platform.get("defaultTickrate").and_then(Json::as_f64).map(|t| t as u64)
+                .unwrap_or(CLOCK_SPEED / TIMER_RATE);
+            let quirks = parse_quirks(platform.get("quirks"), Quirks::default());
+            Some(Platform { id, name, tick_rate, quirks })
+        }).collect();
+        return Ok(Database {
+            platforms,
+            programs: Json::parse(programs).map_err(|e| format!("programs.json: {}", e))?,
+            hashes: Json::parse(hashes).map_err(|e| format!("sha1-hashes.json: {}", e))?,
+        });
+    }
+
+    pub fn platform(&self, id: &str) -> Option<&Platform> {
+        return self.platforms.iter().find(|platform| platform.id == id);
+    }
+
+    pub fn platform_ids(&self) -> Vec<&str> {
+        return self.platforms.iter().map(|platform| platform.id.as_str()).collect();
+    }
+
+    // Settings for ROM contents, None for unknown ROMs
+    pub fn lookup(&self, binary: &[u8]) -> Option<RomInfo> {
+        let hash = sha1_hex(binary);
+        let index = self.hashes.get(&hash)?.as_f64()? as usize;
+        let program = self.programs.as_array().get(index)?;
+        let rom = program.get("roms")?.get(&hash)?;
+
+        // First listed platform is the one the ROM was written for
+        let platform_id = rom.get("platforms").and_then(|p| p.as_array().first()).and_then(Json::as_str);
+        let platform = platform_id.and_then(|id| self.platform(id)).cloned();
+        let mut quirks = platform.as_ref().map(|p| p.quirks).unwrap_or_default();
+        if let Some(id) = platform_id {
+            quirks = parse_quirks(rom.get("quirkyPlatforms").and_then(|q| q.get(id)), quirks);
+        }
+        let tick_rate = rom.get("tickrate").and_then(Json::as_f64).map(|t| t as u64)
+            .or(platform.as_ref().map(|p| p.tick_rate))
+            .unwrap_or(CLOCK_SPEED / TIMER_RATE);
+
+        let title = program.get("title").and_then(Json::as_str)
+            .or(rom.get("file").and_then(Json::as_str))
+            .unwrap_or("Unknown")
+            .to_string();
+        let authors = program.get("authors").map(|a| a.as_array().iter().filter_map(Json::as_str).map(str::to_string).collect())
+            .unwrap_or_default();
+        let keys = rom.get("keys").map(|k| k.as_object().iter()
+            .filter_map(|(name, key)| Some((name.clone(), key.as_f64()? as u8)))
+            .filter(|(_, key)| *key < 16)
+            .collect()
+        ).unwrap_or_default();
+        let colors = rom.get("colors").and_then(|c| c.get("pixels"))
+            .map(|p| p.as_array().iter().filter_map(Json::as_str).map(str::to_string).collect())
+            .unwrap_or_default();
+
+        return Some(RomInfo {
+            title,
+            authors,
+            release: program.get("release").and_then(Json::as_str).map(str::to_string),
+            platform,
+            quirks,
+            tick_rate: tick_rate.max(1),
+            start_address: rom.get("startAddress").and_then(Json::as_f64).map(|a| a as u16),
+            keys,
+            colors,
+        });
+    }
+}
+
+// Quirks object over base, missing quirks keep the base value
+fn parse_quirks(json: Option<&Json>, base: Quirks) -> Quirks {
+    let json = match json {
+        Some(json) => json,
+        None => return base,
+    };
+    let flag = |name: &str, default: bool| json.get(name).and_then(Json::as_bool).unwrap_or(default);
+    return Quirks {
+        shift: flag("shift", base.shift),
+        memory_increment_by_x: flag("memoryIncrementByX", base.memory_increment_by_x),
+        memory_leave_i_unchanged: flag("memoryLeaveIUnchanged", base.memory_leave_i_unchanged),
+        wrap: flag("wrap", base.wrap),
+        jump: flag("jump", base.jump),
+        vblank: flag("vblank", base.vblank),
+        logic: flag("logic", base.logic),
+    };
+}
+
+// Lowercase hex SHA-1 digest, as used for database keys
+pub fn sha1_hex(data: &[u8]) -> String {
+    return sha1(data).iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // Pad with a one bit, zeros and the message length in bits
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, add) in h.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (idx, value) in h.iter().enumerate() {
+        digest[idx * 4..idx * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    return digest;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha1_test_vectors() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(sha1_hex(&[b'a'; 1_000_000]), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn lookup_applies_entry_over_platform() {
+        let rom = [0x60, 0x05, 0x12, 0x00];
+        let hash = sha1_hex(&rom);
+        let programs = format!(r##"[
+            {{"title": "Other", "roms": {{}}}},
+            {{"title": "Loop", "authors": ["A", "B"], "release": "1990", "roms": {{"{}": {{
+                "platforms": ["superchip"],
+                "quirkyPlatforms": {{"superchip": {{"vblank": true}}}},
+                "tickrate": 40,
+                "startAddress": 1536,
+                "keys": {{"up": 5, "a": 6, "bad": 20}},
+                "colors": {{"pixels": ["#000000", "#ffffff"]}}
+            }}}}}}
+        ]"##, hash);
+        let hashes = format!(r#"{{"{}": 1}}"#, hash);
+        let database = Database::parse(PLATFORMS, &programs, &hashes).unwrap();
+
+        let info = database.lookup(&rom).unwrap();
+        let platform = database.platform("superchip").unwrap();
+        assert_eq!(info.label(), "Loop by A, B");
+        assert_eq!(info.release.as_deref(), Some("1990"));
+        assert_eq!(info.platform.as_ref().unwrap().id, "superchip");
+        assert_eq!(info.quirks, Quirks { vblank: true, ..platform.quirks });
+        assert_eq!(info.tick_rate, 40);
+        assert_eq!(info.start_address, Some(0x600));
+        assert_eq!(info.keys, vec![("up".to_string(), 5), ("a".to_string(), 6)]);
+        assert_eq!(info.colors, vec!["#000000", "#ffffff"]);
+        assert!(database.lookup(&rom[..2]).is_none());
+    }
+
+    #[test]
+    fn bundled_platforms_parse() {
+        let database = Database::bundled();
+        assert_eq!(database.platform("originalChip8").unwrap().tick_rate, 15);
+        assert!(database.platform("originalChip8").unwrap().quirks.vblank);
+        assert!(database.platform("xochip").unwrap().quirks.wrap);
+    }
+}
@@ -0,0 +1,489 @@
+use crate::cpu::{AccessKind, CpuEvent, CPU, MEMORY_SIZE};
+use crate::expr::{self, Expr, Template};
+use std::collections::VecDeque;
+
+const LOG_LIMIT: usize = 200;
+
+pub struct Condition {
+    pub source: String,
+    expr: Expr,
+}
+
+pub struct Breakpoint {
+    pub address: Option<u16>, // None checks condition on every instruction
+    pub enabled: bool,
+    pub condition: Option<Condition>,
+    pub hits: u32,
+    pub hit_target: Option<u32>, // Only trigger once hit this many times
+    pub log_message: Option<Template>, // Logpoints write to log instead of stopping
+    pub triggered: bool, // Stopped execution on last check
+}
+
+impl Breakpoint {
+    fn new(address: Option<u16>) -> Breakpoint {
+        Breakpoint {
+            address,
+            enabled: true,
+            condition: None,
+            hits: 0,
+            hit_target: None,
+            log_message: None,
+            triggered: false,
+        }
+    }
+
+    pub fn is_logpoint(&self) -> bool {
+        return self.log_message.is_some();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read, // Includes instruction fetch
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(&self, kind: AccessKind) -> bool {
+        match self {
+            WatchKind::Read => kind != AccessKind::Write,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Access => true,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WatchKind::Read => "r",
+            WatchKind::Write => "w",
+            WatchKind::Access => "rw",
+        }
+    }
+}
+
+// Watchpoint on inclusive address range
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    pub enabled: bool,
+    pub hits: u32,
+    pub triggered: bool,
+}
+
+// Machine events that stop execution
+#[derive(Default)]
+pub struct EventBreaks {
+    pub draw: bool,
+    pub collision: bool, // Draw only when sprite collides
+    pub key_wait: bool,
+    pub sound: bool,
+    pub clear: bool,
+    pub self_modify: bool, // Write into memory that has been executed
+    pub stack_overflow: bool,
+}
+
+pub const EVENT_NAMES: [&str; 7] = ["draw", "collision", "keywait", "sound", "clear", "smc", "stack"];
+
+impl EventBreaks {
+    fn flag(&mut self, name: &str) -> Option<&mut bool> {
+        let flag = match name {
+            "draw" => &mut self.draw,
+            "collision" => &mut self.collision,
+            "keywait" => &mut self.key_wait,
+            "sound" => &mut self.sound,
+            "clear" => &mut self.clear,
+            "smc" => &mut self.self_modify,
+            "stack" => &mut self.stack_overflow,
+            _ => return None,
+        };
+        return Some(flag);
+    }
+
+    // Names of enabled events
+    pub fn names(&self) -> Vec<&'static str> {
+        let flags = [self.draw, self.collision, self.key_wait, self.sound, self.clear, self.self_modify, self.stack_overflow];
+        return EVENT_NAMES.iter().zip(flags).filter(|(_, on)| *on).map(|(name, _)| *name).collect();
+    }
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>, // Sorted by address, conditional "anywhere" breakpoints first
+    watchpoints: Vec<Watchpoint>,
+    pub events: EventBreaks,
+    code: Vec<bool>, // Addresses fetched as instructions
+    log: VecDeque<String>,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            events: EventBreaks::default(),
+            code: vec![false; MEMORY_SIZE],
+            log: VecDeque::new(),
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn push_log(&mut self, line: String) {
+        if self.log.len() >= LOG_LIMIT {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    fn insert(&mut self, bp: Breakpoint) -> usize {
+        let idx = self.breakpoints.partition_point(|b| b.address <= bp.address);
+        self.breakpoints.insert(idx, bp);
+        return idx;
+    }
+
+    // Add breakpoint to address or remove it if one already exists
+    pub fn toggle_breakpoint(&mut self, address: u16) {
+        match self.breakpoints.iter().position(|bp| bp.address == Some(address)) {
+            Some(idx) => {self.breakpoints.remove(idx);}
+            None => {self.insert(Breakpoint::new(Some(address)));}
+        }
+    }
+
+    // Add breakpoint with optional condition. Returns index of the new breakpoint.
+    pub fn add_breakpoint(&mut self, address: Option<u16>, condition: Option<&str>) -> Result<usize, String> {
+        let mut bp = Breakpoint::new(address);
+        if let Some(source) = condition {
+            bp.condition = Some(Condition { source: source.trim().to_string(), expr: expr::parse(source)? });
+        }
+        if bp.address.is_none() && bp.condition.is_none() {
+            return Err("Breakpoint without address needs a condition".to_string());
+        }
+        return Ok(self.insert(bp));
+    }
+
+    pub fn remove_breakpoint(&mut self, idx: usize) {
+        if idx < self.breakpoints.len() {
+            self.breakpoints.remove(idx);
+        }
+    }
+
+    // Enable or disable breakpoint without forgetting it
+    pub fn toggle_enabled(&mut self, idx: usize) {
+        if let Some(bp) = self.breakpoints.get_mut(idx) {
+            bp.enabled = !bp.enabled;
+        }
+    }
+
+    // Empty input clears the condition
+    pub fn set_condition(&mut self, idx: usize, source: &str) -> Result<(), String> {
+        let condition = match source.trim() {
+            "" => None,
+            s => Some(Condition { source: s.to_string(), expr: expr::parse(s)? }),
+        };
+        if let Some(bp) = self.breakpoints.get_mut(idx) {
+            if bp.address.is_none() && condition.is_none() {
+                return Err("Breakpoint without address needs a condition".to_string());
+            }
+            bp.condition = condition;
+        }
+        return Ok(());
+    }
+
+    pub fn set_hit_target(&mut self, idx: usize, target: Option<u32>) {
+        if let Some(bp) = self.breakpoints.get_mut(idx) {
+            bp.hit_target = target;
+            bp.hits = 0;
+        }
+    }
+
+    // Empty message turns logpoint back into a breakpoint
+    pub fn set_log_message(&mut self, idx: usize, message: &str) -> Result<(), String> {
+        let template = match message {
+            "" => None,
+            m => Some(Template::parse(m)?),
+        };
+        if let Some(bp) = self.breakpoints.get_mut(idx) {
+            bp.log_message = template;
+        }
+        return Ok(());
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        return self.breakpoints.iter().any(|bp| bp.address == Some(address));
+    }
+
+    // Check if execution should stop before running instruction at pc.
+    // Logpoints that trigger write their message to the log.
+    pub fn should_break(&mut self, cpu: &CPU) -> bool {
+        let mut stop = false;
+        let mut logged = Vec::new();
+        for bp in self.breakpoints.iter_mut() {
+            bp.triggered = false;
+            if !bp.enabled || bp.address.is_some_and(|addr| addr != cpu.pc) {
+                continue;
+            }
+            if let Some(condition) = &bp.condition {
+                if condition.expr.eval(cpu) == 0 {
+                    continue;
+                }
+            }
+            bp.hits += 1;
+            if bp.hit_target.is_some_and(|target| bp.hits < target) {
+                continue;
+            }
+            match &bp.log_message {
+                Some(template) => logged.push(format!("{:03X}: {}", cpu.pc, template.format(cpu))),
+                None => {
+                    bp.triggered = true;
+                    stop = true;
+                }
+            }
+        }
+        for line in logged {
+            self.push_log(line);
+        }
+        return stop;
+    }
+
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) {
+        let (start, end) = (start.min(end), start.max(end));
+        self.watchpoints.push(Watchpoint { start, end, kind, enabled: true, hits: 0, triggered: false });
+    }
+
+    pub fn remove_watchpoint(&mut self, idx: usize) {
+        if idx < self.watchpoints.len() {
+            self.watchpoints.remove(idx);
+        }
+    }
+
+    pub fn toggle_watchpoint_enabled(&mut self, idx: usize) {
+        if let Some(wp) = self.watchpoints.get_mut(idx) {
+            wp.enabled = !wp.enabled;
+        }
+    }
+
+    // Check memory accesses of the cycle that just executed. Every matching access
+    // is logged and the report of the first one is returned.
+    pub fn check_watchpoints(&mut self, cpu: &CPU) -> Option<String> {
+        let mut logged = Vec::new();
+        let (pc, ins) = cpu.last_executed().unwrap_or((cpu.pc, 0));
+        for wp in self.watchpoints.iter_mut() {
+            wp.triggered = false;
+            if !wp.enabled {
+                continue;
+            }
+            for access in cpu.get_mem_accesses() {
+                if access.address < wp.start || access.address > wp.end || !wp.kind.matches(access.kind) {
+                    continue;
+                }
+                let what = match access.kind {
+                    AccessKind::Fetch => format!("Fetch {:03X}: {:02X}", access.address, access.old),
+                    AccessKind::Read => format!("Read {:03X}: {:02X}", access.address, access.old),
+                    AccessKind::Write => format!("Write {:03X}: {:02X} -> {:02X}", access.address, access.old, access.new),
+                };
+                logged.push(format!("{} by {:03X} ({:04X})", what, pc, ins));
+                wp.triggered = true;
+            }
+            if wp.triggered {
+                wp.hits += 1;
+            }
+        }
+        let report = logged.first().cloned();
+        for line in logged {
+            self.push_log(line);
+        }
+        return report;
+    }
+
+    // Replace enabled events with space or comma separated names
+    pub fn set_event_breaks(&mut self, input: &str) -> Result<(), String> {
+        let mut events = EventBreaks::default();
+        for name in input.split(|c: char| c == ',' || c.is_whitespace()).filter(|n| !n.is_empty()) {
+            match events.flag(&name.to_lowercase()) {
+                Some(flag) => *flag = true,
+                None => return Err(format!("Unknown event '{}', expected one of: {}", name, EVENT_NAMES.join(" "))),
+            }
+        }
+        self.events = events;
+        return Ok(());
+    }
+
+    // Check machine events of the cycle that just executed. Every event that
+    // should stop is logged and the report of the first one is returned.
+    pub fn check_events(&mut self, cpu: &CPU) -> Option<String> {
+        let mut reports = Vec::new();
+        let (pc, ins) = cpu.last_executed().unwrap_or((cpu.pc, 0));
+
+        for access in cpu.get_mem_accesses() {
+            let address = access.address as usize;
+            match access.kind {
+                AccessKind::Fetch => self.code[address] = true,
+                AccessKind::Write if self.code[address] && self.events.self_modify => {
+                    reports.push(format!("Write to code {:03X}: {:02X} -> {:02X}", address, access.old, access.new));
+                }
+                _ => {}
+            }
+        }
+
+        for event in cpu.get_events() {
+            let report = match *event {
+                CpuEvent::Draw { collision } if self.events.draw || (self.events.collision && collision) => {
+                    format!("Draw{}", if collision { " with collision" } else { "" })
+                }
+                CpuEvent::Clear if self.events.clear => "Clear screen".to_string(),
+                CpuEvent::KeyWait(reg) if self.events.key_wait => format!("Wait for key into V{:X}", reg),
+                CpuEvent::SoundStart if self.events.sound => "Sound timer started".to_string(),
+                CpuEvent::StackOverflow if self.events.stack_overflow => "Stack overflow".to_string(),
+                _ => continue,
+            };
+            reports.push(report);
+        }
+
+        let reports: Vec<String> = reports.into_iter().map(|r| format!("{} at {:03X} ({:04X})", r, pc, ins)).collect();
+        for report in &reports {
+            self.push_log(report.clone());
+        }
+        return reports.into_iter().next();
+    }
+
+    pub fn get_breakpoints(&self) -> &[Breakpoint] {
+        return &self.breakpoints;
+    }
+
+    pub fn get_watchpoints(&self) -> &[Watchpoint] {
+        return &self.watchpoints;
+    }
+
+    pub fn get_log(&self) -> &VecDeque<String> {
+        return &self.log;
+    }
+}
+
+// Parse watchpoint typed by user: "<start>[-<end>] [r|w|rw]", write by default
+pub fn parse_watchpoint(input: &str) -> Option<(u16, u16, WatchKind)> {
+    let mut parts = input.split_whitespace();
+    let range = parts.next()?;
+    let kind = match parts.next() {
+        None | Some("w") => WatchKind::Write,
+        Some("r") => WatchKind::Read,
+        Some("rw") => WatchKind::Access,
+        _ => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_address(start)?, parse_address(end)?),
+        None => (parse_address(range)?, parse_address(range)?),
+    };
+    return Some((start, end, kind));
+}
+
+// Parse address typed by user. Accepts "200", "0x200" and "$200", always hex.
+pub fn parse_address(input: &str) -> Option<u16> {
+    let s = input.trim();
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).or_else(|| s.strip_prefix('$')).unwrap_or(s);
+    match u16::from_str_radix(s, 16) {
+        Ok(addr) if (addr as usize) < MEMORY_SIZE => Some(addr),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Program, PROGRAM_START};
+
+    fn cpu(rom: &[u16]) -> CPU {
+        let mut cpu = CPU::new(false);
+        let rom = rom.iter().flat_map(|ins| ins.to_be_bytes()).collect();
+        cpu.load_program(&Program { rom, address: PROGRAM_START, data: None, patches: Vec::new() }).unwrap();
+        return cpu;
+    }
+
+    #[test]
+    fn hit_count() {
+        let mut debugger = Debugger::new();
+        let cpu = cpu(&[0x1200]);
+        let idx = debugger.add_breakpoint(Some(0x200), None).unwrap();
+        debugger.set_hit_target(idx, Some(3));
+        let stops: Vec<bool> = (0..4).map(|_| debugger.should_break(&cpu)).collect();
+        assert_eq!(stops, vec![false, false, true, true]);
+        assert_eq!(debugger.get_breakpoints()[idx].hits, 4);
+
+        debugger.toggle_enabled(idx);
+        assert!(!debugger.should_break(&cpu));
+        assert_eq!(debugger.get_breakpoints()[idx].hits, 4);
+    }
+
+    #[test]
+    fn conditions_and_logpoints() {
+        let mut debugger = Debugger::new();
+        let mut cpu = cpu(&[0x1200]);
+        assert!(debugger.add_breakpoint(None, None).is_err());
+        let anywhere = debugger.add_breakpoint(None, Some("V0 == 5")).unwrap();
+        assert!(!debugger.should_break(&cpu));
+        cpu.set_register(0, 5);
+        assert!(debugger.should_break(&cpu));
+        assert!(debugger.get_breakpoints()[anywhere].triggered);
+        assert!(debugger.set_condition(anywhere, "").is_err());
+
+        let mut debugger = Debugger::new();
+        let idx = debugger.add_breakpoint(Some(0x200), Some("V0 > 1")).unwrap();
+        debugger.set_log_message(idx, "V0={V0}").unwrap();
+        assert!(!debugger.should_break(&cpu));
+        assert_eq!(debugger.get_log().back().unwrap(), "200: V0=5");
+        debugger.set_log_message(idx, "").unwrap();
+        assert!(debugger.should_break(&cpu));
+    }
+
+    #[test]
+    fn watch_ranges() {
+        // V0 and V1 stored to 300 and 301, then read back
+        let mut cpu = cpu(&[0xA300, 0xF155, 0xA300, 0xF165]);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(0x301, 0x300, WatchKind::Write);
+        debugger.add_watchpoint(0x302, 0x310, WatchKind::Access);
+        debugger.add_watchpoint(0x300, 0x300, WatchKind::Read);
+
+        cpu.next_cycle();
+        assert_eq!(debugger.check_watchpoints(&cpu), None);
+        cpu.next_cycle();
+        assert_eq!(debugger.check_watchpoints(&cpu).unwrap(), "Write 300: 00 -> 00 by 202 (F155)");
+        let hits: Vec<u32> = debugger.get_watchpoints().iter().map(|wp| wp.hits).collect();
+        assert_eq!(hits, vec![1, 0, 0]);
+        assert_eq!(debugger.get_log().len(), 2);
+
+        cpu.next_cycle();
+        cpu.next_cycle();
+        assert_eq!(debugger.check_watchpoints(&cpu).unwrap(), "Read 300: 00 by 206 (F165)");
+        assert!(debugger.get_watchpoints()[2].triggered && !debugger.get_watchpoints()[0].triggered);
+    }
+
+    #[test]
+    fn log_is_limited() {
+        let mut debugger = Debugger::new();
+        for idx in 0..LOG_LIMIT + 5 {
+            debugger.push_log(idx.to_string());
+        }
+        assert_eq!(debugger.get_log().len(), LOG_LIMIT);
+        assert_eq!(debugger.get_log().front().unwrap(), "5");
+    }
+
+    #[test]
+    fn watchpoint_input() {
+        assert_eq!(parse_watchpoint("300").map(|w| (w.0, w.1)), Some((0x300, 0x300)));
+        assert!(parse_watchpoint("300").is_some_and(|w| w.2 == WatchKind::Write));
+        assert!(parse_watchpoint("0x300-$30F r").is_some_and(|w| (w.0, w.1) == (0x300, 0x30F) && w.2 == WatchKind::Read));
+        assert!(parse_watchpoint("300 rw").is_some_and(|w| w.2 == WatchKind::Access));
+        assert!(parse_watchpoint("300 x").is_none());
+        assert!(parse_watchpoint("300 r extra").is_none());
+        assert!(parse_watchpoint("1000").is_none());
+        assert!(parse_watchpoint("").is_none());
+    }
+}
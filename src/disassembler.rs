@@ -1,31 +1,39 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+// Decode instruction, unknown instructions are shown as data bytes
 pub fn decode(ins: u16) -> String {
+    return try_decode(ins).unwrap_or_else(|| format!("DB {:02X}, {:02X}", ins >> 8, ins & 0x00ff));
+}
 
-    match ins & 0xf000 {
-        0x0000 => match ins & 0x00ff {
+pub fn try_decode(ins: u16) -> Option<String> {
+    let s = match ins & 0xf000 {
+        0x0000 => match ins {
             0x00e0 => "CLS".to_string(),
             0x00ee => "RET".to_string(),
-            _ => panic!("Unknown instruction {:X}", ins)
+            0x0000 => return None,
+            _ => format!("SYS {:X}", ins & 0x0fff),
         },
         0x1000 => format!("JP {:X}", ins & 0x0fff),
-        0x2000 => format!("SYS {:X}", ins & 0x0fff),
+        0x2000 => format!("CALL {:X}", ins & 0x0fff),
         0x3000 => format!("SE V{:X}, {:X}", (ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8),
         0x4000 => format!("SNE V{:X}, {:X}", (ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8),
-        0x5000 => format!("SE V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
+        0x5000 => format!("SE V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
         0x6000 => format!("LD V{:X}, {:X}", (ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8),
         0x7000 => format!("ADD V{:X}, {:X}", (ins >> 8 & 0xf) as u8, (ins & 0x00ff) as u8),
         0x8000 => match ins & 0x000f {
-            0x00 => format!("LD V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x01 => format!("OR V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x02 => format!("AND V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x03 => format!("XOR V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x04 => format!("ADD V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x05 => format!("SUB V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x06 => format!("SHR V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x07 => format!("SUBN V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            0x0e => format!("SHL V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
-            _ => panic!("Unknown instruction {:X}", ins)
+            0x00 => format!("LD V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x01 => format!("OR V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x02 => format!("AND V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x03 => format!("XOR V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x04 => format!("ADD V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x05 => format!("SUB V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x06 => format!("SHR V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x07 => format!("SUBN V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            0x0e => format!("SHL V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
+            _ => return None,
         }
-        0x9000 => format!("SNE V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0x00f0) as u8),
+        0x9000 => format!("SNE V{:X}, V{:X}", (ins >> 8 & 0xf) as u8, (ins >> 4 & 0xf) as u8),
         0xA000 => format!("LD I, {:X}", ins & 0x0fff),
         0xB000 => format!("JP V0, {:X}", ins & 0x0fff),
         0xC000 => format!("RND V{:X}, {:X}", (ins >> 8 & 0xf) as u8, ins & 0x00ff),
@@ -33,9 +41,10 @@ pub fn decode(ins: u16) -> String {
         0xE000 => match ins & 0x00ff {
             0x9E => format!("SKP V{:X}", (ins >> 8 & 0xf) as u8),
             0xA1 => format!("SKNP V{:X}", (ins >> 8 & 0xf) as u8),
-            _ => panic!("Unknown instruction {:X}", ins)
+            _ => return None,
         }
         0xF000 => match ins & 0x00ff {
+            0x02 if ins == 0xF002 => "LD AUDIO, [I]".to_string(),
             0x07 => format!("LD V{:X}, DT", (ins >> 8 & 0xf) as u8),
             0x0A => format!("LD V{:X}, K", (ins >> 8 & 0xf) as u8),
             0x15 => format!("LD DT, V{:X}", (ins >> 8 & 0xf) as u8),
@@ -43,10 +52,59 @@ pub fn decode(ins: u16) -> String {
             0x1E => format!("ADD I, V{:X}", (ins >> 8 & 0xf) as u8),
             0x29 => format!("LD F, V{:X}", (ins >> 8 & 0xf) as u8),
             0x33 => format!("LD B, V{:X}", (ins >> 8 & 0xf) as u8),
+            0x3A => format!("LD PITCH, V{:X}", (ins >> 8 & 0xf) as u8),
             0x55 => format!("LD [I], V{:X}", (ins >> 8 & 0xf) as u8),
             0x65 => format!("LD V{:X}, [I]", (ins >> 8 & 0xf) as u8),
-            _ => panic!("Unknown instruction {:X}", ins)
+            _ => return None,
+        }
+        _ => return None,
+    };
+    return Some(s);
+}
+
+// Address of code referenced by jump or call, used for labels
+pub fn branch_target(ins: u16) -> Option<u16> {
+    match ins & 0xf000 {
+        0x1000 | 0x2000 | 0xB000 => Some(ins & 0x0fff),
+        _ => None,
+    }
+}
+
+// Labels for addresses, loaded from file with "<hex address> <name>" per line
+#[derive(Default)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+        let mut symbols = Symbols::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (address, name) = line.split_once(char::is_whitespace)
+                .ok_or(format!("{}:{}: expected '<address> <name>'", path, idx + 1))?;
+            let address = address.trim_start_matches("0x").trim_start_matches('$');
+            let address = u16::from_str_radix(address, 16)
+                .map_err(|_| format!("{}:{}: invalid address '{}'", path, idx + 1, address))?;
+            symbols.names.insert(address, name.trim().to_string());
         }
-        _ => panic!("Unknown instruction {:X}", ins)
+        return Ok(symbols);
+    }
+
+    pub fn insert(&mut self, address: u16, name: &str) {
+        self.names.insert(address, name.to_string());
+    }
+
+    pub fn get(&self, address: u16) -> Option<&str> {
+        return self.names.get(&address).map(|s| s.as_str());
+    }
+
+    // Closest symbol at or before address
+    pub fn containing(&self, address: u16) -> Option<(u16, &str)> {
+        return self.names.range(..=address).next_back().map(|(a, s)| (*a, s.as_str()));
     }
 }
@@ -0,0 +1,431 @@
+use crate::cpu::CPU;
+
+// Small expression language used by conditional breakpoints and logpoints.
+//
+//   VF == 1 && I > 0x300
+//   [I+2] == 0x10
+//   (V0 & 0x0F) != 0 || PC >= 0x250
+//
+// Values are CPU registers (V0-VF, I, PC, SP, DT, ST), memory bytes [addr] and
+// numbers (decimal, 0x hex or 0b binary). Comparisons evaluate to 1 or 0.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    V(u8),
+    I,
+    PC,
+    SP,
+    DT,
+    ST,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Not,
+    Neg,
+    BitNot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(i64),
+    Reg(Register),
+    Mem(Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// Longest operators first so "<=" is not read as "<"
+const OPERATORS: [&str; 22] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "|", "^", "&", "<", ">", "+", "-", "*", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = input;
+    loop {
+        rest = rest.trim_start();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+
+        if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            let (word, radix) = match &rest[..end] {
+                w if w.starts_with("0x") || w.starts_with("0X") => (&w[2..], 16),
+                w if w.starts_with("0b") || w.starts_with("0B") => (&w[2..], 2),
+                w => (w, 10),
+            };
+            let value = i64::from_str_radix(word, radix).map_err(|_| format!("Invalid number '{}'", &rest[..end]))?;
+            tokens.push(Token::Num(value));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_uppercase()));
+            rest = &rest[end..];
+        } else {
+            let op = OPERATORS.iter().find(|op| rest.starts_with(**op)).ok_or(format!("Unexpected character '{}'", c))?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+    }
+}
+
+fn binary_op(token: &Token) -> Option<(BinOp, u8)> {
+    // Operator and its binding power, higher binds tighter
+    let op = match token {
+        Token::Op(op) => *op,
+        _ => return None,
+    };
+    let result = match op {
+        "||" => (BinOp::Or, 1),
+        "&&" => (BinOp::And, 2),
+        "|" => (BinOp::BitOr, 3),
+        "^" => (BinOp::BitXor, 4),
+        "&" => (BinOp::BitAnd, 5),
+        "==" => (BinOp::Eq, 6),
+        "!=" => (BinOp::Ne, 6),
+        "<" => (BinOp::Lt, 7),
+        "<=" => (BinOp::Le, 7),
+        ">" => (BinOp::Gt, 7),
+        ">=" => (BinOp::Ge, 7),
+        "<<" => (BinOp::Shl, 8),
+        ">>" => (BinOp::Shr, 8),
+        "+" => (BinOp::Add, 9),
+        "-" => (BinOp::Sub, 9),
+        "*" => (BinOp::Mul, 10),
+        _ => return None,
+    };
+    return Some(result);
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.pos);
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        return token;
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            _ => Err(format!("Expected '{}'", op)),
+        }
+    }
+
+    // Precedence climbing over binary operators
+    fn expr(&mut self, min_power: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some((op, power)) = self.peek().and_then(binary_op) {
+            if power < min_power {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expr(power + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        return Ok(lhs);
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some(Token::Op("!")) => UnOp::Not,
+            Some(Token::Op("-")) => UnOp::Neg,
+            Some(Token::Op("~")) => UnOp::BitNot,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Ident(name)) => parse_register(&name).map(Expr::Reg).ok_or(format!("Unknown register '{}'", name)),
+            Some(Token::Op("(")) => {
+                let e = self.expr(0)?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Some(Token::Op("[")) => {
+                let e = self.expr(0)?;
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(e)))
+            }
+            Some(Token::Op(op)) => Err(format!("Unexpected '{}'", op)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+impl Register {
+    pub fn name(&self) -> String {
+        match self {
+            Register::V(idx) => format!("V{:X}", idx),
+            Register::I => "I".to_string(),
+            Register::PC => "PC".to_string(),
+            Register::SP => "SP".to_string(),
+            Register::DT => "DT".to_string(),
+            Register::ST => "ST".to_string(),
+        }
+    }
+
+    // Largest value register can hold, PC must leave room for a whole instruction
+    pub fn max(&self) -> i64 {
+        match self {
+            Register::I => 0x0fff,
+            Register::PC => 0x0ffe,
+            Register::SP => 0x0f,
+            _ => 0xff,
+        }
+    }
+
+    pub fn read(&self, cpu: &CPU) -> i64 {
+        match self {
+            Register::V(idx) => cpu.get_registers()[*idx as usize] as i64,
+            Register::I => cpu.ir as i64,
+            Register::PC => cpu.pc as i64,
+            Register::SP => cpu.sp as i64,
+            Register::DT => cpu.dt as i64,
+            Register::ST => cpu.st as i64,
+        }
+    }
+}
+
+pub fn parse_register(name: &str) -> Option<Register> {
+    let reg = match name {
+        "I" => Register::I,
+        "PC" => Register::PC,
+        "SP" => Register::SP,
+        "DT" => Register::DT,
+        "ST" => Register::ST,
+        _ => {
+            let idx = name.strip_prefix('V')?;
+            if idx.len() != 1 {
+                return None;
+            }
+            Register::V(u8::from_str_radix(idx, 16).ok()?)
+        }
+    };
+    return Some(reg);
+}
+
+pub fn parse(input: &str) -> Result<Expr, String> {
+    let mut parser = Parser { tokens: tokenize(input)?, pos: 0 };
+    let e = parser.expr(0)?;
+    if parser.pos < parser.tokens.len() {
+        return Err("Unexpected input after expression".to_string());
+    }
+    return Ok(e);
+}
+
+impl Expr {
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(reg) => reg.read(cpu),
+            Expr::Mem(addr) => cpu.read_ram((addr.eval(cpu) & 0x0fff) as u16) as i64,
+            Expr::Unary(op, e) => {
+                let v = e.eval(cpu);
+                match op {
+                    UnOp::Not => (v == 0) as i64,
+                    UnOp::Neg => v.wrapping_neg(),
+                    UnOp::BitNot => !v,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let l = lhs.eval(cpu);
+                // Short circuit logical operators
+                match op {
+                    BinOp::Or if l != 0 => return 1,
+                    BinOp::And if l == 0 => return 0,
+                    _ => {}
+                }
+                let r = rhs.eval(cpu);
+                match op {
+                    BinOp::Or | BinOp::And => (r != 0) as i64,
+                    BinOp::BitOr => l | r,
+                    BinOp::BitXor => l ^ r,
+                    BinOp::BitAnd => l & r,
+                    BinOp::Eq => (l == r) as i64,
+                    BinOp::Ne => (l != r) as i64,
+                    BinOp::Lt => (l < r) as i64,
+                    BinOp::Le => (l <= r) as i64,
+                    BinOp::Gt => (l > r) as i64,
+                    BinOp::Ge => (l >= r) as i64,
+                    BinOp::Shl => l.wrapping_shl(r as u32),
+                    BinOp::Shr => l.wrapping_shr(r as u32),
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                    BinOp::Mul => l.wrapping_mul(r),
+                }
+            }
+        }
+    }
+}
+
+enum Segment {
+    Text(String),
+    Value(Expr, char), // Expression and format: 'd', 'x' or 'X'
+}
+
+// Logpoint message with embedded expressions, e.g. "V0={V0:X} at {PC:x}"
+pub struct Template {
+    pub source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(input: &str) -> Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut rest = input;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or("Missing '}' in message")? + start;
+            let inner = &rest[start + 1..end];
+            let (source, format) = match inner.rsplit_once(':') {
+                Some((e, f @ ("d" | "x" | "X"))) => (e, f.chars().next().unwrap()),
+                Some((_, f)) => return Err(format!("Unknown format '{}'", f)),
+                None => (inner, 'd'),
+            };
+            segments.push(Segment::Value(parse(source)?, format));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        return Ok(Template { source: input.to_string(), segments });
+    }
+
+    pub fn format(&self, cpu: &CPU) -> String {
+        let mut s = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => s.push_str(text),
+                Segment::Value(e, 'x') => s.push_str(&format!("{:x}", e.eval(cpu))),
+                Segment::Value(e, 'X') => s.push_str(&format!("{:X}", e.eval(cpu))),
+                Segment::Value(e, _) => s.push_str(&e.eval(cpu).to_string()),
+            }
+        }
+        return s;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new(false);
+        cpu.set_register(0x0, 0x12);
+        cpu.set_register(0xF, 1);
+        cpu.ir = 0x300;
+        cpu.write_ram(0x302, 0x10);
+        return cpu;
+    }
+
+    fn eval(input: &str) -> i64 {
+        return parse(input).unwrap().eval(&cpu());
+    }
+
+    #[test]
+    fn tokenizer_reads_numbers_and_longest_operators() {
+        let tokens = tokenize("v0<=0x1F && [i]>=0b101").unwrap();
+        assert_eq!(tokens, vec![
+            Token::Ident("V0".to_string()),
+            Token::Op("<="),
+            Token::Num(0x1F),
+            Token::Op("&&"),
+            Token::Op("["),
+            Token::Ident("I".to_string()),
+            Token::Op("]"),
+            Token::Op(">="),
+            Token::Num(5),
+        ]);
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("10 - 4 - 3"), 3);
+        assert_eq!(eval("1 << 2 + 1"), 8);
+        assert_eq!(eval("6 & 3 == 2"), 0); // == binds tighter than &
+        assert_eq!(eval("1 | 0 && 0"), 0);
+        assert_eq!(eval("0 && 1 || 1"), 1);
+        assert_eq!(eval("-2 * 3"), -6);
+        assert_eq!(eval("!0 + ~0"), 0);
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        assert_eq!(eval("V0"), 0x12);
+        assert_eq!(eval("VF == 1 && I > 0x2FF"), 1);
+        assert_eq!(eval("[I+2] == 0x10"), 1);
+        assert_eq!(eval("(V0 & 0x0F) != 0 || PC >= 0x250"), 1);
+        assert_eq!(eval("PC"), 0x200);
+        assert_eq!(eval("SP + DT + ST"), 0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("V0 ==").is_err());
+        assert!(parse("(V0").is_err());
+        assert!(parse("[I").is_err());
+        assert!(parse("VG").is_err());
+        assert!(parse("V0 $ 1").is_err());
+        assert!(parse("0x").is_err());
+        assert!(parse("1 2").is_err());
+    }
+
+    #[test]
+    fn template_formats_values() {
+        let template = Template::parse("V0={V0} hex={V0:x} HEX={V0:X} at {PC:X}").unwrap();
+        assert_eq!(template.format(&cpu()), "V0=18 hex=12 HEX=12 at 200");
+        assert_eq!(Template::parse("plain").unwrap().format(&cpu()), "plain");
+        assert!(Template::parse("{V0").is_err());
+        assert!(Template::parse("{V0:q}").is_err());
+        assert!(Template::parse("{V0 +}").is_err());
+    }
+}
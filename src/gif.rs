@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+
+// GIF reader and writer for palette images, enough for octocarts and display
+// recordings. Only pixel indexes are read, colours, frame offsets and timing
+// are ignored.
+
+pub struct Frame {
+    pub pixels: Vec<u8>, // Palette indexes, row by row
+}
+
+pub struct Image {
+    pub frames: Vec<Frame>,
+}
+
+const MAX_CODE_SIZE: u32 = 12;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.pos + count > self.data.len() {
+            return Err("GIF ends unexpectedly".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        return Ok(bytes);
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        return Ok(self.bytes(1)?[0]);
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn skip_palette(&mut self, flags: u8) -> Result<(), String> {
+        let size = 2usize << (flags & 0x07);
+        self.bytes(size * 3)?;
+        return Ok(());
+    }
+
+    // Data sub-blocks up to the zero length terminator
+    fn sub_blocks(&mut self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        loop {
+            let len = self.u8()? as usize;
+            if len == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(self.bytes(len)?);
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let mut reader = Reader { data, pos: 0 };
+    let header = reader.bytes(6)?;
+    if header != b"GIF87a" && header != b"GIF89a" {
+        return Err("Not a GIF image".to_string());
+    }
+    reader.bytes(4)?; // Screen size
+    let flags = reader.u8()?;
+    reader.bytes(2)?; // Background colour and aspect ratio
+    if flags & 0x80 != 0 {
+        reader.skip_palette(flags)?; // Global colour table
+    }
+
+    let mut frames = Vec::new();
+    loop {
+        match reader.u8()? {
+            0x21 => {
+                reader.u8()?; // Extension label
+                reader.sub_blocks()?;
+            }
+            0x2C => {
+                reader.bytes(4)?; // Frame position
+                let frame_width = reader.u16()? as usize;
+                let frame_height = reader.u16()? as usize;
+                let flags = reader.u8()?;
+                if flags & 0x80 != 0 {
+                    reader.skip_palette(flags)?; // Local colour table
+                }
+                let min_code_size = reader.u8()? as u32;
+                let mut pixels = lzw_decode(&reader.sub_blocks()?, min_code_size)?;
+                pixels.resize(frame_width * frame_height, 0);
+                if flags & 0x40 != 0 && frame_width > 0 {
+                    pixels = deinterlace(&pixels, frame_width, frame_height);
+                }
+                frames.push(Frame { pixels });
+            }
+            0x3B => break,
+            byte => return Err(format!("Invalid GIF block {:02X}", byte)),
+        }
+    }
+    return Ok(Image { frames });
+}
+
+// Interlaced rows come in passes: every 8th from 0, every 8th from 4,
+// every 4th from 2 and every 2nd from 1
+fn deinterlace(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut out = vec![0; pixels.len()];
+    let mut rows = pixels.chunks(width);
+    for (start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)] {
+        for y in (start..height).step_by(step) {
+            if let Some(row) = rows.next() {
+                out[y * width..(y + 1) * width].copy_from_slice(row);
+            }
+        }
+    }
+    return out;
+}
+
+fn lzw_decode(data: &[u8], min_code_size: u32) -> Result<Vec<u8>, String> {
+    if !(1..=8).contains(&min_code_size) {
+        return Err(format!("Invalid LZW code size {}", min_code_size));
+    }
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut table: Vec<Vec<u8>> = Vec::new();
+    let reset = |table: &mut Vec<Vec<u8>>| {
+        table.clear();
+        table.extend((0..clear).map(|idx| vec![idx as u8]));
+        table.push(Vec::new());
+        table.push(Vec::new());
+    };
+    reset(&mut table);
+
+    let mut out = Vec::new();
+    let mut code_size = min_code_size + 1;
+    let mut previous: Option<u16> = None;
+    let (mut bits, mut bit_count) = (0u32, 0u32);
+    for byte in data {
+        bits |= (*byte as u32) << bit_count;
+        bit_count += 8;
+        while bit_count >= code_size {
+            let code = (bits & ((1 << code_size) - 1)) as u16;
+            bits >>= code_size;
+            bit_count -= code_size;
+
+            if code == clear {
+                reset(&mut table);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end {
+                return Ok(out);
+            }
+            let entry = match (table.get(code as usize), previous) {
+                (Some(entry), _) => entry.clone(),
+                // Code being defined by this step: previous string plus its first byte
+                (None, Some(prev)) if code as usize == table.len() => {
+                    let mut entry = table[prev as usize].clone();
+                    entry.push(entry[0]);
+                    entry
+                }
+                _ => return Err(format!("Invalid LZW code {}", code)),
+            };
+            out.extend_from_slice(&entry);
+            if let Some(prev) = previous {
+                if table.len() < 1 << MAX_CODE_SIZE {
+                    let mut new = table[prev as usize].clone();
+                    new.push(entry[0]);
+                    table.push(new);
+                }
+            }
+            if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+            }
+            previous = Some(code);
+        }
+    }
+    return Ok(out);
+}
+
+// Single frame GIF with a 256 colour global table
+pub fn encode(width: usize, height: usize, pixels: &[u8], palette: &[(u8, u8, u8)]) -> Vec<u8> {
+    let mut out = header(width, height, palette);
+    write_frame(&mut out, width, height, pixels);
+    out.push(0x3B);
+    return out;
+}
+
+// Looping animation of full size frames, each shown for its delay in
+// hundredths of a second
+pub fn encode_frames(width: usize, height: usize, frames: &[(Vec<u8>, u16)], palette: &[(u8, u8, u8)]) -> Vec<u8> {
+    let mut out = header(width, height, palette);
+    out.extend_from_slice(&[0x21, 0xFF, 11]);
+    out.extend_from_slice(b"NETSCAPE2.0");
+    out.extend_from_slice(&[3, 1, 0, 0, 0]); // Loop forever
+    for (pixels, delay) in frames {
+        out.extend_from_slice(&[0x21, 0xF9, 4, 0]); // Graphic control, no transparency
+        out.extend_from_slice(&delay.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        write_frame(&mut out, width, height, pixels);
+    }
+    out.push(0x3B);
+    return out;
+}
+
+fn header(width: usize, height: usize, palette: &[(u8, u8, u8)]) -> Vec<u8> {
+    let mut out = b"GIF89a".to_vec();
+    out.extend_from_slice(&(width as u16).to_le_bytes());
+    out.extend_from_slice(&(height as u16).to_le_bytes());
+    out.extend_from_slice(&[0xF7, 0, 0]); // Global table of 256 entries, 8 bit colour
+    for idx in 0..256 {
+        let (r, g, b) = palette.get(idx).copied().unwrap_or((0, 0, 0));
+        out.extend_from_slice(&[r, g, b]);
+    }
+    return out;
+}
+
+fn write_frame(out: &mut Vec<u8>, width: usize, height: usize, pixels: &[u8]) {
+    out.push(0x2C);
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(&(width as u16).to_le_bytes());
+    out.extend_from_slice(&(height as u16).to_le_bytes());
+    out.push(0); // No local table, not interlaced
+    out.push(8);
+    for block in lzw_encode(pixels, 8).chunks(255) {
+        out.push(block.len() as u8);
+        out.extend_from_slice(block);
+    }
+    out.push(0);
+}
+
+fn lzw_encode(pixels: &[u8], min_code_size: u32) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut out = Vec::new();
+    let (mut bits, mut bit_count) = (0u32, 0u32);
+    let mut code_size = min_code_size + 1;
+    let mut emit = |code: u16, code_size: u32| {
+        bits |= (code as u32) << bit_count;
+        bit_count += code_size;
+        while bit_count >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            bit_count -= 8;
+        }
+    };
+
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end + 1;
+    emit(clear, code_size);
+    let mut current: Option<u16> = None;
+    for pixel in pixels {
+        let prefix = match current {
+            Some(prefix) => prefix,
+            None => {
+                current = Some(*pixel as u16);
+                continue;
+            }
+        };
+        if let Some(code) = table.get(&(prefix, *pixel)) {
+            current = Some(*code);
+            continue;
+        }
+        emit(prefix, code_size);
+        if next_code < 1 << MAX_CODE_SIZE {
+            table.insert((prefix, *pixel), next_code);
+            // Decoder widens codes once the table reaches the current limit
+            if next_code == 1 << code_size {
+                code_size += 1;
+            }
+            next_code += 1;
+        } else {
+            // Table full, start over
+            emit(clear, code_size);
+            table.clear();
+            next_code = end + 1;
+            code_size = min_code_size + 1;
+        }
+        current = Some(*pixel as u16);
+    }
+    if let Some(code) = current {
+        emit(code, code_size);
+    }
+    emit(end, code_size);
+    if bit_count > 0 {
+        out.push(bits as u8);
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pixel with index 0 in a 2 colour table, the common 1x1 spacer GIF
+    const SPACER: [u8; 43] = [
+        0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+    ];
+
+    #[test]
+    fn round_trip() {
+        // Long runs fill the code table and force a clear
+        let pixels: Vec<u8> = (0..20000u32).map(|idx| (((idx * 7919) % 251) ^ (idx / 300)) as u8).collect();
+        let palette: Vec<(u8, u8, u8)> = (0..256).map(|idx| (idx as u8, 0, 255 - idx as u8)).collect();
+        let image = decode(&encode(200, 100, &pixels, &palette)).unwrap();
+        assert_eq!(image.frames.len(), 1);
+        assert_eq!(image.frames[0].pixels, pixels);
+    }
+
+    #[test]
+    fn animation() {
+        let frames = vec![(vec![0, 1, 2, 3], 2), (vec![3, 2, 1, 0], 1)];
+        let data = encode_frames(2, 2, &frames, &[(1, 2, 3)]);
+        assert!(data.windows(11).any(|w| w == b"NETSCAPE2.0"));
+        let image = decode(&data).unwrap();
+        let pixels: Vec<Vec<u8>> = image.frames.into_iter().map(|f| f.pixels).collect();
+        assert_eq!(pixels, vec![frames[0].0.clone(), frames[1].0.clone()]);
+    }
+
+    #[test]
+    fn other_encoder() {
+        let image = decode(&SPACER).unwrap();
+        assert_eq!(image.frames.len(), 1);
+        assert_eq!(image.frames[0].pixels, vec![0]);
+    }
+
+    #[test]
+    fn interlaced() {
+        // Rows stored in pass order 0, 4, 2, 6, 1, 3, 5, 7
+        let stored = [0u8, 4, 2, 6, 1, 3, 5, 7];
+        let mut data = encode(1, 8, &stored, &[]);
+        let flags = 13 + 256 * 3 + 9;
+        data[flags] |= 0x40;
+        let image = decode(&data).unwrap();
+        assert_eq!(image.frames[0].pixels, (0..8).collect::<Vec<u8>>());
+
+        // Empty interlaced frame
+        let mut data = encode(0, 4, &[], &[]);
+        data[flags] |= 0x40;
+        assert!(decode(&data).unwrap().frames[0].pixels.is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(decode(b"PNG").is_err());
+        assert!(decode(&SPACER[..30]).is_err());
+        let mut data = SPACER;
+        data[37] = 12; // LZW code size
+        assert!(decode(&data).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+
+// Terminal image output for the display, drawn over the TUI after each frame.
+//
+// Kitty graphics protocol sends the framebuffer as raw RGB data in base64
+// chunks and lets the terminal scale it to a cell area. Sixel has no scaling,
+// so six pixel high bands per colour are sent upscaled by a whole number to
+// keep pixels sharp.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Graphics {
+    Kitty,
+    Sixel,
+}
+
+pub const GRAPHICS_MODES: [&str; 4] = ["auto", "kitty", "sixel", "none"];
+
+// Cell size used when the terminal does not report pixel dimensions
+const DEFAULT_CELL: (u16, u16) = (8, 16);
+
+// Kitty image id, replaced on every frame
+const KITTY_IMAGE_ID: u32 = 1;
+
+// Kitty image id used by the support query, never displayed
+const KITTY_QUERY_ID: u32 = 31;
+
+// How long to wait for the terminal to answer the capability query
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+impl Graphics {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Graphics::Kitty => "kitty",
+            Graphics::Sixel => "sixel",
+        }
+    }
+}
+
+// Resolve "auto", "kitty", "sixel" or "none", Ok(None) means character rendering
+pub fn select(mode: &str) -> Result<Option<Graphics>, String> {
+    match mode.to_lowercase().as_str() {
+        "auto" => Ok(detect()),
+        "kitty" => Ok(Some(Graphics::Kitty)),
+        "sixel" => Ok(Some(Graphics::Sixel)),
+        "none" => Ok(None),
+        _ => Err(format!("Unknown graphics mode '{}', expected one of: {}", mode, GRAPHICS_MODES.join(" "))),
+    }
+}
+
+// Ask the terminal which protocol it supports, falling back to well known
+// terminals from the environment when it does not answer. Runs before the TUI
+// starts so the reply does not race with the input reader.
+pub fn detect() -> Option<Graphics> {
+    let term = env::var("TERM").unwrap_or_default().to_lowercase();
+    // Multiplexers answer queries themselves but pass neither protocol through reliably
+    if env::var("TMUX").is_ok() || term.starts_with("screen") {
+        return None;
+    }
+    if let Some(graphics) = query() {
+        return graphics;
+    }
+    return detect_env();
+}
+
+// Send a kitty graphics query followed by a primary device attributes request.
+// Every terminal answers the latter, so its reply marks the end of the answers.
+// None when the terminal is not interactive or does not answer in time.
+fn query() -> Option<Option<Graphics>> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return None;
+    }
+    enable_raw_mode().ok()?;
+    let mut stdout = io::stdout();
+    let sent = write!(stdout, "\x1b_Gi={},s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\\x1b[c", KITTY_QUERY_ID)
+        .and_then(|_| stdout.flush());
+    let mut reply = Vec::new();
+    let mut result = None;
+    if sent.is_ok() {
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        while result.is_none() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || !read_reply(&mut reply, left) {
+                break;
+            }
+            result = parse_reply(&reply);
+        }
+    }
+    let _ = disable_raw_mode();
+    return result;
+}
+
+// Append what the terminal has sent within the timeout, false when nothing came
+#[cfg(unix)]
+fn read_reply(reply: &mut Vec<u8>, timeout: Duration) -> bool {
+    let mut fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    if unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } <= 0 {
+        return false;
+    }
+    let mut buf = [0u8; 256];
+    let count = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    if count <= 0 {
+        return false;
+    }
+    reply.extend_from_slice(&buf[..count as usize]);
+    return true;
+}
+
+#[cfg(not(unix))]
+fn read_reply(_reply: &mut Vec<u8>, _timeout: Duration) -> bool {
+    return false;
+}
+
+// Protocol from the query replies, None until the device attributes reply is
+// complete. Kitty answers OK to the graphics query, sixel support is
+// attribute 4 in the device attributes.
+fn parse_reply(reply: &[u8]) -> Option<Option<Graphics>> {
+    let text = String::from_utf8_lossy(reply);
+    let start = text.find("\x1b[?")?;
+    let end = start + text[start..].find('c')?;
+    if text.contains(&format!("\x1b_Gi={};OK", KITTY_QUERY_ID)) {
+        return Some(Some(Graphics::Kitty));
+    }
+    if text[start + 3..end].split(';').any(|attr| attr == "4") {
+        return Some(Some(Graphics::Sixel));
+    }
+    return Some(None);
+}
+
+// Guess protocol support from well known terminals in the environment
+fn detect_env() -> Option<Graphics> {
+    let var = |name: &str| env::var(name).unwrap_or_default().to_lowercase();
+    let term = var("TERM");
+    let program = var("TERM_PROGRAM");
+
+    if env::var("KITTY_WINDOW_ID").is_ok() || term.contains("kitty") || term.contains("ghostty")
+        || matches!(program.as_str(), "wezterm" | "ghostty") {
+        return Some(Graphics::Kitty);
+    }
+    if term.contains("sixel") || term.starts_with("foot") || term.starts_with("mlterm") || term.starts_with("contour")
+        || matches!(program.as_str(), "iterm.app" | "mintty") {
+        return Some(Graphics::Sixel);
+    }
+    return None;
+}
+
+// Pixel size of one terminal cell
+#[cfg(unix)]
+pub fn cell_size() -> (u16, u16) {
+    let mut size = libc::winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if !ok || size.ws_col == 0 || size.ws_row == 0 || size.ws_xpixel == 0 || size.ws_ypixel == 0 {
+        return DEFAULT_CELL;
+    }
+    return (size.ws_xpixel / size.ws_col, size.ws_ypixel / size.ws_row);
+}
+
+#[cfg(not(unix))]
+pub fn cell_size() -> (u16, u16) {
+    return DEFAULT_CELL;
+}
+
+// Whole number scale and cell offset that fit width x height into cols x rows,
+// None when the area is too small for the image at scale 1
+pub fn fit(width: usize, height: usize, cols: u16, rows: u16, cell: (u16, u16)) -> Option<(usize, u16, u16)> {
+    let (area_w, area_h) = (cols as usize * cell.0 as usize, rows as usize * cell.1 as usize);
+    let scale = (area_w / width).min(area_h / height);
+    if scale == 0 {
+        return None;
+    }
+    let (image_cols, image_rows) = cells(width, height, scale, cell);
+    return Some((scale, cols.saturating_sub(image_cols) / 2, rows.saturating_sub(image_rows) / 2));
+}
+
+// Cells covered by width x height upscaled by scale
+fn cells(width: usize, height: usize, scale: usize, cell: (u16, u16)) -> (u16, u16) {
+    return ((width * scale).div_ceil(cell.0 as usize) as u16, (height * scale).div_ceil(cell.1 as usize) as u16);
+}
+
+// Image escape sequence for pixels of width x height upscaled by scale on a
+// terminal with the given cell size, pixels are indexes into the RGB palette
+pub fn encode(graphics: Graphics, pixels: &[u8], width: usize, height: usize, scale: usize, cell: (u16, u16), palette: &[(u8, u8, u8)]) -> String {
+    match graphics {
+        Graphics::Kitty => kitty(pixels, width, height, cells(width, height, scale, cell), palette),
+        Graphics::Sixel => sixel(pixels, width, height, scale, palette),
+    }
+}
+
+// Remove a previously drawn image, sixel output is simply drawn over
+pub fn clear(graphics: Graphics) -> String {
+    match graphics {
+        Graphics::Kitty => format!("\x1b_Ga=d,d=I,i={},q=2\x1b\\", KITTY_IMAGE_ID),
+        Graphics::Sixel => String::new(),
+    }
+}
+
+// Image at its own size, displayed over cols x rows cells by the terminal
+fn kitty(pixels: &[u8], width: usize, height: usize, (cols, rows): (u16, u16), palette: &[(u8, u8, u8)]) -> String {
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for &pixel in &pixels[..width * height] {
+        let (r, g, b) = palette[pixel as usize];
+        rgb.extend_from_slice(&[r, g, b]);
+    }
+    let data = base64(&rgb);
+
+    // Payload is sent in chunks of at most 4096 bytes, m=1 while more follow
+    let mut out = clear(Graphics::Kitty);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(4096).collect();
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let more = (idx + 1 < chunks.len()) as u8;
+        if idx == 0 {
+            out.push_str(&format!("\x1b_Ga=T,f=24,s={},v={},c={},r={},i={},C=1,q=2,m={};", width, height, cols, rows, KITTY_IMAGE_ID, more));
+        } else {
+            out.push_str(&format!("\x1b_Gm={};", more));
+        }
+        out.push_str(std::str::from_utf8(chunk).unwrap());
+        out.push_str("\x1b\\");
+    }
+    return out;
+}
+
+fn sixel(pixels: &[u8], width: usize, height: usize, scale: usize, palette: &[(u8, u8, u8)]) -> String {
+    let (w, h) = (width * scale, height * scale);
+    let mut out = format!("\x1bP0;1;0q\"1;1;{};{}", w, h);
+    // Palette registers take RGB in percent
+    for (idx, (r, g, b)) in palette.iter().enumerate() {
+        let percent = |v: u8| v as u32 * 100 / 255;
+        out.push_str(&format!("#{};2;{};{};{}", idx, percent(*r), percent(*g), percent(*b)));
+    }
+    for band in (0..h).step_by(6) {
+        let rows = band / scale..((band + 6).min(h) - 1) / scale + 1;
+        for color in 0..palette.len() as u8 {
+            // Skip colours the band does not use
+            if !rows.clone().any(|y| pixels[y * width..(y + 1) * width].contains(&color)) {
+                continue;
+            }
+            out.push_str(&format!("#{}", color));
+            // Run length encode equal sixels with !<count><char>
+            let mut run: Option<(u8, usize)> = None;
+            for x in 0..w {
+                let mut bits = 0;
+                for dy in 0..6 {
+                    let y = band + dy;
+                    if y < h && pixels[x / scale + y / scale * width] == color {
+                        bits |= 1 << dy;
+                    }
+                }
+                run = match run {
+                    Some((b, n)) if b == bits => Some((b, n + 1)),
+                    Some((b, n)) => {
+                        push_sixel(&mut out, b, n);
+                        Some((bits, 1))
+                    }
+                    None => Some((bits, 1)),
+                };
+            }
+            if let Some((b, n)) = run {
+                push_sixel(&mut out, b, n);
+            }
+            // Back to start of band for the next colour
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    return out;
+}
+
+fn push_sixel(out: &mut String, bits: u8, count: usize) {
+    let c = (0x3f + bits) as char;
+    if count > 3 {
+        out.push_str(&format!("!{}{}", count, c));
+    } else {
+        for _ in 0..count {
+            out.push(c);
+        }
+    }
+}
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn sixel_bands() {
+        let palette = [(0, 0, 0), (255, 128, 0)];
+        // 2x1 image, left pixel off and right pixel on
+        let out = encode(Graphics::Sixel, &[0, 1], 2, 1, 1, DEFAULT_CELL, &palette);
+        assert_eq!(out, "\x1bP0;1;0q\"1;1;2;1#0;2;0;0;0#1;2;100;50;0#0@?$#1?@$-\x1b\\");
+
+        // Scale 4 gives one band of four pixel high sixels, runs over three are counted
+        let out = encode(Graphics::Sixel, &[1, 1], 2, 1, 4, DEFAULT_CELL, &palette);
+        assert_eq!(out, "\x1bP0;1;0q\"1;1;8;4#0;2;0;0;0#1;2;100;50;0#1!8N$-\x1b\\");
+
+        // Six rows high image upscaled by 2 takes two bands
+        let out = encode(Graphics::Sixel, &[1; 6], 1, 6, 2, DEFAULT_CELL, &palette);
+        assert_eq!(out.matches('-').count(), 2);
+        assert!(out.ends_with("#1~~$-#1~~$-\x1b\\"));
+    }
+
+    #[test]
+    fn kitty_is_scaled_by_terminal() {
+        let palette = [(0, 0, 0), (255, 255, 255)];
+        let pixels = vec![1; 64 * 32];
+        let out = encode(Graphics::Kitty, &pixels, 64, 32, 5, (8, 16), &palette);
+        assert!(out.starts_with(&clear(Graphics::Kitty)));
+        assert!(out.contains("a=T,f=24,s=64,v=32,c=40,r=10,"));
+        // Payload is the image at its own size, not upscaled
+        let payload: usize = out.split("\x1b_G").skip(2).map(|chunk| chunk.split(';').nth(1).unwrap().len() - 2).sum();
+        assert_eq!(payload, (64 * 32 * 3usize).div_ceil(3) * 4);
+        assert_eq!(out.matches("m=1;").count(), 1);
+        assert!(out.contains("m=0;"));
+    }
+
+    #[test]
+    fn fit_area() {
+        assert_eq!(fit(64, 32, 80, 20, (8, 16)), Some((10, 0, 0)));
+        assert_eq!(fit(64, 32, 100, 30, (8, 16)), Some((12, 2, 3)));
+        assert_eq!(fit(64, 32, 7, 20, (8, 16)), None);
+    }
+
+    #[test]
+    fn query_replies() {
+        assert_eq!(parse_reply(b""), None);
+        assert_eq!(parse_reply(b"\x1b_Gi=31;OK\x1b\\"), None);
+        assert_eq!(parse_reply(b"\x1b_Gi=31;OK\x1b\\\x1b[?62;c"), Some(Some(Graphics::Kitty)));
+        assert_eq!(parse_reply(b"\x1b[?62;4;22c"), Some(Some(Graphics::Sixel)));
+        assert_eq!(parse_reply(b"\x1b[?62;42;22c"), Some(None));
+        assert_eq!(parse_reply(b"\x1b[?1;2c"), Some(None));
+    }
+}
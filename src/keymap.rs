@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::fs;
+
+// Keyboard characters for each keypad position, in COSMAC VIP layout order:
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+const KEYPAD_ORDER: [u8; 16] = [0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF];
+
+const QWERTY: [&str; 16] = ["1", "2", "3", "4", "q", "w", "e", "r", "a", "s", "d", "f", "z", "x", "c", "v"];
+// AZERTY number row gives symbols without shift, accept both
+const AZERTY: [&str; 16] = ["1&", "2é", "3\"", "4'", "a", "z", "e", "r", "q", "s", "d", "f", "w", "x", "c", "v"];
+const DVORAK: [&str; 16] = ["1", "2", "3", "4", "'", ",", ".", "p", "a", "o", "e", "u", ";", "q", "j", "k"];
+
+pub const PRESETS: [&str; 4] = ["qwerty", "azerty", "dvorak", "numpad"];
+
+// Keys other than characters that a ROM database entry can bind
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum NamedKey {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Clone)]
+pub struct KeyMap {
+    keys: HashMap<char, u8>, // Lowercase keyboard character to hex key
+    named: HashMap<NamedKey, u8>, // Arrow keys bound for the loaded ROM
+}
+
+impl Default for KeyMap {
+    fn default() -> KeyMap {
+        KeyMap::preset("qwerty").unwrap()
+    }
+}
+
+impl KeyMap {
+    pub fn preset(name: &str) -> Option<KeyMap> {
+        let rows = match name.to_lowercase().as_str() {
+            "qwerty" => QWERTY,
+            "azerty" => AZERTY,
+            "dvorak" => DVORAK,
+            "numpad" => return Some(KeyMap::numpad()),
+            _ => return None,
+        };
+        let mut keys = HashMap::new();
+        for (chars, key) in rows.iter().zip(KEYPAD_ORDER) {
+            for c in chars.chars() {
+                keys.insert(c, key);
+            }
+        }
+        return Some(KeyMap { keys, named: HashMap::new() });
+    }
+
+    // Digits map to themselves, operators and enter to A-F
+    fn numpad() -> KeyMap {
+        let mut keys = HashMap::new();
+        for digit in 0..10u8 {
+            keys.insert((b'0' + digit) as char, digit);
+        }
+        for (c, key) in ['/', '*', '-', '+', '.', '\n'].iter().zip(0xA..=0xF) {
+            keys.insert(*c, key);
+        }
+        return KeyMap { keys, named: HashMap::new() };
+    }
+
+    pub fn key_for(&self, c: char) -> Option<u8> {
+        return self.keys.get(&c.to_lowercase().next().unwrap_or(c)).copied();
+    }
+
+    pub fn key_for_named(&self, key: NamedKey) -> Option<u8> {
+        return self.named.get(&key).copied();
+    }
+
+    // Bind ROM database inputs on top of the layout: directions to the arrow
+    // keys, "a" to space and "b" to enter. Player 2 inputs stay on the layout.
+    pub fn apply_rom_keys(&mut self, keys: &[(String, u8)]) {
+        for (name, key) in keys {
+            match name.as_str() {
+                "up" => { self.named.insert(NamedKey::Up, *key); }
+                "down" => { self.named.insert(NamedKey::Down, *key); }
+                "left" => { self.named.insert(NamedKey::Left, *key); }
+                "right" => { self.named.insert(NamedKey::Right, *key); }
+                "a" => { self.keys.insert(' ', *key); }
+                "b" => { self.keys.insert('\n', *key); }
+                _ => {}
+            }
+        }
+    }
+
+    // Map character to key, replacing other characters bound to the same key
+    pub fn set(&mut self, c: char, key: u8) {
+        self.keys.retain(|_, k| *k != key);
+        self.keys.insert(c.to_lowercase().next().unwrap_or(c), key);
+    }
+
+    // Apply overrides, one per line or comma separated:
+    //   preset azerty
+    //   5=w
+    // where "5=w" binds keypad key 5 to keyboard character w
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        for entry in spec.split(['\n', ',']) {
+            let entry = entry.split('#').next().unwrap_or("").trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if let Some(name) = entry.strip_prefix("preset") {
+                *self = KeyMap::preset(name.trim())
+                    .ok_or(format!("Unknown key map preset '{}', expected one of: {}", name.trim(), PRESETS.join(" ")))?;
+                continue;
+            }
+            let (key, c) = entry.split_once('=').ok_or(format!("Invalid key mapping '{}', expected <hex key>=<char>", entry))?;
+            let key = u8::from_str_radix(key.trim(), 16).ok().filter(|k| *k < 16)
+                .ok_or(format!("Invalid keypad key '{}'", key.trim()))?;
+            let mut chars = c.trim().chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => self.set(c, key),
+                _ => return Err(format!("Invalid keyboard key '{}'", c.trim())),
+            }
+        }
+        return Ok(());
+    }
+
+    // Keyboard character bound to key, for help text
+    pub fn char_for(&self, key: u8) -> Option<char> {
+        let mut chars: Vec<char> = self.keys.iter().filter(|(_, k)| **k == key).map(|(c, _)| *c).collect();
+        chars.sort();
+        return chars.first().copied();
+    }
+}
+
+// Per-ROM overrides file next to the ROM
+pub fn rom_path(rom_path: &str) -> String {
+    return format!("{}.keymap", rom_path);
+}
+
+// Overrides in file as comma separated bindings, None when it does not exist
+pub fn load_overrides(path: &str) -> Result<Option<String>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return Ok(None),
+    };
+    KeyMap::default().apply(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let entries: Vec<&str> = text.split(['\n', ','])
+        .map(|entry| entry.split('#').next().unwrap_or("").trim())
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    return Ok(Some(entries.join(", ")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_file() {
+        let path = std::env::temp_dir().join(format!("chip_8_test_{}.keymap", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        assert_eq!(load_overrides(&path), Ok(None));
+
+        fs::write(&path, "# Pong\npreset azerty\n1=w, 4=s # left paddle\n\n").unwrap();
+        let bindings = load_overrides(&path).unwrap().unwrap();
+        assert_eq!(bindings, "preset azerty, 1=w, 4=s");
+        let mut keymap = KeyMap::default();
+        keymap.apply(&bindings).unwrap();
+        assert_eq!((keymap.key_for('w'), keymap.key_for('z')), (Some(0x1), Some(0x5)));
+
+        fs::write(&path, "1=w\nG=x\n").unwrap();
+        let error = load_overrides(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error, format!("{}: Invalid keypad key 'G'", path));
+    }
+}
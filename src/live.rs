@@ -0,0 +1,286 @@
+use crate::audio::{Audio, AudioOptions};
+use crate::cpu::{CPU, CpuEvent, Program, Quirks, TIMER_RATE};
+use crate::database::RomInfo;
+use crate::keymap::{KeyMap, NamedKey};
+use crate::phosphor::{Persistence, Phosphor};
+use crate::record::Recorder;
+use crate::render::{self, RenderMode};
+use crate::theme::Theme;
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags, PopKeyboardEnhancementFlags},
+    execute, queue,
+    style::{self, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    terminal::{self, disable_raw_mode, enable_raw_mode, supports_keyboard_enhancement, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use tui::style::Color;
+
+// Full screen player without the debugger. The display is drawn with half
+// blocks at one character per pixel column and only rows that changed since
+// the last frame are written again.
+
+const COLS: usize = 64;
+const ROWS: usize = 16; // Two pixel rows per character row
+
+pub struct LiveOptions {
+    pub keymap: KeyMap,
+    pub key_hold: Duration,
+    pub theme: Theme,
+    pub persistence: Persistence,
+    pub audio: AudioOptions,
+    pub quirks: Quirks,
+    pub tick_rate: u64, // Instructions per frame
+    pub rom: Option<RomInfo>,
+    pub record: Option<String>, // GIF file for a recording of the display
+}
+
+struct Live {
+    cpu: CPU,
+    keymap: KeyMap,
+    key_hold: Duration,
+    key_presses: [Option<Instant>; 16],
+    key_releases: bool,
+    theme: Theme,
+    phosphor: Phosphor,
+    audio: Audio,
+    tick_rate: u64,
+    rom: Option<RomInfo>,
+    recorder: Option<Recorder>,
+    bell: bool, // Ring terminal bell on next draw, when there is no audio device
+    lines: Vec<String>, // Rows on screen, redrawn when they differ
+    status: String,
+    redraw: bool, // Terminal was cleared, draw everything
+}
+
+impl Live {
+    // Returns false when the player asked to quit
+    fn handle_key(&mut self, event: KeyEvent) -> bool {
+        if event.kind != KeyEventKind::Release {
+            match event.code {
+                KeyCode::Esc => return false,
+                KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => return false,
+                _ => {}
+            }
+        }
+        let key = match event.code {
+            KeyCode::Char(c) => self.keymap.key_for(c),
+            KeyCode::Enter => self.keymap.key_for('\n'),
+            KeyCode::Up => self.keymap.key_for_named(NamedKey::Up),
+            KeyCode::Down => self.keymap.key_for_named(NamedKey::Down),
+            KeyCode::Left => self.keymap.key_for_named(NamedKey::Left),
+            KeyCode::Right => self.keymap.key_for_named(NamedKey::Right),
+            _ => return true,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return true,
+        };
+        match event.kind {
+            KeyEventKind::Release => self.cpu.set_key(key, false),
+            _ => {
+                self.cpu.set_key(key, true);
+                if !self.key_releases {
+                    self.key_presses[key as usize] = Some(Instant::now());
+                }
+            }
+        }
+        return true;
+    }
+
+    // Release keys not pressed again within hold time
+    fn release_keys(&mut self) {
+        for key in 0..16u8 {
+            if let Some(pressed) = self.key_presses[key as usize] {
+                if pressed.elapsed() >= self.key_hold {
+                    self.key_presses[key as usize] = None;
+                    self.cpu.set_key(key, false);
+                }
+            }
+        }
+    }
+
+    // Run one 60 Hz frame
+    fn frame(&mut self) -> bool {
+        for _ in 0..self.tick_rate {
+            if self.cpu.next_cycle() == -1 {
+                return false;
+            }
+            if self.cpu.get_events().contains(&CpuEvent::SoundStart) && !self.audio.has_device() {
+                self.bell = true;
+            }
+        }
+        let sound = self.cpu.st > 0;
+        self.cpu.tick_timers();
+        self.audio.tick(sound, self.cpu.get_audio_pattern());
+        let pixels = self.cpu.get_pixels();
+        self.phosphor.update(&pixels);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.add(&self.phosphor.levels(&pixels));
+        }
+        return true;
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let levels = self.phosphor.levels(&self.cpu.get_pixels());
+        let cells = render::render(&levels, 64, 32, RenderMode::HalfBlock, COLS, ROWS);
+        for (row, line) in cells.iter().enumerate() {
+            let mut text = String::new();
+            let mut colors = None;
+            for cell in line {
+                let cell_colors = (self.theme.blend(cell.fg), self.theme.blend(cell.bg));
+                if colors != Some(cell_colors) {
+                    text.push_str(&format!(
+                        "{}{}",
+                        SetForegroundColor(to_crossterm(cell_colors.0)),
+                        SetBackgroundColor(to_crossterm(cell_colors.1))
+                    ));
+                    colors = Some(cell_colors);
+                }
+                text.push(cell.ch);
+            }
+            if self.redraw || self.lines.get(row) != Some(&text) {
+                queue!(out, cursor::MoveTo(0, row as u16), Print(&text), ResetColor)?;
+            }
+            if row < self.lines.len() {
+                self.lines[row] = text;
+            } else {
+                self.lines.push(text);
+            }
+        }
+        let mut status = "<Esc> Quit".to_string();
+        if let Some(rom) = &self.rom {
+            status.push_str(&format!("  {}", rom.label()));
+        }
+        // Sound shown next to help when it can not be heard
+        if self.cpu.st > 0 && !self.audio.has_device() {
+            status.push_str("  ♪");
+        }
+        if self.redraw || status != self.status {
+            queue!(out, cursor::MoveTo(0, ROWS as u16), terminal::Clear(terminal::ClearType::CurrentLine), Print(&status))?;
+            self.status = status;
+        }
+        if self.bell {
+            queue!(out, Print('\x07'))?;
+            self.bell = false;
+        }
+        self.redraw = false;
+        return out.flush();
+    }
+}
+
+pub fn live_start(program: Program, options: LiveOptions) -> Result<(), Box<dyn Error>> {
+    let audio = Audio::new(&options.audio, TIMER_RATE as u32)?;
+    let mut cpu = CPU::new(false);
+    cpu.quirks = options.quirks;
+    cpu.load_program(&program)?;
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+    let key_releases = matches!(supports_keyboard_enhancement(), Ok(true));
+    if key_releases {
+        // Alternate keys make Shift+letter arrive as the uppercase letter
+        execute!(
+            stdout,
+            PushKeyboardEnhancementFlags(
+                KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                    | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                    | KeyboardEnhancementFlags::REPORT_ALTERNATE_KEYS
+            )
+        )?;
+    }
+
+    let recorder = options.record.as_ref()
+        .map(|_| Recorder::new(64, 32, options.theme.display_rgb[0], options.theme.display_rgb[1]));
+    let mut live = Live {
+        cpu,
+        keymap: options.keymap,
+        key_hold: options.key_hold,
+        key_presses: [None; 16],
+        key_releases,
+        theme: options.theme,
+        phosphor: Phosphor::new(options.persistence),
+        audio,
+        tick_rate: options.tick_rate,
+        rom: options.rom,
+        recorder,
+        bell: false,
+        lines: Vec::new(),
+        status: String::new(),
+        redraw: true,
+    };
+    let res = run_live(&mut live, &mut stdout);
+    live.audio.finish()?;
+
+    // restore terminal
+    if key_releases {
+        execute!(stdout, PopKeyboardEnhancementFlags)?;
+    }
+    execute!(stdout, ResetColor, cursor::Show, LeaveAlternateScreen)?;
+    disable_raw_mode()?;
+    if let (Some(path), Some(recorder)) = (&options.record, &live.recorder) {
+        fs::write(path, recorder.encode()).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    res?;
+    Ok(())
+}
+
+fn run_live(live: &mut Live, stdout: &mut io::Stdout) -> io::Result<()> {
+    let frame_time = Duration::from_micros(1_000_000 / TIMER_RATE);
+    let mut next_frame = Instant::now();
+    loop {
+        // Handle input until the next frame is due
+        while event::poll(next_frame.saturating_duration_since(Instant::now()))? {
+            match event::read()? {
+                Event::Key(key) if !live.handle_key(key) => return Ok(()),
+                Event::Resize(_, _) => {
+                    queue!(stdout, terminal::Clear(terminal::ClearType::All))?;
+                    live.redraw = true;
+                }
+                _ => {}
+            }
+        }
+        live.release_keys();
+        if !live.frame() {
+            return Err(io::Error::other(format!("Program counter {:03X} is outside memory", live.cpu.pc)));
+        }
+        live.draw(stdout)?;
+
+        // Skip frames instead of running behind
+        next_frame += frame_time;
+        if next_frame < Instant::now() {
+            next_frame = Instant::now() + frame_time;
+        }
+    }
+}
+
+fn to_crossterm(color: Color) -> style::Color {
+    match color {
+        Color::Reset => style::Color::Reset,
+        Color::Black => style::Color::Black,
+        Color::Red => style::Color::DarkRed,
+        Color::Green => style::Color::DarkGreen,
+        Color::Yellow => style::Color::DarkYellow,
+        Color::Blue => style::Color::DarkBlue,
+        Color::Magenta => style::Color::DarkMagenta,
+        Color::Cyan => style::Color::DarkCyan,
+        Color::Gray => style::Color::Grey,
+        Color::DarkGray => style::Color::DarkGrey,
+        Color::LightRed => style::Color::Red,
+        Color::LightGreen => style::Color::Green,
+        Color::LightYellow => style::Color::Yellow,
+        Color::LightBlue => style::Color::Blue,
+        Color::LightMagenta => style::Color::Magenta,
+        Color::LightCyan => style::Color::Cyan,
+        Color::White => style::Color::White,
+        Color::Indexed(idx) => style::Color::AnsiValue(idx),
+        Color::Rgb(r, g, b) => style::Color::Rgb { r, g, b },
+    }
+}
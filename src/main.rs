@@ -1,56 +1,779 @@
 mod cpu;
 mod tui;
 mod disassembler;
+mod assembler;
+mod debugger;
+mod expr;
+mod keymap;
+mod render;
+mod graphics;
+mod theme;
+mod phosphor;
+mod record;
+mod live;
+mod audio;
+mod json;
+mod database;
+mod gif;
+mod octo;
+mod octocart;
+mod memimage;
+mod toml;
+mod config;
 
-use cpu::CPU;
-use std::fmt::format;
-use std::fs::File;
-use std::io::Read;
-use clap::Parser;
+use config::Config;
+use cpu::{CPU, Program, Quirks};
+use database::{Database, RomInfo};
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+use clap::{Parser, Subcommand};
 
-/// Simple program to greet a person
+/// CHIP-8 emulator, debugger and tools
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    /// Name of the person to greet
-    #[arg(short, long, default_value = "false")]
-    tui: bool,
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Play a ROM in the terminal
+    Run(RunArgs),
+    /// Run a ROM in the debugger
+    Tui(TuiArgs),
+    /// Print the instructions of a ROM
+    Disasm(DisasmArgs),
+    /// Assemble source in disassembler syntax to a ROM
+    Asm(AsmArgs),
+    /// Print every executed instruction and the display on draws
+    Trace(TraceArgs),
+    /// Run a ROM without display and report the emulation speed
+    Bench(BenchArgs),
+    /// Show what is known about a ROM and the settings it runs with
+    Info(InfoArgs),
+}
+
+// ROM loading and machine settings shared by the commands that run a ROM
+#[derive(clap::Args, Debug)]
+struct RomArgs {
+    /// ROM file, or an Octo cartridge GIF
+    file: String,
+
+    /// Settings file used instead of ~/.config/chip_8/config.toml. Settings in
+    /// <rom>.toml next to the ROM and the options below override it
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Print the settings after merging all configuration layers and exit
+    #[arg(long, default_value = "false")]
+    print_config: bool,
+
+    /// ROM database directory with platforms.json, programs.json and sha1-hashes.json.
+    /// Defaults to ~/.config/chip_8/database when installed there, else the built in copy
+    #[arg(long)]
+    database: Option<String>,
+
+    /// Platform whose quirks and speed are used, a platform id from the ROM database
+    #[arg(long)]
+    platform: Option<String>,
 
-    #[arg(short, long, default_value = "false")]
-    debug: bool,
+    /// Instructions executed per frame [default: 8]
+    #[arg(long)]
+    tick_rate: Option<u64>,
 
+    /// Quirks to turn on, or off with a leading '-', such as "shift,-vblank"
+    #[arg(long)]
+    quirks: Option<String>,
 
+    /// Executed instructions kept for the debugger [default: 500]
+    #[arg(long)]
+    history: Option<usize>,
+
+    /// Address the ROM is loaded to and started from: hex address, vip (200) or eti660 (600).
+    /// Defaults to the ROM's database entry or 200
+    #[arg(long)]
+    load_address: Option<String>,
+
+    /// Extra data file to place in memory next to the ROM
+    #[arg(long)]
+    data: Option<String>,
+
+    /// Hex address of the data file, directly after the ROM by default
+    #[arg(long)]
+    data_address: Option<String>,
+
+    /// Memory image written over the loaded program, "<file>[@<hex address>]".
+    /// Intel HEX for .hex files, raw images go to address 0 unless given
+    #[arg(long)]
+    import_image: Vec<String>,
+}
+
+// Keypad and display settings for the interactive commands
+#[derive(clap::Args, Debug)]
+struct DisplayArgs {
+    /// Keyboard layout for the keypad: qwerty, azerty, dvorak or numpad [default: qwerty]
     #[arg(short, long)]
+    keymap: Option<String>,
+
+    /// Milliseconds a key stays held when the terminal reports no key releases [default: 250]
+    #[arg(long)]
+    key_hold: Option<u64>,
+
+    /// Colour theme: default, green, amber, xochip, mono or path to a theme file.
+    /// A theme replaces the ROM's database colours
+    #[arg(long)]
+    theme: Option<String>,
+
+    /// Display colours over the theme, comma separated, background first
+    #[arg(long)]
+    palette: Option<String>,
+
+    /// Colours the terminal can show: auto, truecolor, 256, 16 or mono [default: auto]
+    #[arg(long)]
+    colors: Option<String>,
+
+    /// Flicker reduction: off, hold[:frames] or decay[:factor] [default: off]
+    #[arg(long)]
+    persistence: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct AudioArgs {
+    /// Beeper waveform: square or sine [default: square]
+    #[arg(long)]
+    waveform: Option<String>,
+
+    /// Beeper frequency in Hz [default: 440]
+    #[arg(long)]
+    tone: Option<f64>,
+
+    /// Beeper volume, 0-100 [default: 25]
+    #[arg(long)]
+    volume: Option<f64>,
+
+    /// Record sound to a WAV file
+    #[arg(long)]
+    wav: Option<String>,
+
+    /// Do not play sound, terminal bell and visual indicator are used instead
+    #[arg(long, default_value = "false")]
+    mute: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+
+    #[command(flatten)]
+    display: DisplayArgs,
+
+    #[command(flatten)]
+    audio: AudioArgs,
+
+    /// Write the ROM with its quirks, speed and colours as an Octo cartridge GIF and exit
+    #[arg(long)]
+    export_cart: Option<String>,
+
+    /// Write memory after loading to a raw or Intel HEX (.hex) image and exit
+    #[arg(long)]
+    export_image: Option<String>,
+
+    /// Memory exported with --export-image: all, <start>-<end> or <start>+<length>
+    #[arg(long, default_value = "all")]
+    export_range: String,
+
+    /// Record the display as shown, with persistence, to an animated GIF written on exit
+    #[arg(long)]
+    record: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct TuiArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+
+    #[command(flatten)]
+    display: DisplayArgs,
+
+    #[command(flatten)]
+    audio: AudioArgs,
+
+    /// Symbol file with "<hex address> <name>" per line
+    #[arg(short, long)]
+    symbols: Option<String>,
+
+    /// Display render mode: stretch, block, halfblock or braille [default: halfblock]
+    #[arg(short, long)]
+    render: Option<String>,
+
+    /// Terminal image protocol for the display: auto, kitty, sixel or none [default: auto]
+    #[arg(short, long)]
+    graphics: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DisasmArgs {
+    /// ROM file, or an Octo cartridge GIF
+    file: String,
+
+    /// Symbol file with "<hex address> <name>" per line
+    #[arg(short, long)]
+    symbols: Option<String>,
+
+    /// Address the ROM is loaded to: hex address, vip (200) or eti660 (600)
+    #[arg(long, default_value = "200")]
+    load_address: String,
+
+    /// Print source the asm command can assemble, with labels for jump targets
+    #[arg(long, default_value = "false")]
+    source: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct AsmArgs {
+    /// Source file
     file: String,
+
+    /// ROM file to write, the source file name with .ch8 by default
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Address the ROM is loaded to, labels count from it: hex address, vip (200) or eti660 (600)
+    #[arg(long, default_value = "200")]
+    load_address: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct TraceArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+
+    /// Stop after this many instructions, by default runs until the program jumps to itself, waits for a key or leaves memory
+    #[arg(long)]
+    cycles: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BenchArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+
+    /// Instructions to execute
+    #[arg(long, default_value = "1000000")]
+    cycles: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    #[command(flatten)]
+    rom: RomArgs,
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    // Open file on arg 1
-    let mut file = File::open(args.file).expect("File not found");
-    let mut binary: Vec<u8> = Vec::new();
-    file.read_to_end(&mut binary).expect("Error reading file");
+    // --print-config shows the merged settings instead of running the command
+    let printed = match cli.command.rom_layer() {
+        Some((rom, layer)) if rom.print_config => Some(layer.and_then(|layer| print_config(rom, layer))),
+        _ => None,
+    };
+    let result = match printed {
+        Some(result) => result,
+        None => match cli.command {
+            Command::Run(args) => run(args),
+            Command::Tui(args) => run_tui(args),
+            Command::Disasm(args) => disasm(args),
+            Command::Asm(args) => asm(args),
+            Command::Trace(args) => trace(args),
+            Command::Bench(args) => bench(args),
+            Command::Info(args) => info(args),
+        },
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
 
-    if args.tui {
-        let _ = tui::tui_start(binary, args.debug);
-    } else {
-        println!("Starting CHIP-8 emulator...");
-        let mut cpu = CPU::new(args.debug);
+impl Command {
+    // ROM arguments and command line settings layer of commands that open a ROM
+    fn rom_layer(&self) -> Option<(&RomArgs, Result<Config, String>)> {
+        return match self {
+            Command::Run(args) => Some((&args.rom, args.layer())),
+            Command::Tui(args) => Some((&args.rom, args.layer())),
+            Command::Trace(args) => Some((&args.rom, args.rom.layer())),
+            Command::Bench(args) => Some((&args.rom, args.rom.layer())),
+            Command::Info(args) => Some((&args.rom, args.rom.layer())),
+            Command::Disasm(_) | Command::Asm(_) => None,
+        };
+    }
+}
 
-        cpu.load_bin(binary, false);
+impl RunArgs {
+    fn layer(&self) -> Result<Config, String> {
+        let mut layer = self.rom.layer()?;
+        self.display.fill(&mut layer);
+        self.audio.fill(&mut layer);
+        return Ok(layer);
+    }
+}
+
+impl TuiArgs {
+    fn layer(&self) -> Result<Config, String> {
+        let mut layer = self.rom.layer()?;
+        self.display.fill(&mut layer);
+        self.audio.fill(&mut layer);
+        layer.render = self.render.clone();
+        layer.graphics = self.graphics.clone();
+        return Ok(layer);
+    }
+}
+
+impl RomArgs {
+    // Command line settings layer
+    fn layer(&self) -> Result<Config, String> {
+        return Ok(Config {
+            platform: self.platform.clone(),
+            tick_rate: self.tick_rate.map(|t| t.max(1)),
+            load_address: self.load_address.clone(),
+            history: self.history.map(|h| h.max(1)),
+            quirks: match &self.quirks {
+                Some(spec) => config::QuirkSettings::parse(spec)?,
+                None => config::QuirkSettings::default(),
+            },
+            ..Config::default()
+        });
+    }
+}
+
+impl DisplayArgs {
+    fn fill(&self, config: &mut Config) {
+        config.keymap = self.keymap.clone();
+        config.key_hold = self.key_hold;
+        config.theme = self.theme.clone();
+        config.palette = self.palette.as_ref().map(|p| p.split(',').map(|c| c.trim().to_string()).collect());
+        config.colors = self.colors.clone();
+        config.persistence = self.persistence.clone();
+    }
+}
+
+impl AudioArgs {
+    fn fill(&self, config: &mut Config) {
+        config.waveform = self.waveform.clone();
+        config.tone = self.tone;
+        config.volume = self.volume;
+        config.wav = self.wav.clone();
+        config.mute = self.mute.then_some(true);
+    }
+}
+
+// ROM loaded with its settings
+struct Session {
+    program: Program,
+    rom: Option<RomInfo>, // Database entry or cartridge settings
+    cartridge: bool,
+    config: Config, // Every layer merged, the defaults layer sets all values but palette, bindings and wav
+    quirks: Quirks,
+}
+
+// ROM bytes, from the cartridge for Octo cartridges
+fn read_rom(path: &str) -> Result<(Vec<u8>, Option<octocart::Cart>), String> {
+    let binary = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if !octocart::is_cart(&binary) {
+        return Ok((binary, None));
+    }
+    let cart = octocart::read(&binary).map_err(|e| format!("{}: {}", path, e))?;
+    return Ok((cart.rom.clone(), Some(cart)));
+}
+
+// Read the ROM, look it up and merge the settings layers
+fn open(args: &RomArgs, command_line: Config) -> Result<Session, String> {
+    let (binary, rom, cartridge, config) = read_settings(args, command_line)?;
+    let address = cpu::parse_load_address(config.load_address.as_ref().unwrap())?;
+    let program = load_program(args, binary, address)?;
+    let quirks = config.quirks.apply(Quirks::default());
+    return Ok(Session { program, rom, cartridge, config, quirks });
+}
+
+fn print_config(args: &RomArgs, command_line: Config) -> Result<(), String> {
+    let (_, _, _, config) = read_settings(args, command_line)?;
+    print!("{}", config.to_toml());
+    return Ok(());
+}
+
+// ROM bytes, what is known about the ROM, whether it is a cartridge and the
+// merged settings
+fn read_settings(args: &RomArgs, command_line: Config) -> Result<(Vec<u8>, Option<RomInfo>, bool, Config), String> {
+    // Platform, quirks and colours of known ROMs, defaults otherwise
+    let database = Database::find(args.database.as_deref())?;
+    // Octo cartridges carry their own settings
+    let (binary, cart) = read_rom(&args.file)?;
+    let cartridge = cart.is_some();
+    let rom = match cart {
+        Some(cart) => {
+            let title = Path::new(&args.file).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            Some(cart.rom_info(&title))
+        }
+        None => database.lookup(&binary),
+    };
+    let config = load_config(args, rom.as_ref(), &database, command_line)?;
+    return Ok((binary, rom, cartridge, config));
+}
+
+// Settings layers in order: defaults, global file, database entry or
+// cartridge, <rom>.toml with <rom>.keymap and the command line
+fn load_config(args: &RomArgs, rom: Option<&RomInfo>, database: &Database, command_line: Config) -> Result<Config, String> {
+    let global = match &args.config {
+        Some(path) => Some(Config::load(path)?.ok_or(format!("{}: file not found", path))?),
+        None => match config::global_path() {
+            Some(path) => Config::load(&path.to_string_lossy())?,
+            None => None,
+        },
+    };
+    let mut rom_file = Config::load(&config::rom_path(&args.file))?;
+    if let Some(bindings) = keymap::load_overrides(&keymap::rom_path(&args.file))? {
+        let layer = rom_file.get_or_insert_with(Config::default);
+        layer.bindings = Some(match layer.bindings.take() {
+            Some(earlier) => format!("{}, {}", earlier, bindings),
+            None => bindings,
+        });
+    }
+    let layers = [
+        global,
+        rom.map(Config::from_rom),
+        rom_file,
+        Some(command_line),
+    ];
+    let mut config = Config::defaults();
+    for layer in layers.iter().flatten() {
+        config.merge(layer, database)?;
+    }
+    return Ok(config);
+}
+
+// ROM and data file placed as given on the command line
+fn load_program(args: &RomArgs, rom: Vec<u8>, address: u16) -> Result<Program, String> {
+    let data = match &args.data {
+        Some(path) => {
+            let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            let data_address = match &args.data_address {
+                Some(input) => debugger::parse_address(input).ok_or(format!("Invalid data address '{}'", input))?,
+                None => (address as usize + rom.len()).min(u16::MAX as usize) as u16,
+            };
+            Some((data, data_address))
+        }
+        None => None,
+    };
+    let mut patches = Vec::new();
+    for image in &args.import_image {
+        let (path, address) = match image.rsplit_once('@') {
+            Some((path, address)) => (path, debugger::parse_address(address).ok_or(format!("Invalid image address '{}'", address))?),
+            None => (image.as_str(), 0),
+        };
+        patches.extend(memimage::load(path, address)?);
+    }
+    let program = Program { rom, address, data, patches };
+    program.validate().map_err(|e| format!("{}: {}", args.file, e))?;
+    return Ok(program);
+}
+
+// CPU with the session's program and settings
+fn session_cpu(session: &Session, debug: bool) -> Result<CPU, String> {
+    let mut cpu = CPU::new(debug);
+    cpu.quirks = session.quirks;
+    cpu.history_limit = session.config.history.unwrap();
+    cpu.pixel_chars = (session.config.char_on.unwrap(), session.config.char_off.unwrap());
+    cpu.load_program(&session.program)?;
+    return Ok(cpu);
+}
+
+// Key map from preset, ROM keys and configured bindings
+fn session_keymap(session: &Session) -> Result<(keymap::KeyMap, Duration), String> {
+    let config = &session.config;
+    let preset = config.keymap.as_ref().unwrap();
+    let mut keymap = keymap::KeyMap::preset(preset).ok_or(format!("Unknown key map preset '{}'", preset))?;
+    if let Some(rom) = &session.rom {
+        keymap.apply_rom_keys(&rom.keys);
+    }
+    if let Some(bindings) = &config.bindings {
+        keymap.apply(bindings)?;
+    }
+    return Ok((keymap, Duration::from_millis(config.key_hold.unwrap())));
+}
+
+fn session_theme(config: &Config) -> Result<theme::Theme, String> {
+    let depth = theme::parse_depth(config.colors.as_ref().unwrap())?;
+    let name = config.theme.as_ref().unwrap();
+    let mut theme_spec = theme::ThemeSpec::builtin(name)
+        .ok_or(format!("Unknown theme '{}', expected one of: {}", name, theme::THEMES.join(" ")))?;
+    for (key, color) in &config.theme_colors {
+        theme_spec.set(key, color)?;
+    }
+    // Background and fill, there is no second XO-CHIP plane to use the others
+    for (idx, color) in config.palette.iter().flatten().take(2).enumerate() {
+        theme_spec.set(&format!("color{}", idx), color)?;
+    }
+    return Ok(theme_spec.resolve(depth));
+}
+
+fn session_audio(config: &Config) -> Result<audio::AudioOptions, String> {
+    return Ok(audio::AudioOptions {
+        waveform: audio::Waveform::parse(config.waveform.as_ref().unwrap())
+            .ok_or("Unknown waveform, expected square or sine".to_string())?,
+        frequency: config.tone.unwrap(),
+        volume: config.volume.unwrap() / 100.0,
+        wav: config.wav.clone(),
+        device: !config.mute.unwrap(),
+    });
+}
+
+fn run(args: RunArgs) -> Result<(), String> {
+    let session = open(&args.rom, args.layer()?)?;
+    let config = &session.config;
+    let theme = session_theme(config)?;
+
+    if let Some(path) = &args.export_image {
+        let (start, end) = memimage::parse_range(&args.export_range)
+            .ok_or(format!("Invalid export range '{}'", args.export_range))?;
+        let cpu = session_cpu(&session, false)?;
+        memimage::save(path, &cpu.get_memory(), start, end)?;
+        return Ok(());
+    }
+
+    if let Some(path) = &args.export_cart {
+        let cart = octocart::Cart {
+            rom: session.program.rom.clone(),
+            tick_rate: config.tick_rate,
+            quirks: session.quirks,
+            colors: Vec::new(),
+            keys: session.rom.as_ref().map(|rom| rom.keys.clone()).unwrap_or_default(),
+        };
+        fs::write(path, octocart::write(&cart, &theme.display_rgb)).map_err(|e| format!("{}: {}", path, e))?;
+        return Ok(());
+    }
+
+    let (keymap, key_hold) = session_keymap(&session)?;
+    let options = live::LiveOptions {
+        keymap,
+        key_hold,
+        theme,
+        persistence: phosphor::Persistence::parse(config.persistence.as_ref().unwrap())?,
+        audio: session_audio(config)?,
+        quirks: session.quirks,
+        tick_rate: config.tick_rate.unwrap(),
+        rom: session.rom,
+        record: args.record.clone(),
+    };
+    return live::live_start(session.program, options).map_err(|e| e.to_string());
+}
+
+fn run_tui(args: TuiArgs) -> Result<(), String> {
+    let session = open(&args.rom, args.layer()?)?;
+    let config = &session.config;
 
-        let rows = cpu.get_registers().into_iter().enumerate().map(|(idx, x)| format!("V{:X}:{:X}",idx, x)).collect::<Vec<String>>();
+    let symbols = match &args.symbols {
+        Some(path) => disassembler::Symbols::load(path)?,
+        None => disassembler::Symbols::default(),
+    };
+    let (keymap, key_hold) = session_keymap(&session)?;
+    let render_mode = render::RenderMode::parse(config.render.as_ref().unwrap())
+        .ok_or(format!("Unknown render mode, expected one of: {}", render::RENDER_MODES.join(" ")))?;
+    let options = tui::TuiOptions {
+        debug: false,
+        symbols,
+        keymap,
+        key_hold,
+        render_mode,
+        graphics: graphics::select(config.graphics.as_ref().unwrap())?,
+        theme: session_theme(config)?,
+        persistence: phosphor::Persistence::parse(config.persistence.as_ref().unwrap())?,
+        audio: session_audio(config)?,
+        quirks: session.quirks,
+        history: config.history.unwrap(),
+        tick_rate: config.tick_rate.unwrap(),
+        rom: session.rom,
+    };
+    return tui::tui_start(session.program, options).map_err(|e| e.to_string());
+}
 
-        println!("{:?}", rows);
+fn disasm(args: DisasmArgs) -> Result<(), String> {
+    let (binary, _) = read_rom(&args.file)?;
+    // Listing covers memory the ROM would be loaded to
+    let program = Program { rom: binary, address: cpu::parse_load_address(&args.load_address)?, data: None, patches: Vec::new() };
+    program.validate().map_err(|e| format!("{}: {}", args.file, e))?;
+    let mut symbols = match &args.symbols {
+        Some(path) => disassembler::Symbols::load(path)?,
+        None => disassembler::Symbols::default(),
+    };
+    let instructions: Vec<(u16, &[u8])> = program.rom.chunks(2).enumerate()
+        .map(|(idx, bytes)| (program.address + idx as u16 * 2, bytes))
+        .collect();
+    // Jump and call targets inside the ROM get labels for source output
+    if args.source {
+        for (_, bytes) in &instructions {
+            let target = match bytes {
+                [high, low] => disassembler::branch_target((*high as u16) << 8 | *low as u16),
+                _ => None,
+            };
+            if let Some(target) = target.filter(|t| instructions.iter().any(|(a, _)| a == t)) {
+                if symbols.get(target).is_none() {
+                    symbols.insert(target, &format!("L{:03X}", target));
+                }
+            }
+        }
+    }
 
-        cpu.run();
-        return;
+    for (addr, bytes) in &instructions {
+        if let Some(name) = symbols.get(*addr) {
+            println!("{}:", name);
+        }
+        let (hex, text) = match bytes {
+            [high, low] => {
+                let ins = (*high as u16) << 8 | *low as u16;
+                let mut text = disassembler::decode(ins);
+                // Bits the disassembly leaves out, such as the last nibble of 5XY0, are kept as data
+                if args.source && assembler::assemble(&text, *addr).ok().as_deref() != Some(&bytes[..]) {
+                    text = format!("DB {:02X}, {:02X}", high, low);
+                }
+                if let Some(name) = disassembler::branch_target(ins).and_then(|t| symbols.get(t)).filter(|_| args.source) {
+                    // Keep "V0, " of JP V0, nnn
+                    let operand = text.rsplit_once(' ').map(|(start, _)| start.to_string()).unwrap_or_default();
+                    text = format!("{} {}", operand, name);
+                }
+                (format!("{:02X} {:02X}", high, low), text)
+            }
+            _ => (format!("{:02X}   ", bytes[0]), format!("DB {:02X}", bytes[0])),
+        };
+        if args.source {
+            println!("    {:<24}; {:03X}  {}", text, addr, hex);
+        } else {
+            println!("{:03X}  {}  {}", addr, hex, text);
+        }
+    }
+    return Ok(());
+}
 
+fn asm(args: AsmArgs) -> Result<(), String> {
+    let source = fs::read_to_string(&args.file).map_err(|e| format!("{}: {}", args.file, e))?;
+    let address = cpu::parse_load_address(&args.load_address)?;
+    let binary = assembler::assemble(&source, address).map_err(|e| format!("{}: {}", args.file, e))?;
+    let output = args.output.unwrap_or_else(|| Path::new(&args.file).with_extension("ch8").to_string_lossy().to_string());
+    if output == args.file {
+        return Err(format!("{}: output would overwrite the source, give --output", args.file));
     }
+    fs::write(&output, &binary).map_err(|e| format!("{}: {}", output, e))?;
+    println!("{}: {} bytes", output, binary.len());
+    return Ok(());
+}
 
+fn trace(args: TraceArgs) -> Result<(), String> {
+    let session = open(&args.rom, args.rom.layer()?)?;
+    // Trace every instruction and print the display on draws
+    let mut cpu = session_cpu(&session, false)?;
+    let mut cycles = 0;
+    while args.cycles.is_none_or(|limit| cycles < limit) {
+        let instruction = match cpu.fetch_no_increment() {
+            Some(instruction) => instruction,
+            None => return Err(format!("Program counter {:03X} is outside memory", cpu.pc)),
+        };
+        println!("PC: {:04X} INS: {:04X}  {}", cpu.pc, instruction, disassembler::decode(instruction));
+        // Programs end by jumping to themselves, there are no keys to wait for
+        if args.cycles.is_none() && instruction == 0x1000 | cpu.pc {
+            println!("Program jumps to itself at {:03X}, stopping", cpu.pc);
+            break;
+        }
+        let pc = cpu.pc;
+        cpu.next_cycle();
+        cycles += 1;
+        let events = cpu.get_events();
+        let waits = events.iter().any(|e| matches!(e, cpu::CpuEvent::KeyWait(_)));
+        if events.iter().any(|e| matches!(e, cpu::CpuEvent::Draw { .. })) {
+            cpu.print_vbuf();
+            println!();
+        }
+        if args.cycles.is_none() && waits {
+            println!("Program waits for a key at {:03X}, stopping", pc);
+            break;
+        }
+    }
+    return Ok(());
 }
 
-   /*
-    let args: Vec<String> = std::env::args().collect();
-   */
+fn bench(args: BenchArgs) -> Result<(), String> {
+    let session = open(&args.rom, args.rom.layer()?)?;
+    let tick_rate = session.config.tick_rate.unwrap();
+    let mut cpu = session_cpu(&session, false)?;
+
+    // Timers tick every tick_rate instructions, like frames without the wait
+    let start = Instant::now();
+    let mut cycles = 0;
+    while cycles < args.cycles && cpu.pc_in_memory() {
+        cpu.next_cycle();
+        cycles += 1;
+        if cycles % tick_rate == 0 {
+            cpu.tick_timers();
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64().max(1e-9);
+    let frames = cycles / tick_rate;
+    println!("{} instructions in {:.3} s", cycles, elapsed);
+    println!("{:.0} instructions/s", cycles as f64 / elapsed);
+    println!("{:.0} frames/s at {} instructions per frame, {:.1}x real time",
+        frames as f64 / elapsed, tick_rate, frames as f64 / elapsed / cpu::TIMER_RATE as f64);
+    if cycles < args.cycles {
+        println!("Stopped early, program counter left memory at {:03X}", cpu.pc);
+    }
+    return Ok(());
+}
+
+fn info(args: InfoArgs) -> Result<(), String> {
+    let session = open(&args.rom, args.rom.layer()?)?;
+    let config = &session.config;
+    let program = &session.program;
+    let binary = &program.rom;
+
+    println!("File:         {}", args.rom.file);
+    println!("Size:         {} bytes", binary.len());
+    println!("SHA-1:        {}", database::sha1_hex(binary));
+    if session.cartridge {
+        println!("Format:       Octo cartridge");
+    }
+    match &session.rom {
+        Some(rom) if !session.cartridge => {
+            println!("Title:        {}", rom.title);
+            if !rom.authors.is_empty() {
+                println!("Authors:      {}", rom.authors.join(", "));
+            }
+            if let Some(release) = &rom.release {
+                println!("Release:      {}", release);
+            }
+        }
+        Some(_) => {}
+        None => println!("Title:        not in the ROM database"),
+    }
+    println!("Platform:     {}", config.platform.as_deref().unwrap_or("none"));
+    println!("Load address: {:03X}-{:03X}", program.address, program.address as usize + program.rom.len() - 1);
+    println!("Tick rate:    {} instructions per frame", config.tick_rate.unwrap());
+    let quirks = config::QUIRK_NAMES.iter().zip([
+        session.quirks.shift,
+        session.quirks.memory_increment_by_x,
+        session.quirks.memory_leave_i_unchanged,
+        session.quirks.wrap,
+        session.quirks.jump,
+        session.quirks.vblank,
+        session.quirks.logic,
+    ]).filter(|(_, on)| *on).map(|(name, _)| *name).collect::<Vec<&str>>();
+    println!("Quirks:       {}", if quirks.is_empty() { "none".to_string() } else { quirks.join(" ") });
+    if let Some(rom) = session.rom.as_ref().filter(|rom| !rom.keys.is_empty()) {
+        let keys = rom.keys.iter().map(|(name, key)| format!("{}={:X}", name, key)).collect::<Vec<String>>();
+        println!("Keys:         {}", keys.join(" "));
+    }
+    if let Some(palette) = &config.palette {
+        println!("Colours:      {}", palette.join(" "));
+    }
+    return Ok(());
+}
@@ -0,0 +1,217 @@
+use crate::cpu::MEMORY_SIZE;
+use crate::debugger::parse_address;
+use std::fs;
+use std::path::Path;
+
+// Memory images on disk, as raw bytes or Intel HEX. A raw image is one block
+// placed at a given address, Intel HEX records carry their own addresses.
+//
+//   :10020000601EF0181204000000000000000000005F
+//   :00000001FF
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageFormat {
+    Raw,
+    IntelHex,
+}
+
+const HEX_RECORD_LEN: usize = 16; // Data bytes per Intel HEX record
+
+impl ImageFormat {
+    // Intel HEX for .hex and .ihx files, raw otherwise
+    pub fn from_path(path: &str) -> ImageFormat {
+        let ext = Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        match ext.as_str() {
+            "hex" | "ihx" | "ihex" => ImageFormat::IntelHex,
+            _ => ImageFormat::Raw,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::IntelHex => "Intel HEX",
+        }
+    }
+}
+
+// Blocks of bytes and their start address
+pub type Blocks = Vec<(u16, Vec<u8>)>;
+
+// Inclusive address range: "all", "<start>-<end>" or "<start>+<length>", hex
+pub fn parse_range(input: &str) -> Option<(u16, u16)> {
+    let input = input.trim();
+    if input.is_empty() || input == "all" {
+        return Some((0, MEMORY_SIZE as u16 - 1));
+    }
+    let (start, end) = if let Some((start, end)) = input.split_once('-') {
+        (parse_address(start)?, parse_address(end)?)
+    } else if let Some((start, len)) = input.split_once('+') {
+        let start = parse_address(start)?;
+        let len = u16::from_str_radix(len.trim().trim_start_matches("0x"), 16).ok().filter(|l| *l > 0)?;
+        (start, start.checked_add(len - 1)?)
+    } else {
+        let address = parse_address(input)?;
+        (address, address)
+    };
+    if start > end || end as usize >= MEMORY_SIZE {
+        return None;
+    }
+    return Some((start, end));
+}
+
+// Image of memory[start..=end]
+pub fn export(memory: &[u8], start: u16, end: u16, format: ImageFormat) -> Vec<u8> {
+    let bytes = &memory[start as usize..=end as usize];
+    match format {
+        ImageFormat::Raw => bytes.to_vec(),
+        ImageFormat::IntelHex => {
+            let mut out = String::new();
+            for (idx, chunk) in bytes.chunks(HEX_RECORD_LEN).enumerate() {
+                out.push_str(&hex_record(start + (idx * HEX_RECORD_LEN) as u16, 0x00, chunk));
+            }
+            out.push_str(&hex_record(0, 0x01, &[]));
+            out.into_bytes()
+        }
+    }
+}
+
+fn hex_record(address: u16, kind: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+    bytes.push(checksum);
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    return format!(":{}\n", hex);
+}
+
+// Blocks in an image, raw images are placed at address
+pub fn import(data: &[u8], format: ImageFormat, address: u16) -> Result<Blocks, String> {
+    let blocks = match format {
+        ImageFormat::Raw => vec![(address, data.to_vec())],
+        ImageFormat::IntelHex => parse_hex(data)?,
+    };
+    for (start, bytes) in &blocks {
+        if *start as usize + bytes.len() > MEMORY_SIZE {
+            return Err(format!("{} bytes at {:03X} do not fit in memory", bytes.len(), start));
+        }
+    }
+    return Ok(blocks);
+}
+
+fn parse_hex(data: &[u8]) -> Result<Blocks, String> {
+    let text = std::str::from_utf8(data).map_err(|_| "Intel HEX file is not text".to_string())?;
+    let mut blocks: Blocks = Vec::new();
+    let mut base = 0u32; // Upper address bits from type 02 and 04 records
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("Intel HEX line {}: {}", line_no + 1, message);
+        let hex = line.strip_prefix(':').ok_or(error("record does not start with ':'"))?;
+        if !hex.is_ascii() {
+            return Err(error("invalid hex digit"));
+        }
+        if hex.len() % 2 != 0 || hex.len() < 10 {
+            return Err(error("invalid record length"));
+        }
+        let bytes = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| error("invalid hex digit"))?;
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(error("checksum mismatch"));
+        }
+        let len = bytes[0] as usize;
+        if bytes.len() != len + 5 {
+            return Err(error("byte count does not match record"));
+        }
+        let offset = (bytes[1] as u32) << 8 | bytes[2] as u32;
+        let payload = &bytes[4..4 + len];
+        match bytes[3] {
+            0x00 => {
+                let address = base + offset;
+                if address as usize + len > MEMORY_SIZE {
+                    return Err(error(&format!("address {:X} is outside memory", address)));
+                }
+                // Join records that continue the previous block
+                match blocks.last_mut() {
+                    Some((start, block)) if *start as usize + block.len() == address as usize => block.extend_from_slice(payload),
+                    _ => blocks.push((address as u16, payload.to_vec())),
+                }
+            }
+            0x01 => return Ok(blocks),
+            0x02 if len == 2 => base = ((payload[0] as u32) << 8 | payload[1] as u32) << 4,
+            0x04 if len == 2 => base = ((payload[0] as u32) << 8 | payload[1] as u32) << 16,
+            0x03 | 0x05 => {} // Start address, not used
+            kind => return Err(error(&format!("unsupported record type {:02X}", kind))),
+        }
+    }
+    return Ok(blocks);
+}
+
+pub fn save(path: &str, memory: &[u8], start: u16, end: u16) -> Result<ImageFormat, String> {
+    let format = ImageFormat::from_path(path);
+    fs::write(path, export(memory, start, end, format)).map_err(|e| format!("{}: {}", path, e))?;
+    return Ok(format);
+}
+
+pub fn load(path: &str, address: u16) -> Result<Blocks, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    return import(&data, ImageFormat::from_path(path), address).map_err(|e| format!("{}: {}", path, e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let memory: Vec<u8> = (0..MEMORY_SIZE).map(|idx| (idx * 31 % 256) as u8).collect();
+        let hex = export(&memory, 0x1f8, 0x21a, ImageFormat::IntelHex);
+        let text = String::from_utf8(hex.clone()).unwrap();
+        assert!(text.starts_with(":1001F800"));
+        assert!(text.ends_with(":00000001FF\n"));
+        assert_eq!(import(&hex, ImageFormat::IntelHex, 0).unwrap(), vec![(0x1f8, memory[0x1f8..=0x21a].to_vec())]);
+
+        let raw = export(&memory, 0x200, 0x20f, ImageFormat::Raw);
+        assert_eq!(import(&raw, ImageFormat::Raw, 0x300).unwrap(), vec![(0x300, memory[0x200..=0x20f].to_vec())]);
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(hex_record(0x200, 0x00, &[0x60, 0x1e]), ":02020000601E7E\n");
+        let err = import(b":02020000601E7F\n", ImageFormat::IntelHex, 0).unwrap_err();
+        assert_eq!(err, "Intel HEX line 1: checksum mismatch");
+    }
+
+    #[test]
+    fn segment_and_linear_records() {
+        // Type 02 sets bits 4-19, type 04 bits 16-31 of following addresses
+        let hex = ":020000020010EC\n:01000300AA52\n:020000040000FA\n:01010000BB43\n:00000001FF\n";
+        let blocks = import(hex.as_bytes(), ImageFormat::IntelHex, 0).unwrap();
+        assert_eq!(blocks, vec![(0x103, vec![0xaa]), (0x100, vec![0xbb])]);
+
+        let err = import(b":020000040001F9\n:01000000AA55\n", ImageFormat::IntelHex, 0).unwrap_err();
+        assert_eq!(err, "Intel HEX line 2: address 10000 is outside memory");
+    }
+
+    #[test]
+    fn invalid_records() {
+        let import_hex = |text: &str| import(text.as_bytes(), ImageFormat::IntelHex, 0).unwrap_err();
+        assert_eq!(import_hex("02020000601E7E"), "Intel HEX line 1: record does not start with ':'");
+        assert_eq!(import_hex(":0202é0000601E7E"), "Intel HEX line 1: invalid hex digit");
+        assert_eq!(import_hex(":03020000609B\n"), "Intel HEX line 1: byte count does not match record");
+        assert!(import(&[0; 4096], ImageFormat::Raw, 1).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("all"), Some((0, 0xfff)));
+        assert_eq!(parse_range("200-2FF"), Some((0x200, 0x2ff)));
+        assert_eq!(parse_range("200+10"), Some((0x200, 0x20f)));
+        assert_eq!(parse_range("2FF-200"), None);
+        assert_eq!(parse_range("F00+200"), None);
+    }
+}
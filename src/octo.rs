@@ -0,0 +1,484 @@
+use crate::cpu::{MEMORY_SIZE, PROGRAM_START};
+use std::collections::HashMap;
+
+// Compiler for the part of Octo, the assembly language of Octo cartridges,
+// that targets plain CHIP-8. '#' starts a comment.
+//
+//   : label            :const NAME value     :alias NAME vX      :org address
+//   :call label        label                 jump label          jump0 label
+//   return  ;          clear                 bcd vX  save vX  load vX
+//   sprite vX vY n     delay := vX           buzzer := vX
+//   i := address       i := hex vX           i += vX
+//   vX := n | vY | random n | delay | key
+//   vX += -= n | vY    vX |= &= ^= =- >>= <<= vY
+//   if vX == != n | vY then ...              if vX key | -key then ...
+//   if ... begin ... else ... end            loop ... while ... again
+//
+// A number on its own is a data byte. Macros, calc expressions, strings and
+// the SUPER-CHIP and XO-CHIP instructions are not supported.
+
+// Binary for source, loaded at PROGRAM_START and run from main
+pub fn compile(source: &str) -> Result<Vec<u8>, String> {
+    let (rom, labels) = Compiler::new(source, false).run()?;
+    // Like Octo, start with a jump to main when main is not first
+    return match labels.get("main") {
+        Some(&main) if main != PROGRAM_START => Ok(Compiler::new(source, true).run()?.0),
+        _ => Ok(rom),
+    };
+}
+
+enum Flow {
+    Begin(usize), // Offset of the jump past the block
+    Else(usize),
+    Loop(u16, Vec<usize>), // Start address and the jumps out of while
+}
+
+struct Compiler {
+    tokens: Vec<(String, usize)>, // Token and line number
+    pos: usize,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize, // Offset into rom
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    fixups: Vec<(usize, String, usize)>, // Instruction offset, label, line
+    flow: Vec<Flow>,
+}
+
+impl Compiler {
+    fn new(source: &str, jump_to_main: bool) -> Compiler {
+        let tokens = source.lines().enumerate().flat_map(|(idx, line)| {
+            let line_text = line.split('#').next().unwrap_or("");
+            line_text.split_whitespace().map(move |token| (token.to_string(), idx + 1))
+        }).collect();
+        let mut compiler = Compiler {
+            tokens,
+            pos: 0,
+            line: 0,
+            rom: Vec::new(),
+            here: 0,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+        };
+        if jump_to_main {
+            compiler.fixups.push((0, "main".to_string(), 1));
+            compiler.rom.extend([0x10, 0x00]);
+            compiler.here = 2;
+        }
+        return compiler;
+    }
+
+    fn run(mut self) -> Result<(Vec<u8>, HashMap<String, u16>), String> {
+        while self.pos < self.tokens.len() {
+            self.statement().map_err(|e| format!("Octo source line {}: {}", self.line, e))?;
+        }
+        if let Some(flow) = self.flow.last() {
+            let missing = if let Flow::Loop(..) = flow { "again" } else { "end" };
+            return Err(format!("Octo source ends without '{}'", missing));
+        }
+        for (offset, label, line) in &self.fixups {
+            let address = *self.labels.get(label)
+                .ok_or(format!("Octo source line {}: unknown label '{}'", line, label))?;
+            self.rom[*offset] |= (address >> 8) as u8 & 0x0f;
+            self.rom[*offset + 1] = address as u8;
+        }
+        return Ok((self.rom, self.labels));
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let (token, line) = self.tokens.get(self.pos).ok_or("unexpected end of source")?;
+        self.line = *line;
+        self.pos += 1;
+        return Ok(token.clone());
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(format!("expected '{}', found '{}'", expected, token));
+        }
+        return Ok(());
+    }
+
+    fn address(&self) -> u16 {
+        return PROGRAM_START + self.here as u16;
+    }
+
+    fn byte(&mut self, value: u8) -> Result<(), String> {
+        if PROGRAM_START as usize + self.here >= MEMORY_SIZE {
+            return Err("program does not fit in memory".to_string());
+        }
+        if self.here >= self.rom.len() {
+            self.rom.resize(self.here + 1, 0);
+        }
+        self.rom[self.here] = value;
+        self.here += 1;
+        return Ok(());
+    }
+
+    fn instruction(&mut self, ins: u16) -> Result<usize, String> {
+        let offset = self.here;
+        self.byte((ins >> 8) as u8)?;
+        self.byte(ins as u8)?;
+        return Ok(offset);
+    }
+
+    // NNN instruction, labels not defined yet are filled in at the end
+    fn instruction_to(&mut self, op: u16, target: &str) -> Result<(), String> {
+        if let Some(address) = self.labels.get(target).copied().or(self.value(target).map(|v| v as u16)) {
+            if address as usize >= MEMORY_SIZE {
+                return Err(format!("address '{}' is past the end of memory", target));
+            }
+            self.instruction(op | address)?;
+        } else if is_name(target) {
+            let offset = self.instruction(op)?;
+            self.fixups.push((offset, target.to_string(), self.line));
+        } else {
+            return Err(format!("'{}' is not an address", target));
+        }
+        return Ok(());
+    }
+
+    // Set a jump emitted earlier to land here
+    fn land(&mut self, offset: usize) {
+        let address = self.address();
+        self.rom[offset] = 0x10 | (address >> 8) as u8;
+        self.rom[offset + 1] = address as u8;
+    }
+
+    fn value(&self, token: &str) -> Option<i64> {
+        if let Some(value) = self.consts.get(token) {
+            return Some(*value);
+        }
+        let (negative, digits) = match token.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, token),
+        };
+        let value = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(bin) = digits.strip_prefix("0b") {
+            i64::from_str_radix(bin, 2).ok()
+        } else {
+            digits.parse::<i64>().ok()
+        }?;
+        return Some(if negative { -value } else { value });
+    }
+
+    fn byte_value(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        return match self.value(&token) {
+            Some(value) if (-128..=255).contains(&value) => Ok(value as u8),
+            Some(_) => Err(format!("'{}' does not fit in a byte", token)),
+            None => Err(format!("'{}' is not a number", token)),
+        };
+    }
+
+    fn register(&self, token: &str) -> Option<u8> {
+        if let Some(reg) = self.aliases.get(token) {
+            return Some(*reg);
+        }
+        let digit = token.strip_prefix('v').filter(|d| d.len() == 1)?;
+        return u8::from_str_radix(digit, 16).ok();
+    }
+
+    fn expect_register(&mut self) -> Result<u16, String> {
+        let token = self.next()?;
+        return self.register(&token).map(u16::from).ok_or(format!("'{}' is not a register", token));
+    }
+
+    // Instruction that skips the next one when the condition is false
+    fn condition(&mut self) -> Result<u16, String> {
+        let x = self.expect_register()? << 8;
+        let op = self.next()?;
+        match op.as_str() {
+            "key" => return Ok(0xE0A1 | x),
+            "-key" => return Ok(0xE09E | x),
+            "==" | "!=" => {}
+            _ => return Err(format!("'{}' is not a supported comparison", op)),
+        }
+        let rhs = self.next()?;
+        let equal = op == "==";
+        if let Some(y) = self.register(&rhs) {
+            return Ok(if equal { 0x9000 } else { 0x5000 } | x | (y as u16) << 4);
+        }
+        self.pos -= 1;
+        let value = self.byte_value()? as u16;
+        return Ok(if equal { 0x4000 } else { 0x3000 } | x | value);
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.next()?;
+                if !is_name(&name) || self.labels.insert(name.clone(), self.address()).is_some() {
+                    return Err(format!("label '{}' is invalid or defined twice", name));
+                }
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                let value = self.labels.get(&value).map(|a| *a as i64).or(self.value(&value))
+                    .ok_or(format!("'{}' is not a number", value))?;
+                self.consts.insert(name, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let reg = self.expect_register()?;
+                self.aliases.insert(name, reg as u8);
+            }
+            ":org" => {
+                let token = self.next()?;
+                match self.value(&token) {
+                    Some(address) if (PROGRAM_START as i64..MEMORY_SIZE as i64).contains(&address) => {
+                        self.here = (address - PROGRAM_START as i64) as usize;
+                    }
+                    _ => return Err(format!("'{}' is not an address in program memory", token)),
+                }
+            }
+            ":call" => {
+                let target = self.next()?;
+                self.instruction_to(0x2000, &target)?;
+            }
+            "jump" | "jump0" => {
+                let target = self.next()?;
+                self.instruction_to(if token == "jump" { 0x1000 } else { 0xB000 }, &target)?;
+            }
+            "return" | ";" => { self.instruction(0x00EE)?; }
+            "clear" => { self.instruction(0x00E0)?; }
+            "bcd" => { let x = self.expect_register()?; self.instruction(0xF033 | x << 8)?; }
+            "save" => { let x = self.expect_register()?; self.instruction(0xF055 | x << 8)?; }
+            "load" => { let x = self.expect_register()?; self.instruction(0xF065 | x << 8)?; }
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let height = self.byte_value()?;
+                if height > 15 {
+                    return Err(format!("sprite height {} is more than 15", height));
+                }
+                self.instruction(0xD000 | x << 8 | y << 4 | height as u16)?;
+            }
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.expect_register()?;
+                self.instruction(if token == "delay" { 0xF015 } else { 0xF018 } | x << 8)?;
+            }
+            "i" => {
+                let op = self.next()?;
+                match op.as_str() {
+                    ":=" => {
+                        let target = self.next()?;
+                        if target == "hex" {
+                            let x = self.expect_register()?;
+                            self.instruction(0xF029 | x << 8)?;
+                        } else {
+                            self.instruction_to(0xA000, &target)?;
+                        }
+                    }
+                    "+=" => { let x = self.expect_register()?; self.instruction(0xF01E | x << 8)?; }
+                    _ => return Err(format!("'i {}' is not supported", op)),
+                }
+            }
+            "if" => {
+                let skip = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => { self.instruction(skip)?; }
+                    "begin" => {
+                        self.instruction(inverse(skip))?;
+                        let jump = self.instruction(0x1000)?;
+                        self.flow.push(Flow::Begin(jump));
+                    }
+                    other => return Err(format!("expected 'then' or 'begin', found '{}'", other)),
+                }
+            }
+            "else" => {
+                let Some(Flow::Begin(jump)) = self.flow.pop() else {
+                    return Err("'else' without 'begin'".to_string());
+                };
+                let past_else = self.instruction(0x1000)?;
+                self.land(jump);
+                self.flow.push(Flow::Else(past_else));
+            }
+            "end" => match self.flow.pop() {
+                Some(Flow::Begin(jump)) | Some(Flow::Else(jump)) => self.land(jump),
+                _ => return Err("'end' without 'begin'".to_string()),
+            },
+            "loop" => self.flow.push(Flow::Loop(self.address(), Vec::new())),
+            "while" => {
+                let skip = self.condition()?;
+                self.instruction(inverse(skip))?;
+                let jump = self.instruction(0x1000)?;
+                match self.flow.iter_mut().rev().find_map(|f| if let Flow::Loop(_, exits) = f { Some(exits) } else { None }) {
+                    Some(exits) => exits.push(jump),
+                    None => return Err("'while' outside a loop".to_string()),
+                }
+            }
+            "again" => {
+                let Some(Flow::Loop(start, exits)) = self.flow.pop() else {
+                    return Err("'again' without 'loop'".to_string());
+                };
+                self.instruction(0x1000 | start)?;
+                for jump in exits {
+                    self.land(jump);
+                }
+            }
+            _ => {
+                if let Some(x) = self.register(&token) {
+                    return self.register_statement(x as u16);
+                }
+                if self.value(&token).is_some() {
+                    self.pos -= 1;
+                    let value = self.byte_value()?;
+                    return self.byte(value);
+                }
+                if !is_name(&token) {
+                    return Err(format!("'{}' is not supported", token));
+                }
+                // A bare label name calls it
+                self.instruction_to(0x2000, &token)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn register_statement(&mut self, x: u16) -> Result<(), String> {
+        let x = x << 8;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        if let Some(y) = self.register(&rhs) {
+            let y = (y as u16) << 4;
+            let ins = match op.as_str() {
+                ":=" => 0x8000,
+                "|=" => 0x8001,
+                "&=" => 0x8002,
+                "^=" => 0x8003,
+                "+=" => 0x8004,
+                "-=" => 0x8005,
+                ">>=" => 0x8006,
+                "=-" => 0x8007,
+                "<<=" => 0x800E,
+                _ => return Err(format!("'{}' is not supported between registers", op)),
+            };
+            self.instruction(ins | x | y)?;
+            return Ok(());
+        }
+        let ins = match (op.as_str(), rhs.as_str()) {
+            (":=", "delay") => 0xF007 | x,
+            (":=", "key") => 0xF00A | x,
+            (":=", "random") => 0xC000 | x | self.byte_value()? as u16,
+            (":=", _) | ("+=", _) | ("-=", _) => {
+                self.pos -= 1;
+                let value = self.byte_value()?;
+                match op.as_str() {
+                    ":=" => 0x6000 | x | value as u16,
+                    "+=" => 0x7000 | x | value as u16,
+                    _ => 0x7000 | x | value.wrapping_neg() as u16,
+                }
+            }
+            _ => return Err(format!("'{} {}' is not supported", op, rhs)),
+        };
+        self.instruction(ins)?;
+        return Ok(());
+    }
+}
+
+// Skip instruction for the opposite condition
+fn inverse(skip: u16) -> u16 {
+    return match skip >> 12 {
+        0x3 => skip & 0x0fff | 0x4000,
+        0x4 => skip & 0x0fff | 0x3000,
+        0x5 => skip & 0x0fff | 0x9000,
+        0x9 => skip & 0x0fff | 0x5000,
+        _ if skip & 0xff == 0xA1 => skip & 0xff00 | 0x9E,
+        _ => skip & 0xff00 | 0xA1,
+    };
+}
+
+fn is_name(token: &str) -> bool {
+    return token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        let source = "
+            :alias x v1
+            :const SPEED 3
+            : main
+                clear
+                x := SPEED  v2 := 0xff  x += 1  x -= 1
+                x := v2  x |= v2  x &= v2  x ^= v2  x += v2  x -= v2  x >>= v2  x =- v2  x <<= v2
+                i := sprite  i := hex x  i += x
+                v3 := random 0x0f  v3 := delay  v3 := key
+                delay := v3  buzzer := v3
+                sprite x v2 5
+                bcd v3  save v3  load v3
+                draw  jump main  jump0 main  :call draw
+            : draw
+                if v0 == 1 then return
+                if v0 != v1 then ;
+                if v0 key then return
+                if v0 -key then return
+            : sprite 0xF0 0x90 -1
+        ";
+        let words: Vec<u16> = [
+            0x00E0, 0x6103, 0x62FF, 0x7101, 0x71FF,
+            0x8120, 0x8121, 0x8122, 0x8123, 0x8124, 0x8125, 0x8126, 0x8127, 0x812E,
+            0xA24C, 0xF129, 0xF11E,
+            0xC30F, 0xF307, 0xF30A,
+            0xF315, 0xF318,
+            0xD125,
+            0xF333, 0xF355, 0xF365,
+            0x223C, 0x1200, 0xB200, 0x223C,
+            0x4001, 0x00EE, 0x5010, 0x00EE, 0xE0A1, 0x00EE, 0xE09E, 0x00EE,
+        ].to_vec();
+        let mut expected: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        expected.extend([0xF0, 0x90, 0xFF]);
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn blocks_and_loops() {
+        let source = "
+            : main
+            loop
+                if v0 == 2 begin v1 := 1 else v1 := 2 end
+                while v2 != 5
+                v2 += 1
+            again
+        ";
+        let words: [u16; 9] = [
+            0x3002, 0x1208, 0x6101, 0x120A, 0x6102, // if begin else end
+            0x4205, 0x1212, // while
+            0x7201, 0x1200, // again
+        ];
+        let expected: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn jumps_to_main_when_not_first() {
+        let source = ": data 1 2\n: main jump main\n";
+        assert_eq!(compile(source).unwrap(), vec![0x12, 0x04, 1, 2, 0x12, 0x04]);
+        assert_eq!(compile("1 2 : other").unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn errors() {
+        let err = compile(": main\n  v0 := 1\n  v0 := 256\n").unwrap_err();
+        assert!(err.contains("line 3: '256' does not fit in a byte"), "{}", err);
+        assert!(compile(": main jump nowhere").unwrap_err().contains("unknown label 'nowhere'"));
+        assert!(compile(": main loop").unwrap_err().contains("without 'again'"));
+        assert!(compile(": main :macro m { }").unwrap_err().contains("':macro' is not supported"));
+        assert!(compile(": main else").is_err());
+        assert!(compile(": main : main").is_err());
+    }
+}
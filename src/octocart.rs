@@ -0,0 +1,241 @@
+use crate::cpu::{Quirks, CLOCK_SPEED, TIMER_RATE};
+use crate::database::RomInfo;
+use crate::gif;
+use crate::json::Json;
+use crate::octo;
+
+// Octo cartridges: GIF images carrying a program and its Octo settings.
+//
+// The payload is a 32 bit big endian length followed by UTF-8 JSON
+//
+//   {"program": "<Octo source>", "options": {"tickrate": 20, ...}}
+//
+// stored in the low nibble of the pixel indexes, two pixels per byte with the
+// high nibble first, continuing through all frames. The high nibble of each
+// index draws the label, so the palette repeats 16 visible colours.
+//
+// The program is Octo source, compiled by octo.rs. Carts using Octo features
+// it does not support are rejected.
+
+const LABEL_WIDTH: usize = 128;
+const LABEL_HEIGHT: usize = 64; // Minimum, grows with the payload
+
+// Settings carried in a cartridge
+pub struct Cart {
+    pub rom: Vec<u8>,
+    pub tick_rate: Option<u64>,
+    pub quirks: Quirks,
+    pub colors: Vec<String>, // Background, plane 1, plane 2, both planes
+    pub keys: Vec<(String, u8)>, // Same names as the ROM database
+}
+
+// Octo option names for the display colours, in theme order
+const COLOR_OPTIONS: [&str; 4] = ["backgroundColor", "fillColor", "fillColor2", "blendColor"];
+
+// Quick check before trying to decode a file as a cartridge
+pub fn is_cart(data: &[u8]) -> bool {
+    return data.starts_with(b"GIF8");
+}
+
+pub fn read(data: &[u8]) -> Result<Cart, String> {
+    let image = gif::decode(data)?;
+    let mut bytes = Vec::new();
+    for frame in &image.frames {
+        for pair in frame.pixels.chunks(2) {
+            if pair.len() == 2 {
+                bytes.push((pair[0] & 0x0f) << 4 | (pair[1] & 0x0f));
+            }
+        }
+    }
+    if bytes.len() < 4 {
+        return Err("Image holds no cartridge data".to_string());
+    }
+    let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let payload = bytes.get(4..4 + len).ok_or("Cartridge data is truncated, not an octocart?")?;
+    let text = std::str::from_utf8(payload).map_err(|_| "Cartridge data is not UTF-8".to_string())?;
+    let json = Json::parse(text).map_err(|e| format!("Cartridge data: {}", e))?;
+
+    let source = json.get("program").and_then(Json::as_str).ok_or("Cartridge has no program")?;
+    let rom = octo::compile(source)?;
+    if rom.is_empty() {
+        return Err("Cartridge program is empty".to_string());
+    }
+    let options = json.get("options");
+    let option = |name: &str| options.and_then(|o| o.get(name));
+    let flag = |name: &str, default: bool| option(name).and_then(Json::as_bool).unwrap_or(default);
+
+    let base = Quirks::default();
+    let quirks = Quirks {
+        shift: flag("shiftQuirks", base.shift),
+        // Not an Octo option, named like the ROM database quirk
+        memory_increment_by_x: flag("memoryIncrementByX", base.memory_increment_by_x),
+        memory_leave_i_unchanged: flag("loadStoreQuirks", base.memory_leave_i_unchanged),
+        wrap: !flag("clipQuirks", !base.wrap),
+        jump: flag("jumpQuirks", base.jump),
+        vblank: flag("vBlankQuirks", base.vblank),
+        logic: flag("logicQuirks", base.logic),
+    };
+    let colors = COLOR_OPTIONS.iter().map_while(|name| option(name).and_then(Json::as_str).map(str::to_string)).collect();
+    let keys = option("keys").map(|k| k.as_object().iter()
+        .filter_map(|(name, key)| Some((name.clone(), key.as_f64()? as u8)))
+        .filter(|(_, key)| *key < 16)
+        .collect()
+    ).unwrap_or_default();
+    return Ok(Cart {
+        rom,
+        tick_rate: option("tickrate").and_then(Json::as_f64).map(|t| (t as u64).max(1)),
+        quirks,
+        colors,
+        keys,
+    });
+}
+
+fn byte_listing(rom: &[u8]) -> String {
+    let mut source = String::from("# ROM bytes\n: main\n");
+    for line in rom.chunks(16) {
+        let hex: Vec<String> = line.iter().map(|b| format!("0x{:02X}", b)).collect();
+        source.push_str(&hex.join(" "));
+        source.push('\n');
+    }
+    return source;
+}
+
+// Cartridge GIF for a ROM, display colours are used for the label and the
+// background and fill colours the cartridge does not set
+pub fn write(cart: &Cart, display: &[(u8, u8, u8); 2]) -> Vec<u8> {
+    let hex = |(r, g, b): (u8, u8, u8)| format!("#{:02X}{:02X}{:02X}", r, g, b);
+    let mut options = vec![
+        ("tickrate".to_string(), Json::Number(cart.tick_rate.unwrap_or(CLOCK_SPEED / TIMER_RATE) as f64)),
+    ];
+    for (idx, name) in COLOR_OPTIONS.iter().enumerate() {
+        let color = cart.colors.get(idx).cloned().or(display.get(idx).map(|rgb| hex(*rgb)));
+        if let Some(color) = color {
+            options.push((name.to_string(), Json::String(color)));
+        }
+    }
+    let flags = [
+        ("buzzColor", Json::String("#FFAA00".to_string())),
+        ("quietColor", Json::String("#000000".to_string())),
+        ("shiftQuirks", Json::Bool(cart.quirks.shift)),
+        ("loadStoreQuirks", Json::Bool(cart.quirks.memory_leave_i_unchanged)),
+        ("memoryIncrementByX", Json::Bool(cart.quirks.memory_increment_by_x)),
+        ("vfOrderQuirks", Json::Bool(false)),
+        ("clipQuirks", Json::Bool(!cart.quirks.wrap)),
+        ("vBlankQuirks", Json::Bool(cart.quirks.vblank)),
+        ("jumpQuirks", Json::Bool(cart.quirks.jump)),
+        ("logicQuirks", Json::Bool(cart.quirks.logic)),
+        ("screenRotation", Json::Number(0.0)),
+        ("maxSize", Json::Number(3584.0)),
+        ("touchInputMode", Json::String("none".to_string())),
+        ("fontStyle", Json::String("octo".to_string())),
+    ];
+    options.extend(flags.into_iter().map(|(name, value)| (name.to_string(), value)));
+    if !cart.keys.is_empty() {
+        let keys = cart.keys.iter().map(|(name, key)| (name.clone(), Json::Number(*key as f64))).collect();
+        options.push(("keys".to_string(), Json::Object(keys)));
+    }
+    let json = Json::Object(vec![
+        ("program".to_string(), Json::String(byte_listing(&cart.rom))),
+        ("options".to_string(), Json::Object(options)),
+    ]);
+
+    let text = json.to_string();
+    let mut payload = (text.len() as u32).to_be_bytes().to_vec();
+    payload.extend_from_slice(text.as_bytes());
+
+    // Label: display background with a frame in the fill colour
+    let height = (payload.len() * 2).div_ceil(LABEL_WIDTH).max(LABEL_HEIGHT);
+    let mut pixels = vec![0u8; LABEL_WIDTH * height];
+    for y in 0..height {
+        for x in 0..LABEL_WIDTH {
+            let border = x < 2 || y < 2 || x >= LABEL_WIDTH - 2 || y >= height - 2;
+            let band = (8..12).contains(&y) && (8..LABEL_WIDTH - 8).contains(&x);
+            pixels[y * LABEL_WIDTH + x] = ((border || band) as u8) << 4;
+        }
+    }
+    for (idx, byte) in payload.iter().enumerate() {
+        pixels[idx * 2] |= byte >> 4;
+        pixels[idx * 2 + 1] |= byte & 0x0f;
+    }
+    let palette: Vec<(u8, u8, u8)> = (0..256).map(|idx| display[(idx >> 4).min(1)]).collect();
+    return gif::encode(LABEL_WIDTH, height, &pixels, &palette);
+}
+
+impl Cart {
+    // Cartridge settings in the form database entries are applied
+    pub fn rom_info(&self, title: &str) -> RomInfo {
+        return RomInfo {
+            title: title.to_string(),
+            authors: Vec::new(),
+            release: None,
+            platform: None,
+            quirks: self.quirks,
+            tick_rate: self.tick_rate.unwrap_or(CLOCK_SPEED / TIMER_RATE),
+            start_address: None,
+            keys: self.keys.clone(),
+            colors: self.colors.clone(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISPLAY: [(u8, u8, u8); 2] = [(0, 0, 0), (255, 255, 255)];
+
+    #[test]
+    fn round_trip() {
+        let cart = Cart {
+            rom: (0..=255).chain(0..100).collect(),
+            tick_rate: Some(30),
+            quirks: Quirks { shift: true, jump: true, wrap: false, vblank: true, memory_increment_by_x: true, ..Quirks::default() },
+            colors: vec!["#112233".to_string(), "#445566".to_string()],
+            keys: vec![("up".to_string(), 5), ("a".to_string(), 6)],
+        };
+        let data = write(&cart, &DISPLAY);
+        assert!(is_cart(&data));
+        let read = read(&data).unwrap();
+        assert_eq!(read.rom, cart.rom);
+        assert_eq!(read.tick_rate, Some(30));
+        assert_eq!(read.quirks, cart.quirks);
+        assert_eq!(read.colors, vec!["#112233", "#445566"]);
+        assert_eq!(read.keys, cart.keys);
+    }
+
+    #[test]
+    fn byte_listing_source() {
+        let source = "# sprite\n: main\n0x12 0b101 255 -1\n: data 7 # end\n";
+        assert_eq!(octo::compile(source).unwrap(), vec![0x12, 0x05, 0xff, 0xff, 0x07]);
+        assert_eq!(octo::compile(&byte_listing(&[1, 2, 0xab])).unwrap(), vec![1, 2, 0xab]);
+    }
+
+    #[test]
+    fn assembly_cart() {
+        // Cart as saved by Octo, with Octo's own option names
+        let text = r#"{"program": ": main\n  v0 := 1\n  loop again\n", "options": {"tickrate": 7, "loadStoreQuirks": false}}"#;
+        let mut payload = (text.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(text.as_bytes());
+        let pixels: Vec<u8> = payload.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect();
+        let read = read(&gif::encode(pixels.len(), 1, &pixels, &[])).unwrap();
+        assert_eq!(read.rom, vec![0x60, 0x01, 0x12, 0x02]);
+        assert_eq!(read.tick_rate, Some(7));
+        assert!(!read.quirks.memory_leave_i_unchanged);
+        assert!(!read.quirks.memory_increment_by_x);
+    }
+
+    #[test]
+    fn unsupported_source_rejected() {
+        let data = write(&Cart { rom: vec![1], tick_rate: None, quirks: Quirks::default(), colors: Vec::new(), keys: Vec::new() }, &DISPLAY);
+        assert!(read(&data).is_ok());
+        let err = octo::compile(": main\n  :macro twice { }\n").unwrap_err();
+        assert!(err.contains("line 2: ':macro' is not supported"), "{}", err);
+        assert!(octo::compile("# nothing\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn plain_gif_is_not_a_cart() {
+        let data = gif::encode(2, 2, &[0, 0, 0, 0], &[]);
+        assert!(read(&data).is_err());
+    }
+}
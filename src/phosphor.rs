@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+
+// Persistence filter against XOR flicker. Games erase and redraw sprites every
+// frame, so a plain snapshot often catches them erased.
+//
+//   hold:N     pixel stays lit while it was lit in any of the last N frames
+//   decay:F    lit pixels fade, keeping F of their brightness per frame
+//
+// Output is a brightness level per pixel from 0.0 to 1.0.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Persistence {
+    Off,
+    Hold(usize),
+    Decay(f32),
+}
+
+// Levels below this are shown as off
+const CUTOFF: f32 = 0.05;
+
+impl Persistence {
+    // "off", "hold", "hold:<frames>", "decay" or "decay:<factor>"
+    pub fn parse(spec: &str) -> Result<Persistence, String> {
+        let (name, value) = match spec.split_once(':') {
+            Some((name, value)) => (name, Some(value.trim())),
+            None => (spec, None),
+        };
+        match (name.trim().to_lowercase().as_str(), value) {
+            ("off", None) => Ok(Persistence::Off),
+            ("hold", None) => Ok(Persistence::Hold(2)),
+            ("hold", Some(frames)) => match frames.parse::<usize>() {
+                Ok(frames) if (1..=60).contains(&frames) => Ok(Persistence::Hold(frames)),
+                _ => Err(format!("Invalid hold frames '{}', expected 1-60", frames)),
+            },
+            ("decay", None) => Ok(Persistence::Decay(0.5)),
+            ("decay", Some(factor)) => match factor.parse::<f32>() {
+                Ok(factor) if (0.0..1.0).contains(&factor) => Ok(Persistence::Decay(factor)),
+                _ => Err(format!("Invalid decay factor '{}', expected 0.0-0.99", factor)),
+            },
+            _ => Err(format!("Invalid persistence '{}', expected off, hold[:frames] or decay[:factor]", spec)),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Persistence::Off => "off".to_string(),
+            Persistence::Hold(frames) => format!("hold:{}", frames),
+            Persistence::Decay(factor) => format!("decay:{}", factor),
+        }
+    }
+}
+
+pub struct Phosphor {
+    mode: Persistence,
+    history: VecDeque<Vec<bool>>, // Last frames for hold, newest first
+    levels: Vec<f32>,
+}
+
+impl Phosphor {
+    pub fn new(mode: Persistence) -> Phosphor {
+        Phosphor { mode, history: VecDeque::new(), levels: Vec::new() }
+    }
+
+    pub fn mode(&self) -> Persistence {
+        return self.mode;
+    }
+
+    // Add one displayed frame
+    pub fn update(&mut self, pixels: &[bool]) {
+        if self.mode == Persistence::Off {
+            return;
+        }
+        if self.levels.len() != pixels.len() {
+            self.history.clear();
+            self.levels = vec![0.0; pixels.len()];
+        }
+        match self.mode {
+            Persistence::Off => {}
+            Persistence::Hold(frames) => {
+                self.history.push_front(pixels.to_vec());
+                self.history.truncate(frames);
+                for (idx, level) in self.levels.iter_mut().enumerate() {
+                    *level = self.history.iter().any(|frame| frame[idx]) as u8 as f32;
+                }
+            }
+            Persistence::Decay(factor) => {
+                for (level, lit) in self.levels.iter_mut().zip(pixels) {
+                    *level = if *lit { 1.0 } else { *level * factor };
+                    if *level < CUTOFF {
+                        *level = 0.0;
+                    }
+                }
+            }
+        }
+    }
+
+    // Filtered levels, pixels as they are when off or before the first update
+    pub fn levels(&self, pixels: &[bool]) -> Vec<f32> {
+        if self.mode == Persistence::Off || self.levels.len() != pixels.len() {
+            return pixels.iter().map(|lit| *lit as u8 as f32).collect();
+        }
+        return self.levels.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Persistence::parse("off"), Ok(Persistence::Off));
+        assert_eq!(Persistence::parse("hold"), Ok(Persistence::Hold(2)));
+        assert_eq!(Persistence::parse("Hold:3"), Ok(Persistence::Hold(3)));
+        assert_eq!(Persistence::parse("decay:0.25"), Ok(Persistence::Decay(0.25)));
+        assert!(Persistence::parse("hold:0").is_err());
+        assert!(Persistence::parse("decay:1").is_err());
+        assert!(Persistence::parse("blur").is_err());
+        assert_eq!(Persistence::parse(&Persistence::Decay(0.5).name()), Ok(Persistence::Decay(0.5)));
+    }
+
+    #[test]
+    fn hold_keeps_pixels_for_n_frames() {
+        let mut phosphor = Phosphor::new(Persistence::Hold(3));
+        assert_eq!(phosphor.levels(&[true, false]), vec![1.0, 0.0]);
+        phosphor.update(&[true, false]);
+        phosphor.update(&[false, true]);
+        phosphor.update(&[false, false]);
+        assert_eq!(phosphor.levels(&[false, false]), vec![1.0, 1.0]);
+        phosphor.update(&[false, false]);
+        assert_eq!(phosphor.levels(&[false, false]), vec![0.0, 1.0]);
+        phosphor.update(&[false, false]);
+        assert_eq!(phosphor.levels(&[false, false]), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn decay_fades_to_off() {
+        let mut phosphor = Phosphor::new(Persistence::Decay(0.5));
+        phosphor.update(&[true]);
+        assert_eq!(phosphor.levels(&[true]), vec![1.0]);
+        let levels: Vec<f32> = (0..5).map(|_| {
+            phosphor.update(&[false]);
+            phosphor.levels(&[false])[0]
+        }).collect();
+        // 0.03125 is under the cutoff
+        assert_eq!(levels, vec![0.5, 0.25, 0.125, 0.0625, 0.0]);
+        phosphor.update(&[true]);
+        assert_eq!(phosphor.levels(&[true]), vec![1.0]);
+    }
+
+    #[test]
+    fn off_passes_pixels_through() {
+        let mut phosphor = Phosphor::new(Persistence::Off);
+        phosphor.update(&[true, false]);
+        assert_eq!(phosphor.levels(&[false, true]), vec![0.0, 1.0]);
+    }
+}
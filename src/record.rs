@@ -0,0 +1,84 @@
+use crate::gif;
+
+// Display recordings as looping GIFs. Frames are persistence filtered
+// brightness levels, so a recording looks like the screen did.
+
+const SCALE: usize = 4; // Image pixels per display pixel
+const SHADES: usize = 16; // Palette steps from background to fill colour
+
+pub struct Recorder {
+    width: usize,
+    height: usize,
+    palette: Vec<(u8, u8, u8)>,
+    frames: Vec<(Vec<u8>, u16)>, // Palette indexes and delay in 1/100 s
+    count: usize, // 60 Hz frames added
+}
+
+impl Recorder {
+    pub fn new(width: usize, height: usize, background: (u8, u8, u8), fill: (u8, u8, u8)) -> Recorder {
+        let palette = (0..SHADES).map(|step| {
+            let blend = |from: u8, to: u8| ((from as usize * (SHADES - 1 - step) + to as usize * step) / (SHADES - 1)) as u8;
+            (blend(background.0, fill.0), blend(background.1, fill.1), blend(background.2, fill.2))
+        }).collect();
+        Recorder { width, height, palette, frames: Vec::new(), count: 0 }
+    }
+
+    // Add one 60 Hz frame of levels from 0.0 to 1.0, row by row
+    pub fn add(&mut self, levels: &[f32]) {
+        let mut pixels = vec![0u8; self.width * SCALE * self.height * SCALE];
+        for (idx, level) in levels.iter().enumerate().take(self.width * self.height) {
+            let shade = (level.clamp(0.0, 1.0) * (SHADES - 1) as f32).round() as u8;
+            let (x, y) = (idx % self.width * SCALE, idx / self.width * SCALE);
+            for row in y..y + SCALE {
+                let start = row * self.width * SCALE + x;
+                pixels[start..start + SCALE].fill(shade);
+            }
+        }
+        // GIF delays are in 1/100 s, so 60 Hz frames get 1 or 2
+        let delay = ((self.count + 1) * 100 / 60 - self.count * 100 / 60) as u16;
+        self.count += 1;
+        match self.frames.last_mut() {
+            Some((last, last_delay)) if *last == pixels => *last_delay += delay,
+            _ => self.frames.push((pixels, delay)),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        return gif::encode_frames(self.width * SCALE, self.height * SCALE, &self.frames, &self.palette);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_and_timing() {
+        let mut recorder = Recorder::new(2, 1, (0, 0, 0), (255, 255, 255));
+        // Same frame repeated is one image shown longer
+        for _ in 0..3 {
+            recorder.add(&[1.0, 0.0]);
+        }
+        recorder.add(&[0.5, 0.0]);
+        let delays: Vec<u16> = recorder.frames.iter().map(|f| f.1).collect();
+        assert_eq!(delays, vec![5, 1]);
+        assert_eq!(recorder.palette[15], (255, 255, 255));
+        assert_eq!(recorder.palette[0], (0, 0, 0));
+
+        let image = gif::decode(&recorder.encode()).unwrap();
+        assert_eq!(image.frames.len(), 2);
+        let first = &image.frames[0].pixels;
+        assert_eq!(first.len(), 2 * SCALE * SCALE);
+        assert_eq!(&first[..2 * SCALE], &[[15u8; SCALE], [0u8; SCALE]].concat()[..]);
+        assert_eq!(image.frames[1].pixels[0], 8); // Half brightness
+    }
+
+    #[test]
+    fn sixty_frames_last_a_second() {
+        let mut recorder = Recorder::new(1, 1, (0, 0, 0), (255, 255, 255));
+        for idx in 0..60 {
+            recorder.add(&[(idx % 2) as f32]);
+        }
+        assert_eq!(recorder.frames.iter().map(|f| f.1 as u32).sum::<u32>(), 100);
+    }
+}
@@ -0,0 +1,150 @@
+// Rasterize the CHIP-8 framebuffer into terminal character cells.
+//
+// Each mode packs a grid of sub pixels into one cell:
+//   block      1x1, pixels are two cells wide to stay square
+//   halfblock  1x2 using upper and lower half blocks
+//   braille    2x4 dots per cell
+// Terminal cells are about twice as tall as wide, so with these the picture
+// keeps its aspect ratio. It is scaled by an integer factor when it fits and
+// centered in the area with empty borders.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RenderMode {
+    Stretch, // Point sampled to fill the whole area
+    Block,
+    HalfBlock,
+    Braille,
+}
+
+pub const RENDER_MODES: [&str; 4] = ["stretch", "block", "halfblock", "braille"];
+
+impl RenderMode {
+    pub fn parse(name: &str) -> Option<RenderMode> {
+        match name.to_lowercase().as_str() {
+            "stretch" => Some(RenderMode::Stretch),
+            "block" => Some(RenderMode::Block),
+            "halfblock" => Some(RenderMode::HalfBlock),
+            "braille" => Some(RenderMode::Braille),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderMode::Stretch => "stretch",
+            RenderMode::Block => "block",
+            RenderMode::HalfBlock => "halfblock",
+            RenderMode::Braille => "braille",
+        }
+    }
+
+    pub fn next(&self) -> RenderMode {
+        match self {
+            RenderMode::Stretch => RenderMode::Block,
+            RenderMode::Block => RenderMode::HalfBlock,
+            RenderMode::HalfBlock => RenderMode::Braille,
+            RenderMode::Braille => RenderMode::Stretch,
+        }
+    }
+
+    // Sub pixels per cell
+    fn cell_size(&self) -> (usize, usize) {
+        match self {
+            RenderMode::Stretch | RenderMode::Block => (1, 1),
+            RenderMode::HalfBlock => (1, 2),
+            RenderMode::Braille => (2, 4),
+        }
+    }
+
+    // Sub pixels per source pixel at scale 1
+    fn pixel_aspect(&self) -> (f32, f32) {
+        match self {
+            RenderMode::Block => (2.0, 1.0),
+            _ => (1.0, 1.0),
+        }
+    }
+}
+
+// Character cell with pixel brightness of foreground and background, 0.0 is off
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cell {
+    pub ch: char,
+    pub fg: f32,
+    pub bg: f32,
+}
+
+const EMPTY: Cell = Cell { ch: ' ', fg: 0.0, bg: 0.0 };
+
+// Braille dot bits by sub pixel position (x, y)
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+// Render pixel levels of width x height into cols x rows cells
+pub fn render(pixels: &[f32], width: usize, height: usize, mode: RenderMode, cols: usize, rows: usize) -> Vec<Vec<Cell>> {
+    let (cw, ch) = mode.cell_size();
+    let (grid_w, grid_h) = (cols * cw, rows * ch);
+    let (ax, ay) = mode.pixel_aspect();
+
+    // Sub pixel size of one source pixel
+    let (scale_x, scale_y, off_x, off_y) = match mode {
+        RenderMode::Stretch => (grid_w as f32 / width as f32, grid_h as f32 / height as f32, 0, 0),
+        _ => {
+            let scale = (grid_w as f32 / (width as f32 * ax)).min(grid_h as f32 / (height as f32 * ay));
+            let scale = if scale >= 1.0 { scale.floor() } else { scale };
+            let (image_w, image_h) = ((width as f32 * ax * scale) as usize, (height as f32 * ay * scale) as usize);
+            // Letterbox on whole cells
+            let off_x = (grid_w - image_w.min(grid_w)) / 2 / cw * cw;
+            let off_y = (grid_h - image_h.min(grid_h)) / 2 / ch * ch;
+            (ax * scale, ay * scale, off_x, off_y)
+        }
+    };
+
+    let level = |gx: usize, gy: usize| -> f32 {
+        if gx < off_x || gy < off_y {
+            return 0.0;
+        }
+        let x = ((gx - off_x) as f32 / scale_x) as usize;
+        let y = ((gy - off_y) as f32 / scale_y) as usize;
+        if x >= width || y >= height {
+            return 0.0;
+        }
+        return pixels[x + y * width];
+    };
+
+    let mut lines = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let mut line = Vec::with_capacity(cols);
+        for col in 0..cols {
+            let (gx, gy) = (col * cw, row * ch);
+            let cell = match mode {
+                RenderMode::Stretch | RenderMode::Block => Cell { ch: ' ', fg: 0.0, bg: level(gx, gy) },
+                // Only lit halves use the foreground, off colour is the terminal background
+                RenderMode::HalfBlock => match (level(gx, gy), level(gx, gy + 1)) {
+                    (0.0, 0.0) => EMPTY,
+                    (0.0, bottom) => Cell { ch: '▄', fg: bottom, bg: 0.0 },
+                    (top, bottom) => Cell { ch: '▀', fg: top, bg: bottom },
+                },
+                // Dots share one colour, the brightest of them
+                RenderMode::Braille => {
+                    let mut bits = 0;
+                    let mut brightest: f32 = 0.0;
+                    for (dy, dots) in BRAILLE_DOTS.iter().enumerate() {
+                        for (dx, dot) in dots.iter().enumerate() {
+                            let dot_level = level(gx + dx, gy + dy);
+                            if dot_level > 0.0 {
+                                bits |= dot;
+                                brightest = brightest.max(dot_level);
+                            }
+                        }
+                    }
+                    match bits {
+                        0 => EMPTY,
+                        _ => Cell { ch: char::from_u32(0x2800 + bits).unwrap(), fg: brightest, bg: 0.0 },
+                    }
+                }
+            };
+            line.push(cell);
+        }
+        lines.push(line);
+    }
+    return lines;
+}
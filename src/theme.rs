@@ -0,0 +1,364 @@
+use crate::toml::{self, Value};
+use std::env;
+use std::fs;
+use tui::style::Color;
+
+// Colour themes for the display and the debugger panels.
+//
+// A theme file is TOML, optionally under a [theme] header, and starts from a
+// built-in theme:
+//
+//   base = "amber"
+//   focus = "#ffd700"
+//   color1 = "yellow"
+//
+// Loading settings turns it into the base theme name and a [theme] section
+// with its colours, so the merged settings show them.
+//
+// Colours are "#rrggbb", an ANSI colour name or "default" for the terminal's
+// own colour. RGB values are reduced to what the terminal can show.
+
+// Theme colour before it is fitted to the terminal
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Shade {
+    Default,
+    Named(u8), // ANSI colour 0-15, uses the terminal palette
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+    Mono,
+}
+
+pub const THEMES: [&str; 5] = ["default", "green", "amber", "xochip", "mono"];
+pub const COLOR_DEPTHS: [&str; 5] = ["auto", "truecolor", "256", "16", "mono"];
+
+const ANSI_NAMES: [&str; 16] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "gray",
+    "darkgray", "lightred", "lightgreen", "lightyellow", "lightblue", "lightmagenta", "lightcyan", "white",
+];
+
+// Typical xterm values, used to pick the nearest ANSI colour
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0), (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0), (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+pub const THEME_KEYS: [&str; 13] = [
+    "text", "background", "header", "contrast", "focus", "cursor", "highlight", "breakpoint", "watchpoint", "label",
+    "muted", "color0", "color1",
+];
+
+// Theme as written, one shade per key in THEME_KEYS order
+#[derive(Clone, PartialEq, Debug)]
+pub struct ThemeSpec {
+    shades: [Shade; 13],
+}
+
+// Theme fitted to the terminal
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub depth: ColorDepth,
+    pub text: Color,
+    pub background: Color,
+    pub header: Color, // Table headers and current instruction
+    pub contrast: Color, // Text on coloured backgrounds
+    pub focus: Color, // Focused panel border, prompts and edits
+    pub cursor: Color,
+    pub highlight: Color, // I register and stepping target
+    pub breakpoint: Color,
+    pub watchpoint: Color,
+    pub label: Color,
+    pub muted: Color,
+    pub display: [Color; 2], // Off and on pixel colours
+    pub display_rgb: [(u8, u8, u8); 2], // Same for image output
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        ThemeSpec::builtin("default").unwrap().resolve(ColorDepth::Ansi16)
+    }
+}
+
+// Colour support from environment, NO_COLOR and COLORTERM conventions
+pub fn detect_depth() -> ColorDepth {
+    let term = env::var("TERM").unwrap_or_default();
+    let colorterm = env::var("COLORTERM").unwrap_or_default().to_lowercase();
+    if env::var("NO_COLOR").map(|v| !v.is_empty()).unwrap_or(false) || term == "dumb" {
+        return ColorDepth::Mono;
+    }
+    if colorterm == "truecolor" || colorterm == "24bit" {
+        return ColorDepth::TrueColor;
+    }
+    if term.contains("256color") {
+        return ColorDepth::Ansi256;
+    }
+    return ColorDepth::Ansi16;
+}
+
+pub fn parse_depth(name: &str) -> Result<ColorDepth, String> {
+    match name.to_lowercase().as_str() {
+        "auto" => Ok(detect_depth()),
+        "truecolor" | "24bit" => Ok(ColorDepth::TrueColor),
+        "256" => Ok(ColorDepth::Ansi256),
+        "16" => Ok(ColorDepth::Ansi16),
+        "mono" => Ok(ColorDepth::Mono),
+        _ => Err(format!("Unknown colour depth '{}', expected one of: {}", name, COLOR_DEPTHS.join(" "))),
+    }
+}
+
+pub fn parse_shade(value: &str) -> Result<Shade, String> {
+    let value = value.trim().trim_matches('"').to_lowercase();
+    if value == "default" || value == "reset" {
+        return Ok(Shade::Default);
+    }
+    if let Some(idx) = ANSI_NAMES.iter().position(|name| *name == value) {
+        return Ok(Shade::Named(idx as u8));
+    }
+    let hex = value.strip_prefix('#').filter(|h| h.len() == 6).ok_or(format!("Invalid colour '{}'", value))?;
+    let rgb = u32::from_str_radix(hex, 16).map_err(|_| format!("Invalid colour '{}'", value))?;
+    return Ok(Shade::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+}
+
+impl ThemeSpec {
+    pub fn builtin(name: &str) -> Option<ThemeSpec> {
+        use Shade::*;
+        // Panel colours of the default theme, display colours follow
+        let panels = [Named(15), Default, Named(4), Named(0), Named(3), Named(15), Named(2), Named(1), Named(5), Named(6), Named(8)];
+        let (panels, display) = match name.to_lowercase().as_str() {
+            "default" => (panels, [Default, Named(15)]),
+            "green" => (
+                [Rgb(51, 255, 51), Rgb(8, 20, 8), Rgb(25, 128, 25), Rgb(8, 20, 8), Rgb(170, 255, 170), Rgb(51, 255, 51),
+                 Rgb(120, 255, 120), Rgb(255, 80, 80), Rgb(200, 255, 120), Rgb(100, 220, 160), Rgb(30, 100, 30)],
+                [Rgb(8, 20, 8), Rgb(51, 255, 51)],
+            ),
+            "amber" => (
+                [Rgb(255, 176, 0), Rgb(26, 16, 0), Rgb(150, 100, 0), Rgb(26, 16, 0), Rgb(255, 220, 120), Rgb(255, 176, 0),
+                 Rgb(255, 204, 80), Rgb(255, 80, 40), Rgb(255, 130, 60), Rgb(230, 190, 110), Rgb(110, 70, 0)],
+                [Rgb(26, 16, 0), Rgb(255, 176, 0)],
+            ),
+            // Octo's default XO-CHIP colours
+            "xochip" => (panels, [Rgb(0x99, 0x66, 0x00), Rgb(0xff, 0xcc, 0x00)]),
+            "mono" => (
+                [Default, Default, Named(15), Named(0), Named(15), Named(15), Named(15), Named(15), Named(15), Default, Default],
+                [Default, Named(15)],
+            ),
+            _ => return None,
+        };
+        let mut shades = [Default; 13];
+        shades[..11].copy_from_slice(&panels);
+        shades[11..].copy_from_slice(&display);
+        return Some(ThemeSpec { shades });
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let idx = THEME_KEYS.iter().position(|k| *k == key)
+            .ok_or(format!("Unknown theme key '{}', expected one of: {}", key, THEME_KEYS.join(" ")))?;
+        self.shades[idx] = parse_shade(value)?;
+        return Ok(());
+    }
+
+    pub fn resolve(&self, depth: ColorDepth) -> Theme {
+        // Monochrome terminals get the mono panels, display stays readable by brightness
+        let spec = match depth {
+            ColorDepth::Mono => ThemeSpec::builtin("mono").unwrap(),
+            _ => self.clone(),
+        };
+        let c = |idx: usize| fit_color(spec.shades[idx], depth);
+        let display_shades = &self.shades[11..];
+        let mut display = [Color::Reset; 2];
+        let mut display_rgb = [(0, 0, 0); 2];
+        for (idx, shade) in display_shades.iter().enumerate() {
+            // Off pixels on the terminal background, lit ones must differ from it
+            display_rgb[idx] = shade_rgb(*shade, if idx == 0 { (0, 0, 0) } else { (255, 255, 255) });
+            display[idx] = match depth {
+                ColorDepth::Mono if idx == 0 => Color::Reset,
+                ColorDepth::Mono => Color::White,
+                _ => fit_color(*shade, depth),
+            };
+        }
+        return Theme {
+            depth,
+            text: c(0),
+            background: c(1),
+            header: c(2),
+            contrast: c(3),
+            focus: c(4),
+            cursor: c(5),
+            highlight: c(6),
+            breakpoint: c(7),
+            watchpoint: c(8),
+            label: c(9),
+            muted: c(10),
+            display,
+            display_rgb,
+        };
+    }
+}
+
+// Theme file as its base theme name and colours
+pub fn load_file(path: &str) -> Result<(String, Vec<(String, String)>), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    return parse_file(&text).map_err(|e| format!("{}: {}", path, e));
+}
+
+pub fn parse_file(text: &str) -> Result<(String, Vec<(String, String)>), String> {
+    let mut base = "default".to_string();
+    let mut colors = Vec::new();
+    for entry in toml::parse(text)? {
+        let error = |message: String| format!("line {}: {}", entry.line, message);
+        if !entry.section.is_empty() && entry.section != "theme" {
+            return Err(error(format!("Unknown section [{}]", entry.section)));
+        }
+        let value = match &entry.value {
+            Value::String(s) => s.clone(),
+            value => return Err(error(format!("{} must be a string, not {}", entry.key, value.type_name()))),
+        };
+        if entry.key == "base" {
+            ThemeSpec::builtin(&value).ok_or(error(format!("Unknown theme '{}', expected one of: {}", value, THEMES.join(" "))))?;
+            base = value.to_lowercase();
+        } else {
+            check_color(&entry.key, &value).map_err(error)?;
+            set_color(&mut colors, &entry.key, &value);
+        }
+    }
+    return Ok((base, colors));
+}
+
+// Theme key and colour as used in a [theme] section
+pub fn check_color(key: &str, value: &str) -> Result<(), String> {
+    return ThemeSpec::builtin("default").unwrap().set(key, value);
+}
+
+// Set colour, replacing an earlier one for the same key
+pub fn set_color(colors: &mut Vec<(String, String)>, key: &str, value: &str) {
+    colors.retain(|(k, _)| k != key);
+    colors.push((key.to_string(), value.to_string()));
+}
+
+impl Theme {
+    // Pixel colour between off and on for a persistence level
+    pub fn blend(&self, level: f32) -> Color {
+        if level <= 0.0 {
+            return self.display[0];
+        }
+        if level >= 1.0 || self.depth == ColorDepth::Mono {
+            return self.display[1];
+        }
+        let (r, g, b) = self.blend_rgb(level);
+        return fit_color(Shade::Rgb(r, g, b), self.depth);
+    }
+
+    pub fn blend_rgb(&self, level: f32) -> (u8, u8, u8) {
+        let (off, on) = (self.display_rgb[0], self.display_rgb[1]);
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * level.clamp(0.0, 1.0)).round() as u8;
+        return (mix(off.0, on.0), mix(off.1, on.1), mix(off.2, on.2));
+    }
+}
+
+fn shade_rgb(shade: Shade, default: (u8, u8, u8)) -> (u8, u8, u8) {
+    match shade {
+        Shade::Default => default,
+        Shade::Named(idx) => ANSI_RGB[idx as usize],
+        Shade::Rgb(r, g, b) => (r, g, b),
+    }
+}
+
+fn named_color(idx: u8) -> Color {
+    match idx {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        7 => Color::Gray,
+        8 => Color::DarkGray,
+        9 => Color::LightRed,
+        10 => Color::LightGreen,
+        11 => Color::LightYellow,
+        12 => Color::LightBlue,
+        13 => Color::LightMagenta,
+        14 => Color::LightCyan,
+        _ => Color::White,
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> i32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2);
+    return d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2);
+}
+
+// Nearest xterm 256 colour from the 6x6x6 cube or the grey ramp
+fn ansi256(rgb: (u8, u8, u8)) -> u8 {
+    let level = |v: u8| if v < 48 { 0 } else if v < 115 { 1 } else { (v as u16 - 35) as u8 / 40 };
+    let value = |l: u8| if l == 0 { 0 } else { 55 + l * 40 };
+    let (r, g, b) = (level(rgb.0), level(rgb.1), level(rgb.2));
+    let cube = 16 + 36 * r + 6 * g + b;
+    let cube_rgb = (value(r), value(g), value(b));
+
+    let average = (rgb.0 as u16 + rgb.1 as u16 + rgb.2 as u16) / 3;
+    let grey = if average > 238 { 23 } else { average.saturating_sub(3) as u8 / 10 };
+    let grey_value = 8 + grey * 10;
+    if distance(rgb, (grey_value, grey_value, grey_value)) < distance(rgb, cube_rgb) {
+        return 232 + grey;
+    }
+    return cube;
+}
+
+fn ansi16(rgb: (u8, u8, u8)) -> u8 {
+    let mut best = 0;
+    for (idx, ansi) in ANSI_RGB.iter().enumerate() {
+        if distance(rgb, *ansi) < distance(rgb, ANSI_RGB[best]) {
+            best = idx;
+        }
+    }
+    return best as u8;
+}
+
+pub fn fit_color(shade: Shade, depth: ColorDepth) -> Color {
+    match (shade, depth) {
+        (Shade::Default, _) => Color::Reset,
+        (Shade::Named(idx), ColorDepth::TrueColor | ColorDepth::Ansi256 | ColorDepth::Ansi16) => named_color(idx),
+        (Shade::Rgb(r, g, b), ColorDepth::TrueColor) => Color::Rgb(r, g, b),
+        (Shade::Rgb(r, g, b), ColorDepth::Ansi256) => Color::Indexed(ansi256((r, g, b))),
+        (Shade::Rgb(r, g, b), ColorDepth::Ansi16) => named_color(ansi16((r, g, b))),
+        // Monochrome: black or white by brightness
+        (shade, ColorDepth::Mono) => match distance(shade_rgb(shade, (0, 0, 0)), (0, 0, 0)) > 3 * 128 * 128 {
+            true => Color::White,
+            false => Color::Black,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theme_file() {
+        let (base, colors) = parse_file("# warm\nbase = \"Amber\"\nfocus = \"#FFD700\"\n[theme]\ncolor1 = \"yellow\"\nfocus = \"red\"\n").unwrap();
+        assert_eq!(base, "amber");
+        assert_eq!(colors, vec![("color1".to_string(), "yellow".to_string()), ("focus".to_string(), "red".to_string())]);
+        assert_eq!(parse_file("").unwrap(), ("default".to_string(), Vec::new()));
+
+        assert!(parse_file("base = amber\n").is_err()); // Strings are quoted
+        assert_eq!(parse_file("base = \"neon\"").unwrap_err(), format!("line 1: Unknown theme 'neon', expected one of: {}", THEMES.join(" ")));
+        assert_eq!(parse_file("\ncolor9 = \"red\"").unwrap_err(), format!("line 2: Unknown theme key 'color9', expected one of: {}", THEME_KEYS.join(" ")));
+        assert_eq!(parse_file("focus = 1").unwrap_err(), "line 1: focus must be a string, not integer");
+        assert!(parse_file("[display]\nfocus = \"red\"").unwrap_err().contains("Unknown section [display]"));
+    }
+
+    #[test]
+    fn colors_are_set_over_base() {
+        let mut spec = ThemeSpec::builtin("amber").unwrap();
+        spec.set("color1", "#102030").unwrap();
+        assert_eq!(spec.resolve(ColorDepth::TrueColor).display_rgb[1], (0x10, 0x20, 0x30));
+        assert!(spec.set("base", "green").is_err());
+    }
+}
@@ -0,0 +1,308 @@
+// Reader for the TOML subset used by configuration files: [section] headers
+// and key = value lines with strings, integers, floats, booleans and arrays,
+// which may span lines.
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "boolean",
+            Value::Array(_) => "array",
+        }
+    }
+}
+
+// Entry with its section, "" before the first header
+pub struct Entry {
+    pub section: String,
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+pub fn parse(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    let mut chars = Chars { chars: text.chars().collect(), pos: 0 };
+    loop {
+        chars.skip_space();
+        let line_no = chars.line();
+        let error = |message: &str| format!("line {}: {}", line_no, message);
+        match chars.peek() {
+            None => return Ok(entries),
+            Some('\n') | Some('#') => {
+                chars.skip_line();
+                continue;
+            }
+            Some('[') => {
+                let line = chars.rest_of_line();
+                let end = line.find(']').ok_or(error("missing ']'"))?;
+                section = line[1..end].trim().to_string();
+                if section.is_empty() || !line[end + 1..].trim().is_empty() && !line[end + 1..].trim().starts_with('#') {
+                    return Err(error("invalid section header"));
+                }
+                continue;
+            }
+            Some(_) => {}
+        }
+        let key = chars.key().map_err(|e| error(&e))?;
+        chars.skip_space();
+        if chars.next() != Some('=') {
+            return Err(error("expected '=' after key"));
+        }
+        // Errors inside values spanning lines point at the line they are on
+        let value = chars.value().map_err(|e| format!("line {}: {}", chars.line(), e))?;
+        chars.skip_space();
+        if !matches!(chars.peek(), None | Some('\n') | Some('#')) {
+            return Err(format!("line {}: unexpected text after value", chars.line()));
+        }
+        chars.skip_line();
+        entries.push(Entry { section: section.clone(), key, value, line: line_no });
+    }
+}
+
+struct Chars {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Chars {
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.pos).copied();
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        return c;
+    }
+
+    // Line number at the current position, from 1
+    fn line(&self) -> usize {
+        return 1 + self.chars[..self.pos.min(self.chars.len())].iter().filter(|c| **c == '\n').count();
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t') | Some('\r')) {
+            self.pos += 1;
+        }
+    }
+
+    // Whitespace, line breaks and comments between array items
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some('\n') => self.pos += 1,
+                Some('#') => self.skip_line(),
+                _ => return,
+            }
+        }
+    }
+
+    // Rest of the line without the line break, which is skipped
+    fn rest_of_line(&mut self) -> String {
+        let start = self.pos;
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.pos += 1;
+        }
+        let line = self.chars[start..self.pos].iter().collect();
+        self.skip_line();
+        return line;
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                return;
+            }
+        }
+    }
+
+    // Bare key of letters, digits, '_' and '-', or a quoted key
+    fn key(&mut self) -> Result<String, String> {
+        if matches!(self.peek(), Some('"') | Some('\'')) {
+            return self.string();
+        }
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err("expected key".to_string());
+        }
+        return Ok(self.chars[start..self.pos].iter().collect());
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        match self.peek() {
+            Some('"') | Some('\'') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    // Arrays can span lines
+                    self.skip_blank();
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.skip_blank();
+                    match self.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Value::Array(items)),
+                        _ => return Err("expected ',' or ']' in array".to_string()),
+                    }
+                }
+            }
+            Some('\n') => Err("missing value".to_string()),
+            Some(_) => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if !matches!(c, ' ' | '\t' | '\r' | '\n' | ',' | ']' | '#')) {
+                    self.pos += 1;
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                let number = word.replace('_', "");
+                if word == "true" || word == "false" {
+                    return Ok(Value::Bool(word == "true"));
+                }
+                if let Some(hex) = number.strip_prefix("0x") {
+                    return i64::from_str_radix(hex, 16).map(Value::Integer).map_err(|_| format!("invalid value '{}'", word));
+                }
+                if let Ok(n) = number.parse::<i64>() {
+                    return Ok(Value::Integer(n));
+                }
+                return number.parse::<f64>().map(Value::Float).map_err(|_| format!("invalid value '{}'", word));
+            }
+            None => Err("missing value".to_string()),
+        }
+    }
+
+    // Basic "..." string with escapes or literal '...' string
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.next().unwrap();
+        let mut s = String::new();
+        loop {
+            if matches!(self.peek(), None | Some('\n')) {
+                return Err("unterminated string".to_string());
+            }
+            match self.next() {
+                Some(c) if c == quote => return Ok(s),
+                Some('\\') if quote == '"' => match self.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('u') => {
+                        let digits: String = (0..4).filter_map(|_| self.next()).collect();
+                        let code = u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32)
+                            .ok_or("invalid \\u escape".to_string())?;
+                        s.push(code);
+                    }
+                    _ => return Err("invalid escape in string".to_string()),
+                },
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+}
+
+// Value written back as TOML
+pub fn format(value: &Value) -> String {
+    match value {
+        Value::String(s) => {
+            let escaped: String = s.chars().map(|c| match c {
+                '"' => "\\\"".to_string(),
+                '\\' => "\\\\".to_string(),
+                '\n' => "\\n".to_string(),
+                '\t' => "\\t".to_string(),
+                c => c.to_string(),
+            }).collect();
+            format!("\"{}\"", escaped)
+        }
+        Value::Integer(n) => n.to_string(),
+        Value::Float(f) if f.fract() == 0.0 => format!("{:.1}", f),
+        Value::Float(f) => f.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Array(items) => format!("[{}]", items.iter().map(format).collect::<Vec<String>>().join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_and_sections() {
+        let text = "top = 1\n\n# comment\n[machine] # trailing\nname = \"a \\\"b\\\"\\u00e9\" # note\n'quoted key' = 'C:\\dir'\r\nhex = 0x1F\nbig = 1_000\nratio = -2.5\non = false\n";
+        let entries = parse(text).unwrap();
+        let found: Vec<(&str, &str, &Value, usize)> = entries.iter().map(|e| (e.section.as_str(), e.key.as_str(), &e.value, e.line)).collect();
+        assert_eq!(found, vec![
+            ("", "top", &Value::Integer(1), 1),
+            ("machine", "name", &Value::String("a \"b\"é".to_string()), 5),
+            ("machine", "quoted key", &Value::String("C:\\dir".to_string()), 6),
+            ("machine", "hex", &Value::Integer(0x1f), 7),
+            ("machine", "big", &Value::Integer(1000), 8),
+            ("machine", "ratio", &Value::Float(-2.5), 9),
+            ("machine", "on", &Value::Bool(false), 10),
+        ]);
+    }
+
+    #[test]
+    fn multi_line_arrays() {
+        let text = "palette = [\n  \"#000000\", # background\n\n  \"#ffffff\",\n]\nnested = [[1, 2],\n  [3]]\nafter = true\n";
+        let entries = parse(text).unwrap();
+        let strings = |items: &[&str]| Value::Array(items.iter().map(|s| Value::String(s.to_string())).collect());
+        assert_eq!(entries[0].value, strings(&["#000000", "#ffffff"]));
+        assert_eq!(entries[1].value, Value::Array(vec![
+            Value::Array(vec![Value::Integer(1), Value::Integer(2)]),
+            Value::Array(vec![Value::Integer(3)]),
+        ]));
+        assert_eq!((entries[2].key.as_str(), entries[2].line), ("after", 8));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| parse(text).err().unwrap();
+        assert_eq!(error("a = 1\n[machine"), "line 2: missing ']'");
+        assert_eq!(error("[]"), "line 1: invalid section header");
+        assert_eq!(error("= 1"), "line 1: expected key");
+        assert_eq!(error("a 1"), "line 1: expected '=' after key");
+        assert_eq!(error("a =\nb = 2"), "line 1: missing value");
+        assert_eq!(error("a = \"open\nb = 2"), "line 1: unterminated string");
+        assert_eq!(error("a = 1 2"), "line 1: unexpected text after value");
+        assert_eq!(error("a = [\n 1,\n 2 3\n]"), "line 3: expected ',' or ']' in array");
+        assert_eq!(error("a = [1,\n"), "line 2: missing value");
+        assert_eq!(error("a = nope"), "line 1: invalid value 'nope'");
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let values = [
+            Value::String("tab\t\"quote\" \\ é\n".to_string()),
+            Value::Integer(-7),
+            Value::Float(3.0),
+            Value::Float(0.25),
+            Value::Bool(true),
+            Value::Array(vec![Value::String("x".to_string()), Value::Array(Vec::new())]),
+        ];
+        for value in values {
+            let entries = parse(&format!("key = {}", format(&value))).unwrap();
+            assert_eq!(entries[0].value, value);
+        }
+    }
+}
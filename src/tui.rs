@@ -1,81 +1,891 @@
 use crate::CPU;
-use crate::disassembler::{decode};
+use crate::cpu::{CpuEvent, Program, Quirks, TIMER_RATE};
+use crate::database::RomInfo;
+use crate::audio::{Audio, AudioOptions};
+use crate::disassembler::{decode, branch_target, Symbols};
+use crate::debugger::{Debugger, parse_address, parse_watchpoint};
+use crate::expr::{self, Register};
+use crate::keymap::{KeyMap, NamedKey};
+use crate::memimage;
+use crate::render::{self, RenderMode};
+use crate::graphics::{self, Graphics};
+use crate::theme::Theme;
+use crate::phosphor::{Persistence, Phosphor};
 use std::{
     error::Error,
-    io,
+    io::{self, Write},
     time::{Duration, Instant},
 };
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout, Rect, Alignment},
-    style::{Color, Style, Modifier},
+    style::{Style, Modifier},
     widgets::{
-        Block, Borders, Row, Table, Cell, TableState, Paragraph, List, ListItem, ListState
+        Block, Borders, Row, Table, Cell, TableState, Paragraph, List, ListItem, ListState, Wrap
     },
     text::{Span, Spans},
 
-    Frame, Terminal, symbols,
+    Frame, Terminal,
 };
 
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind, MouseButton, MouseEvent,
+        MouseEventKind, KeyboardEnhancementFlags, PushKeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+    },
+    cursor, execute, queue,
+    terminal::{disable_raw_mode, enable_raw_mode, supports_keyboard_enhancement, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use resize::{Pixel::RGB8, px::RGB};
 
 enum Window {
     Memory,
+    Cpu,
     Registers,
     Instructions,
+    Disassembly,
+    Breakpoints,
+    Stack,
     //CPU,
 }
 
+// Input mode of the RAM editor
+#[derive(Clone, Copy, PartialEq)]
+enum EditMode {
+    Hex,
+    Ascii,
+}
+
+// Keys go to debugger hotkeys or to the CHIP-8 keypad
+#[derive(Clone, Copy, PartialEq)]
+enum InputMode {
+    Debug,
+    Game,
+}
+
+// Registers listed in the CPU panel
+const CPU_REGISTERS: [Register; 5] = [Register::PC, Register::I, Register::SP, Register::DT, Register::ST];
+
+// Where execution started by a step command stops
+enum RunTarget {
+    Address(u16), // Run to cursor
+    Return(u16, usize), // Step over call, return address and stack depth of caller
+    StackBelow(usize), // Step out of subroutine
+}
+
+// Brightness steps of image output with persistence
+const IMAGE_LEVELS: u8 = 8;
+
+const RUN_BATCH: usize = 1000; // Instructions per tick while running to target
+
+enum PromptKind {
+    Breakpoint,
+    Watchpoint,
+    Events,
+    Goto,
+    Sprite,
+    Register(Register),
+    Condition(usize),
+    HitCount(usize),
+    LogMessage(usize),
+    ExportImage,
+    ImportImage,
+}
+
+// Single line input shown in the help panel
+struct Prompt {
+    kind: PromptKind,
+    input: String,
+}
+
+// Settings given on the command line
+pub struct TuiOptions {
+    pub debug: bool,
+    pub symbols: Symbols,
+    pub keymap: KeyMap,
+    pub key_hold: Duration,
+    pub render_mode: RenderMode,
+    pub graphics: Option<Graphics>,
+    pub theme: Theme,
+    pub persistence: Persistence,
+    pub audio: AudioOptions,
+    pub quirks: Quirks,
+    pub history: usize, // Executed instructions kept for the instruction list
+    pub tick_rate: u64, // Instructions per frame while executing
+    pub rom: Option<RomInfo>, // Database entry of the loaded ROM
+}
+
 struct Tui {
     cpu: CPU,
-    width: u8,
-    height: u8,
-    keys: [bool; 16],
+    keypad_area: Rect, // Inner area of keypad panel from last draw, for mouse clicks
+    mouse_key: Option<u8>, // Key held down with mouse
+    keymap: KeyMap,
+    input_mode: InputMode,
+    key_presses: [Option<Instant>; 16], // Time of last keyboard press per key
+    key_hold: Duration, // Without release events keys are held this long after last press
+    key_releases: bool, // Terminal reports key release events
+    render_mode: RenderMode,
+    theme: Theme,
+    phosphor: Phosphor, // Display persistence, updated every tick
+    audio: Audio,
+    last_timer: Instant, // Timers count down in real time while executing
+    bell: bool, // Sound started without audio device, ring terminal bell
+    graphics: Option<Graphics>, // Image protocol for the display, None renders characters
+    graphics_placement: Option<(u16, u16, usize)>, // Image cell position and scale from last draw
+    graphics_frame: Option<(Vec<u8>, (u16, u16, usize))>, // Image currently on screen
+    rom: Option<RomInfo>,
+    tick_rate: u64, // Instructions per frame while executing
     executing: bool,
     current_window: Window,
     register_table_state: TableState,
     memory_table_state: TableState,
     cpu_table_state: TableState,
     instruction_list_state: ListState,
+    breakpoint_list_state: ListState,
+    stack_list_state: ListState,
+    mem_cursor: u16,
+    mem_edit: Option<EditMode>,
+    mem_nibble: Option<u8>, // High nibble typed in hex mode
+    mem_undo: Vec<Vec<(u16, u8)>>, // Address and previous value of bytes, per edit
+    disasm_cursor: u16,
+    disasm_follow: bool, // Keep disassembly cursor on pc
+    symbols: Symbols,
+    debugger: Debugger,
+    break_hit: Option<u16>, // Address where execution was stopped by breakpoint
+    skip_breakpoint: bool, // Allow running over breakpoint at pc after resuming
+    run_target: Option<RunTarget>,
+    prompt: Option<Prompt>,
+    message: Option<String>,
 }
 
 impl Tui {
-    fn new(debug: bool) -> Tui {
+    fn new(options: TuiOptions, audio: Audio) -> Tui {
+        let mut cpu = CPU::new(options.debug);
+        cpu.quirks = options.quirks;
+        cpu.history_limit = options.history;
+        // Tell which database entry was applied
+        let message = options.rom.as_ref().map(|rom| match &rom.platform {
+            Some(platform) => format!("{} ({}, {} instructions per frame)", rom.label(), platform.name, options.tick_rate),
+            None => rom.label(),
+        });
         Tui {
-            cpu: CPU::new(debug),
-            width: 64,
-            height: 32,
-            keys: [false; 16],
+            cpu,
+            keypad_area: Rect::default(),
+            mouse_key: None,
+            keymap: options.keymap,
+            input_mode: InputMode::Debug,
+            key_presses: [None; 16],
+            key_hold: options.key_hold,
+            key_releases: false,
+            render_mode: options.render_mode,
+            theme: options.theme,
+            phosphor: Phosphor::new(options.persistence),
+            audio,
+            last_timer: Instant::now(),
+            bell: false,
+            graphics: options.graphics,
+            graphics_placement: None,
+            graphics_frame: None,
+            rom: options.rom,
+            tick_rate: options.tick_rate,
             executing: false,
             current_window: Window::Memory,
             register_table_state: TableState::default(),
             memory_table_state: TableState::default(),
             cpu_table_state: TableState::default(),
             instruction_list_state: ListState::default(),
+            breakpoint_list_state: ListState::default(),
+            stack_list_state: ListState::default(),
+            mem_cursor: 0,
+            mem_edit: None,
+            mem_nibble: None,
+            mem_undo: Vec::new(),
+            disasm_cursor: 0,
+            disasm_follow: true,
+            symbols: options.symbols,
+            debugger: Debugger::new(),
+            break_hit: None,
+            skip_breakpoint: false,
+            run_target: None,
+            prompt: None,
+            message,
         }
     }
     fn next_cycle(&mut self) {
-        self.cpu.next_cycle();
+        self.break_hit = None;
+        if self.cpu.next_cycle() == -1 {
+            self.message = Some(self.pc_outside_memory());
+            return;
+        }
+        if let Some(report) = self.check_traps() {
+            self.message = Some(report);
+        }
+    }
+
+    fn pc_outside_memory(&self) -> String {
+        return format!("Program counter {:03X} is outside memory", self.cpu.pc);
+    }
+
+    // Watchpoints and event breakpoints trap after the instruction that caused them
+    fn check_traps(&mut self) -> Option<String> {
+        if self.cpu.get_events().contains(&CpuEvent::SoundStart) && !self.audio.has_device() {
+            self.bell = true;
+        }
+        let watch = self.debugger.check_watchpoints(&self.cpu).map(|r| format!("Watchpoint: {}", r));
+        let event = self.debugger.check_events(&self.cpu).map(|r| format!("Event: {}", r));
+        return watch.or(event);
+    }
+
+    fn toggle_execution(&mut self) {
+        self.executing = !self.executing;
+        self.run_target = None;
+        if self.executing {
+            self.skip_breakpoint = true;
+        }
+    }
+
+    fn run_to(&mut self, target: RunTarget) {
+        self.run_target = Some(target);
+        self.executing = true;
+        self.skip_breakpoint = true;
+    }
+
+    // Step over call instructions, single step anything else
+    pub fn step_over(&mut self) {
+        let ins = self.cpu.fetch_no_increment().unwrap_or(0);
+        if ins & 0xf000 == 0x2000 {
+            self.run_to(RunTarget::Return(self.cpu.pc + 2, self.cpu.get_stack().len()));
+        } else {
+            self.next_cycle();
+        }
+    }
+
+    // Run until current subroutine returns
+    pub fn step_out(&mut self) {
+        let depth = self.cpu.get_stack().len();
+        if depth == 0 {
+            self.message = Some("Not in a subroutine".to_string());
+            return;
+        }
+        self.run_to(RunTarget::StackBelow(depth));
+    }
+
+    pub fn run_to_cursor(&mut self) {
+        match self.selected_address() {
+            Some(address) => self.run_to(RunTarget::Address(address)),
+            None => self.message = Some("No address selected".to_string()),
+        }
+    }
+
+    fn target_reached(&self) -> bool {
+        let depth = self.cpu.get_stack().len();
+        match self.run_target {
+            Some(RunTarget::Address(address)) => self.cpu.pc == address,
+            Some(RunTarget::Return(address, d)) => self.cpu.pc == address && depth == d,
+            Some(RunTarget::StackBelow(d)) => depth < d,
+            None => false,
+        }
     }
 
     fn on_tick(&mut self) {
+        // Run quickly through the code when heading to a target
+        let count = if self.run_target.is_some() { RUN_BATCH } else { self.tick_rate as usize };
+        for _ in 0..count {
+            if !self.executing {
+                break;
+            }
+            self.step_checked();
+        }
+        self.phosphor.update(&self.cpu.get_pixels());
+    }
+
+    // Execute one instruction unless a breakpoint, watchpoint or run target stops execution
+    fn step_checked(&mut self) {
+        // Stop before executing instruction with breakpoint
+        let pc = self.cpu.pc;
+        if !self.skip_breakpoint && self.debugger.should_break(&self.cpu) {
+            self.executing = false;
+            self.run_target = None;
+            self.break_hit = Some(pc);
+            self.message = Some(format!("Breakpoint hit at {:03X}", pc));
+            return;
+        }
+        self.skip_breakpoint = false;
+        self.break_hit = None;
+        if self.cpu.next_cycle() == -1 {
+            self.executing = false; // Program ended
+            self.run_target = None;
+            self.message = Some(self.pc_outside_memory());
+            return;
+        }
+        if let Some(report) = self.check_traps() {
+            self.executing = false;
+            self.run_target = None;
+            self.message = Some(report);
+        }
+        if self.target_reached() {
+            self.executing = false;
+            self.run_target = None;
+            self.message = Some(format!("Stopped at {:03X}", self.cpu.pc));
+        }
+    }
+
+    // Address of the item selected in current window
+    fn selected_address(&self) -> Option<u16> {
+        match self.current_window {
+            Window::Memory => Some(self.mem_cursor),
+            Window::Instructions => match self.instruction_list_state.selected() {
+                Some(0) | None => Some(self.cpu.pc),
+                Some(i) => {
+                    let history = self.cpu.get_history();
+                    history.get(history.len() - i).map(|(addr, _)| *addr)
+                }
+            },
+            Window::Disassembly => Some(self.disasm_address()),
+            Window::Breakpoints => self.breakpoint_list_state.selected()
+                .and_then(|i| self.debugger.get_breakpoints().get(i).and_then(|bp| bp.address)),
+            Window::Stack => self.stack_list_state.selected().and_then(|i| self.frame_address(i)),
+            Window::Registers | Window::Cpu => None,
+        }
+    }
+
+    fn selected_breakpoint(&self) -> Option<usize> {
+        match self.current_window {
+            Window::Breakpoints => self.breakpoint_list_state.selected()
+                .filter(|i| *i < self.debugger.get_breakpoints().len()),
+            _ => None,
+        }
+    }
+
+    // Watchpoints are listed after breakpoints in the same panel
+    fn selected_watchpoint(&self) -> Option<usize> {
+        match self.current_window {
+            Window::Breakpoints => self.breakpoint_list_state.selected()
+                .and_then(|i| i.checked_sub(self.debugger.get_breakpoints().len()))
+                .filter(|i| *i < self.debugger.get_watchpoints().len()),
+            _ => None,
+        }
+    }
+
+    fn breakpoint_list_len(&self) -> usize {
+        return self.debugger.get_breakpoints().len() + self.debugger.get_watchpoints().len();
+    }
+
+    pub fn toggle_breakpoint(&mut self) {
+        if let Window::Breakpoints = self.current_window {
+            // Remove selected, also works for breakpoints without address
+            if let Some(idx) = self.selected_breakpoint() {
+                self.debugger.remove_breakpoint(idx);
+            } else if let Some(idx) = self.selected_watchpoint() {
+                self.debugger.remove_watchpoint(idx);
+            }
+        } else {
+            match self.selected_address() {
+                Some(address) => self.debugger.toggle_breakpoint(address),
+                None => return,
+            }
+        }
+
+        // Keep selection inside the list after removing
+        let count = self.breakpoint_list_len();
+        if let Some(i) = self.breakpoint_list_state.selected() {
+            if count == 0 {
+                self.breakpoint_list_state.select(None);
+            } else if i >= count {
+                self.breakpoint_list_state.select(Some(count - 1));
+            }
+        }
+    }
+
+    pub fn toggle_breakpoint_enabled(&mut self) {
+        if let Some(idx) = self.selected_breakpoint() {
+            self.debugger.toggle_enabled(idx);
+        } else if let Some(idx) = self.selected_watchpoint() {
+            self.debugger.toggle_watchpoint_enabled(idx);
+        }
+    }
+
+    pub fn open_prompt(&mut self, kind: PromptKind) {
+        let input = match kind {
+            PromptKind::Events => self.debugger.events.names().join(" "),
+            _ => String::new(),
+        };
+        self.prompt = Some(Prompt { kind, input });
+    }
+
+    // Open prompt editing selected breakpoint, prefilled with current value
+    pub fn open_breakpoint_prompt(&mut self, kind: fn(usize) -> PromptKind) {
+        let idx = match self.selected_breakpoint() {
+            Some(idx) => idx,
+            None => return,
+        };
+        let bp = &self.debugger.get_breakpoints()[idx];
+        let kind = kind(idx);
+        let input = match kind {
+            PromptKind::Condition(_) => bp.condition.as_ref().map(|c| c.source.clone()),
+            PromptKind::HitCount(_) => bp.hit_target.map(|t| t.to_string()),
+            PromptKind::LogMessage(_) => bp.log_message.as_ref().map(|t| t.source.clone()),
+            _ => None,
+        };
+        self.prompt = Some(Prompt { kind, input: input.unwrap_or_default() });
+    }
+
+    pub fn handle_prompt_key(&mut self, code: KeyCode) {
+        let prompt = match self.prompt.as_mut() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        match code {
+            KeyCode::Char(c) => prompt.input.push(c),
+            KeyCode::Backspace => {prompt.input.pop();}
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Enter => {
+                let prompt = self.prompt.take().unwrap();
+                self.submit_prompt(prompt);
+            }
+            _ => {}
+        }
+    }
+
+    fn submit_prompt(&mut self, prompt: Prompt) {
+        match prompt.kind {
+            PromptKind::Breakpoint => {
+                // "<address> [if <condition>]", address "*" or none breaks anywhere
+                let input = prompt.input.trim();
+                let (address, condition) = match input.split_once("if ") {
+                    Some((address, condition)) => (address.trim(), Some(condition)),
+                    None => (input, None),
+                };
+                let address = match address {
+                    "" | "*" => None,
+                    a => match parse_address(a) {
+                        Some(address) => Some(address),
+                        None => {
+                            self.message = Some(format!("Invalid address: {}", a));
+                            return;
+                        }
+                    },
+                };
+                self.message = match self.debugger.add_breakpoint(address, condition) {
+                    Ok(_) => Some(format!("Breakpoint set: {}", input)),
+                    Err(err) => Some(err),
+                };
+            }
+            PromptKind::Watchpoint => match parse_watchpoint(&prompt.input) {
+                Some((start, end, kind)) => {
+                    self.debugger.add_watchpoint(start, end, kind);
+                    self.message = Some(format!("Watchpoint set: {}", prompt.input.trim()));
+                }
+                None => self.message = Some(format!("Invalid watchpoint: {}", prompt.input)),
+            },
+            PromptKind::Goto => match parse_address(&prompt.input) {
+                Some(address) => {
+                    self.current_window = Window::Memory;
+                    self.goto_memory(address);
+                }
+                None => self.message = Some(format!("Invalid address: {}", prompt.input)),
+            },
+            PromptKind::Sprite => {
+                // Rows of '#'/'1' set and '.'/'0' clear pixels, separated by spaces
+                let mut bytes = Vec::new();
+                for row in prompt.input.split_whitespace() {
+                    if row.len() > 8 || !row.chars().all(|c| "#1.0".contains(c)) {
+                        self.message = Some(format!("Invalid sprite row: {}", row));
+                        return;
+                    }
+                    let bits = row.chars().fold(0u8, |acc, c| acc << 1 | (c == '#' || c == '1') as u8);
+                    bytes.push(bits << (8 - row.len()));
+                }
+                let writes = bytes.iter().enumerate().map(|(i, byte)| (self.mem_cursor.wrapping_add(i as u16) & 0x0fff, *byte)).collect();
+                self.edit_bytes(writes);
+                self.message = Some(format!("Wrote {} sprite rows at {:03X}", bytes.len(), self.mem_cursor));
+            }
+            PromptKind::Register(reg) => {
+                self.message = self.set_register(reg, &prompt.input).err();
+            }
+            PromptKind::Events => {
+                self.message = self.debugger.set_event_breaks(&prompt.input).err();
+            }
+            PromptKind::Condition(idx) => {
+                self.message = self.debugger.set_condition(idx, &prompt.input).err();
+            }
+            PromptKind::HitCount(idx) => match prompt.input.trim() {
+                "" | "0" => self.debugger.set_hit_target(idx, None),
+                s => match s.parse::<u32>() {
+                    Ok(n) => self.debugger.set_hit_target(idx, Some(n)),
+                    Err(_) => self.message = Some(format!("Invalid hit count: {}", s)),
+                },
+            },
+            PromptKind::LogMessage(idx) => {
+                self.message = self.debugger.set_log_message(idx, &prompt.input).err();
+            }
+            PromptKind::ExportImage => {
+                // "<file> [range]", whole memory by default
+                let mut parts = prompt.input.split_whitespace();
+                let path = parts.next().unwrap_or("");
+                let range = parts.next().unwrap_or("all");
+                self.message = match memimage::parse_range(range) {
+                    _ if path.is_empty() => Some("No file given".to_string()),
+                    Some((start, end)) => match memimage::save(path, &self.cpu.get_memory(), start, end) {
+                        Ok(format) => Some(format!("Saved {:03X}-{:03X} to {} as {}", start, end, path, format.name())),
+                        Err(err) => Some(err),
+                    },
+                    None => Some(format!("Invalid range: {}", range)),
+                };
+            }
+            PromptKind::ImportImage => {
+                // "<file> [address]", raw images go to the memory cursor by default
+                let mut parts = prompt.input.split_whitespace();
+                let path = parts.next().unwrap_or("");
+                let address = match parts.next() {
+                    Some(a) => match parse_address(a) {
+                        Some(address) => address,
+                        None => {
+                            self.message = Some(format!("Invalid address: {}", a));
+                            return;
+                        }
+                    },
+                    None => self.mem_cursor,
+                };
+                match memimage::load(path, address) {
+                    Ok(blocks) => {
+                        let writes: Vec<(u16, u8)> = blocks.iter()
+                            .flat_map(|(start, bytes)| bytes.iter().enumerate().map(move |(i, byte)| (start + i as u16, *byte)))
+                            .collect();
+                        let count = writes.len();
+                        self.edit_bytes(writes);
+                        self.message = Some(format!("Loaded {} bytes from {}, <Z> undoes", count, path));
+                    }
+                    Err(err) => self.message = Some(err),
+                }
+            }
+        }
+    }
+
+    pub fn move_mem_cursor(&mut self, delta: i32) {
+        let len = self.cpu.get_memory().len() as i32;
+        self.mem_cursor = (self.mem_cursor as i32 + delta).rem_euclid(len) as u16;
+        self.mem_nibble = None;
+        self.memory_table_state.select(Some(self.mem_cursor as usize / 16));
+    }
+
+    fn goto_memory(&mut self, address: u16) {
+        self.mem_cursor = address;
+        self.mem_nibble = None;
+        self.memory_table_state.select(Some(address as usize / 16));
+    }
+
+    // Write byte and remember old value for undo
+    fn edit_byte(&mut self, address: u16, value: u8) {
+        self.edit_bytes(vec![(address, value)]);
+    }
+
+    // Write bytes as one edit, undone together
+    fn edit_bytes(&mut self, writes: Vec<(u16, u8)>) {
+        if writes.is_empty() {
+            return;
+        }
+        let previous = writes.iter().map(|(address, _)| (*address, self.cpu.read_ram(*address))).collect();
+        self.mem_undo.push(previous);
+        for (address, value) in writes {
+            self.cpu.write_ram(address, value);
+        }
+    }
+
+    pub fn undo_edit(&mut self) {
+        match self.mem_undo.pop() {
+            Some(previous) => {
+                // Reverse order restores the oldest value of bytes written twice
+                for (address, value) in previous.iter().rev() {
+                    self.cpu.write_ram(*address, *value);
+                }
+                let address = previous[0].0;
+                self.goto_memory(address);
+                self.message = Some(match previous.len() {
+                    1 => format!("Undo edit at {:03X}", address),
+                    count => format!("Undo {} byte edit at {:03X}", count, address),
+                });
+            }
+            None => self.message = Some("Nothing to undo".to_string()),
+        }
+    }
+
+    pub fn start_edit(&mut self) {
+        match self.current_window {
+            Window::Memory => {
+                self.mem_edit = Some(EditMode::Hex);
+                self.mem_nibble = None;
+                self.goto_memory(self.mem_cursor);
+            }
+            Window::Registers | Window::Cpu => self.edit_register(),
+            Window::Stack => self.jump_to_frame(),
+            _ => {}
+        }
+    }
+
+    // Frame 0 is the current pc, others are call sites from innermost out
+    fn frame_address(&self, frame: usize) -> Option<u16> {
+        if frame == 0 {
+            return Some(self.cpu.pc);
+        }
+        let stack = self.cpu.get_stack();
+        return stack.get(stack.len().checked_sub(frame)?).map(|ret| ret.wrapping_sub(2));
+    }
+
+    // Show selected frame in disassembly and memory views
+    fn jump_to_frame(&mut self) {
+        if let Some(address) = self.stack_list_state.selected().and_then(|i| self.frame_address(i)) {
+            self.disasm_cursor = address;
+            self.disasm_follow = false;
+            self.goto_memory(address);
+        }
+    }
+
+    fn selected_register(&self) -> Option<Register> {
+        match self.current_window {
+            Window::Registers => self.register_table_state.selected().map(|i| Register::V(i as u8)),
+            Window::Cpu => self.cpu_table_state.selected().and_then(|i| CPU_REGISTERS.get(i).copied()),
+            _ => None,
+        }
+    }
+
+    // Prompt new value for selected register, prefilled with current value
+    fn edit_register(&mut self) {
+        let reg = match self.selected_register() {
+            Some(reg) => reg,
+            None => return,
+        };
         if self.executing {
-            if self.cpu.next_cycle() == -1 {
-                self.executing = false; // Program ended
+            self.message = Some("Pause execution to edit registers".to_string());
+            return;
+        }
+        if reg == Register::SP {
+            self.message = Some("SP follows the call stack and cannot be edited".to_string());
+            return;
+        }
+        let input = format!("0x{:X}", reg.read(&self.cpu));
+        self.prompt = Some(Prompt { kind: PromptKind::Register(reg), input });
+    }
+
+    // Value can be any expression, e.g. "0x2A", "V1 + 1" or "[I]"
+    fn set_register(&mut self, reg: Register, input: &str) -> Result<(), String> {
+        let value = expr::parse(input)?.eval(&self.cpu);
+        if value < 0 || value > reg.max() {
+            return Err(format!("{} must be between 0 and {:X}", reg.name(), reg.max()));
+        }
+        let old = reg.read(&self.cpu);
+        match reg {
+            Register::V(idx) => self.cpu.set_register(idx, value as u8),
+            Register::I => self.cpu.ir = value as u16,
+            Register::PC => self.cpu.pc = value as u16,
+            Register::SP => return Err("SP follows the call stack and cannot be edited".to_string()),
+            Register::DT => self.cpu.dt = value as u8,
+            Register::ST => self.cpu.st = value as u8,
+        }
+        self.debugger.push_log(format!("{:03X}: set {} {:X} -> {:X}", self.cpu.pc, reg.name(), old, value));
+        return Ok(());
+    }
+
+    pub fn handle_edit_key(&mut self, code: KeyCode) {
+        let mode = match self.mem_edit {
+            Some(mode) => mode,
+            None => return,
+        };
+        match code {
+            KeyCode::Esc | KeyCode::Enter => {
+                self.mem_edit = None;
+                self.mem_nibble = None;
+            }
+            KeyCode::Tab => {
+                self.mem_edit = Some(if mode == EditMode::Hex { EditMode::Ascii } else { EditMode::Hex });
+                self.mem_nibble = None;
+            }
+            KeyCode::Left | KeyCode::Backspace => self.move_mem_cursor(-1),
+            KeyCode::Right => self.move_mem_cursor(1),
+            KeyCode::Up => self.move_mem_cursor(-16),
+            KeyCode::Down => self.move_mem_cursor(16),
+            KeyCode::Char(c) if mode == EditMode::Ascii && c.is_ascii() => {
+                self.edit_byte(self.mem_cursor, c as u8);
+                self.move_mem_cursor(1);
+            }
+            KeyCode::Char(c) if mode == EditMode::Hex => {
+                let digit = match c.to_digit(16) {
+                    Some(d) => d as u8,
+                    None => return,
+                };
+                match self.mem_nibble.take() {
+                    None => self.mem_nibble = Some(digit),
+                    Some(high) => {
+                        self.edit_byte(self.mem_cursor, high << 4 | digit);
+                        self.move_mem_cursor(1);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn disasm_address(&self) -> u16 {
+        if self.disasm_follow {
+            return self.cpu.pc;
+        }
+        return self.disasm_cursor;
+    }
+
+    // Move disassembly cursor by instructions, stops following pc
+    pub fn scroll_disassembly(&mut self, delta: i32) {
+        let address = self.disasm_address() as i32 + delta * 2;
+        self.disasm_cursor = address.clamp(0, 0xffe) as u16;
+        self.disasm_follow = false;
+    }
+
+    pub fn follow_pc(&mut self) {
+        self.disasm_follow = true;
+    }
+
+    // Count timers down at TIMER_RATE and play the tick's sound
+    fn run_timers(&mut self) {
+        let tick = Duration::from_micros(1_000_000 / TIMER_RATE);
+        while self.last_timer.elapsed() >= tick {
+            self.last_timer += tick;
+            if self.executing {
+                let sound = self.cpu.st > 0;
+                self.cpu.tick_timers();
+                self.audio.tick(sound, self.cpu.get_audio_pattern());
+            }
+        }
+    }
+
+    fn ring_bell(&mut self) -> io::Result<()> {
+        if self.bell {
+            self.bell = false;
+            let mut stdout = io::stdout();
+            write!(stdout, "\x07")?;
+            stdout.flush()?;
+        }
+        return Ok(());
+    }
+
+    // Pixel brightness after persistence filter
+    pub fn display_levels(&self) -> Vec<f32> {
+        return self.phosphor.levels(&self.cpu.get_pixels());
+    }
+
+    // Write display image over the drawn frame when it has changed
+    fn draw_graphics(&mut self) -> io::Result<()> {
+        let graphics = match self.graphics {
+            Some(graphics) => graphics,
+            None => return Ok(()),
+        };
+        // Levels are quantized to a small palette for sixel
+        let steps = IMAGE_LEVELS - 1;
+        let frame = self.graphics_placement.map(|placement| {
+            let pixels = self.display_levels().iter().map(|level| (level * steps as f32).round() as u8).collect();
+            (pixels, placement)
+        });
+        if frame == self.graphics_frame {
+            return Ok(());
+        }
+        let mut stdout = io::stdout();
+        match &frame {
+            Some((pixels, (x, y, scale))) => {
+                queue!(stdout, cursor::MoveTo(*x, *y))?;
+                let palette: Vec<(u8, u8, u8)> = (0..=steps).map(|idx| self.theme.blend_rgb(idx as f32 / steps as f32)).collect();
+                write!(stdout, "{}", graphics::encode(graphics, pixels, 64, 32, *scale, graphics::cell_size(), &palette))?;
+            }
+            None => write!(stdout, "{}", graphics::clear(graphics))?,
+        }
+        stdout.flush()?;
+        self.graphics_frame = frame;
+        return Ok(());
+    }
+
+    pub fn cycle_render_mode(&mut self) {
+        self.render_mode = self.render_mode.next();
+        self.message = Some(format!("Render mode {}", self.render_mode.name()));
+    }
+
+    pub fn toggle_input_mode(&mut self) {
+        self.input_mode = match self.input_mode {
+            InputMode::Debug => InputMode::Game,
+            InputMode::Game => InputMode::Debug,
+        };
+    }
+
+    // Press or release keypad key mapped to keyboard key
+    pub fn handle_game_key(&mut self, event: KeyEvent) {
+        let key = match event.code {
+            KeyCode::Char(c) => self.keymap.key_for(c),
+            KeyCode::Enter => self.keymap.key_for('\n'),
+            KeyCode::Up => self.keymap.key_for_named(NamedKey::Up),
+            KeyCode::Down => self.keymap.key_for_named(NamedKey::Down),
+            KeyCode::Left => self.keymap.key_for_named(NamedKey::Left),
+            KeyCode::Right => self.keymap.key_for_named(NamedKey::Right),
+            KeyCode::Esc if event.kind == KeyEventKind::Press => {
+                self.input_mode = InputMode::Debug;
+                return;
+            }
+            _ => return,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return,
+        };
+        match event.kind {
+            KeyEventKind::Release => {
+                if self.mouse_key != Some(key) {
+                    self.cpu.set_key(key, false);
+                }
+            }
+            _ => {
+                self.cpu.set_key(key, true);
+                // Fall back to hold timeout when release will not be reported
+                if !self.key_releases {
+                    self.key_presses[key as usize] = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    // Release keyboard keys not pressed again within hold time
+    pub fn release_keys(&mut self) {
+        for key in 0..16u8 {
+            if let Some(pressed) = self.key_presses[key as usize] {
+                if pressed.elapsed() >= self.key_hold {
+                    self.key_presses[key as usize] = None;
+                    if self.mouse_key != Some(key) {
+                        self.cpu.set_key(key, false);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn handle_mouse(&mut self, event: MouseEvent) {
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if let Some(key) = keypad_key_at(self.keypad_area, event.column, event.row) {
+                    self.cpu.set_key(key, true);
+                    self.mouse_key = Some(key);
+                }
+            }
+            MouseEventKind::Up(MouseButton::Left) => {
+                if let Some(key) = self.mouse_key.take() {
+                    self.cpu.set_key(key, false);
+                }
             }
+            _ => {}
         }
     }
 
     pub fn cycle_window(&mut self) {
         match self.current_window {
-            Window::Memory => self.current_window = Window::Registers,
+            Window::Memory => self.current_window = Window::Cpu,
+            Window::Cpu => self.current_window = Window::Registers,
             Window::Registers => self.current_window = Window::Instructions,
-            Window::Instructions => self.current_window = Window::Memory,
+            Window::Instructions => self.current_window = Window::Disassembly,
+            Window::Disassembly => self.current_window = Window::Breakpoints,
+            Window::Breakpoints => self.current_window = Window::Stack,
+            Window::Stack => self.current_window = Window::Memory,
 
         }
     }
@@ -84,6 +894,7 @@ impl Tui {
         let (func, state) = match self.current_window {
             Window::Memory => (self.cpu.get_memory().len() / 16, &mut self.memory_table_state),
             Window::Registers => (self.cpu.get_registers().len(), &mut self.register_table_state),
+            Window::Cpu => (CPU_REGISTERS.len(), &mut self.cpu_table_state),
             _ => panic!("Invalid window"),
         };
 
@@ -104,6 +915,7 @@ impl Tui {
         let (func, state) = match self.current_window {
             Window::Memory => (self.cpu.get_memory().len() / 16, &mut self.memory_table_state),
             Window::Registers => (self.cpu.get_registers().len(), &mut self.register_table_state),
+            Window::Cpu => (CPU_REGISTERS.len(), &mut self.cpu_table_state),
             _ => panic!("Invalid window"),
         };
 
@@ -123,8 +935,13 @@ impl Tui {
     pub fn handle_next_list(&mut self) {
         let (func, state) = match self.current_window {
             Window::Instructions => (self.cpu.get_history().len() + 1, &mut self.instruction_list_state),
+            Window::Breakpoints => (self.breakpoint_list_len(), &mut self.breakpoint_list_state),
+            Window::Stack => (self.cpu.get_stack().len() + 1, &mut self.stack_list_state),
             _ => panic!("Invalid window"),
         };
+        if func == 0 {
+            return;
+        }
 
         let i = match state.selected() {
             Some(i) => {
@@ -142,8 +959,13 @@ impl Tui {
     pub fn handle_prev_list(&mut self) {
         let (func, state) = match self.current_window {
             Window::Instructions => (self.cpu.get_history().len() + 1, &mut self.instruction_list_state),
+            Window::Breakpoints => (self.breakpoint_list_len(), &mut self.breakpoint_list_state),
+            Window::Stack => (self.cpu.get_stack().len() + 1, &mut self.stack_list_state),
             _ => panic!("Invalid window"),
         };
+        if func == 0 {
+            return;
+        }
 
         let i = match state.selected() {
             Some(i) => {
@@ -160,34 +982,62 @@ impl Tui {
 
     pub fn handle_next(&mut self) {
         match self.current_window {
-            Window::Memory | Window::Registers => self.handle_next_table(),
-            Window::Instructions => self.handle_next_list(),
+            Window::Memory => self.move_mem_cursor(16),
+            Window::Registers | Window::Cpu => self.handle_next_table(),
+            Window::Instructions | Window::Breakpoints | Window::Stack => self.handle_next_list(),
+            Window::Disassembly => self.scroll_disassembly(1),
         }
     }
 
     pub fn handle_prev(&mut self) {
         match self.current_window {
-            Window::Memory | Window::Registers => self.handle_prev_table(),
-            Window::Instructions => self.handle_prev_list(),
+            Window::Memory => self.move_mem_cursor(-16),
+            Window::Registers | Window::Cpu => self.handle_prev_table(),
+            Window::Instructions | Window::Breakpoints | Window::Stack => self.handle_prev_list(),
+            Window::Disassembly => self.scroll_disassembly(-1),
         }
     }
 }
 
-pub fn tui_start(binary: Vec<u8>, debug: bool) -> Result<(), Box<dyn Error>> {
+pub fn tui_start(program: Program, options: TuiOptions) -> Result<(), Box<dyn Error>> {
+    let audio = Audio::new(&options.audio, TIMER_RATE as u32)?;
+    let graphics = options.graphics;
+    let mut tui = Tui::new(options, audio);
+    tui.cpu.load_program(&program)?;
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+
+    // Ask for key release events where the terminal supports it
+    let key_releases = matches!(supports_keyboard_enhancement(), Ok(true));
+    if key_releases {
+        // Alternate keys make Shift+letter arrive as the uppercase letter
+        execute!(
+            stdout,
+            PushKeyboardEnhancementFlags(
+                KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                    | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                    | KeyboardEnhancementFlags::REPORT_ALTERNATE_KEYS
+            )
+        )?;
+    }
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
     // create app and run it
-    let tick_rate = Duration::from_millis(250);
-    let mut tui = Tui::new(debug);
-    tui.cpu.load_bin(binary, false);
+    let frame_time = Duration::from_micros(1_000_000 / TIMER_RATE);
+    tui.key_releases = key_releases;
     tui.executing = true;
-    let res = run_tui(&mut terminal, tui, tick_rate);
+    let res = run_tui(&mut terminal, &mut tui, frame_time);
+    tui.audio.finish()?;
 
     // restore terminal
+    if let Some(graphics) = graphics {
+        write!(terminal.backend_mut(), "{}", graphics::clear(graphics))?;
+    }
+    if key_releases {
+        execute!(terminal.backend_mut(), PopKeyboardEnhancementFlags)?;
+    }
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
@@ -205,24 +1055,78 @@ pub fn tui_start(binary: Vec<u8>, debug: bool) -> Result<(), Box<dyn Error>> {
 
 fn run_tui<B: Backend>(
     terminal: &mut Terminal<B>,
-    mut tui: Tui,
-    tick_rate: Duration,
+    tui: &mut Tui,
+    frame_time: Duration,
 ) -> io::Result<()> {
     let mut last_tick = Instant::now();
     loop {
-        terminal.draw(|f| ui(f, &mut tui))?;
+        terminal.draw(|f| ui(f, tui))?;
+        tui.draw_graphics()?;
+        tui.ring_bell()?;
 
-        let timeout = tick_rate
+        // Frames run at the timer rate, which also keeps audio fed
+        let timeout = frame_time
             .checked_sub(last_tick.elapsed())
             .unwrap_or_else(|| Duration::from_secs(0));
         if event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
+            let event = event::read()?;
+            // Screen is cleared on resize, draw image again
+            if let Event::Resize(_, _) = event {
+                tui.graphics_frame = None;
+            }
+            if let Event::Mouse(mouse) = event {
+                tui.handle_mouse(mouse);
+            }
+            if let Event::Key(key) = event {
+                if tui.input_mode == InputMode::Game && key.code != KeyCode::F(2) {
+                    tui.handle_game_key(key);
+                    continue;
+                }
+                // Debugger only acts on presses
+                if key.kind == KeyEventKind::Release {
+                    continue;
+                }
+                if tui.prompt.is_some() {
+                    tui.handle_prompt_key(key.code);
+                    continue;
+                }
+                if tui.mem_edit.is_some() {
+                    tui.handle_edit_key(key.code);
+                    continue;
+                }
+                if key.code == KeyCode::F(2) {
+                    tui.toggle_input_mode();
+                    continue;
+                }
                 match key.code {
                     KeyCode::Down => tui.handle_next(),
                     KeyCode::Up => tui.handle_prev(),
+                    KeyCode::Left => if let Window::Memory = tui.current_window { tui.move_mem_cursor(-1) },
+                    KeyCode::Right => if let Window::Memory = tui.current_window { tui.move_mem_cursor(1) },
+                    KeyCode::Enter => tui.start_edit(),
+                    KeyCode::Char('g') => tui.open_prompt(PromptKind::Goto),
+                    KeyCode::Char('S') => if let Window::Memory = tui.current_window { tui.open_prompt(PromptKind::Sprite) },
+                    KeyCode::Char('z') => tui.undo_edit(),
+                    KeyCode::Char('X') => tui.open_prompt(PromptKind::ExportImage),
+                    KeyCode::Char('I') => tui.open_prompt(PromptKind::ImportImage),
                     KeyCode::Tab => tui.cycle_window(),
-                    KeyCode::Char('p') => tui.executing = !tui.executing,
+                    KeyCode::Char('p') => tui.toggle_execution(),
                     KeyCode::Char('n') => tui.next_cycle(),
+                    KeyCode::Char('o') => tui.step_over(),
+                    KeyCode::Char('u') => tui.step_out(),
+                    KeyCode::Char('r') => tui.run_to_cursor(),
+                    KeyCode::Char('f') => tui.follow_pc(),
+                    KeyCode::Char('m') => tui.cycle_render_mode(),
+                    KeyCode::PageDown => if let Window::Disassembly = tui.current_window { tui.scroll_disassembly(16) },
+                    KeyCode::PageUp => if let Window::Disassembly = tui.current_window { tui.scroll_disassembly(-16) },
+                    KeyCode::Char('b') => tui.toggle_breakpoint(),
+                    KeyCode::Char('B') => tui.open_prompt(PromptKind::Breakpoint),
+                    KeyCode::Char('W') => tui.open_prompt(PromptKind::Watchpoint),
+                    KeyCode::Char('E') => tui.open_prompt(PromptKind::Events),
+                    KeyCode::Char('e') => tui.toggle_breakpoint_enabled(),
+                    KeyCode::Char('c') => tui.open_breakpoint_prompt(PromptKind::Condition),
+                    KeyCode::Char('h') => tui.open_breakpoint_prompt(PromptKind::HitCount),
+                    KeyCode::Char('l') => tui.open_breakpoint_prompt(PromptKind::LogMessage),
                     KeyCode::Char('q') => {return Ok(());}
                     _ => {}
                 }
@@ -230,7 +1134,10 @@ fn run_tui<B: Backend>(
             }
         }
 
-        if last_tick.elapsed() >= tick_rate {
+        tui.release_keys();
+        tui.run_timers();
+
+        if last_tick.elapsed() >= frame_time {
             tui.on_tick();
             last_tick = Instant::now();
         }
@@ -239,33 +1146,36 @@ fn run_tui<B: Backend>(
 
 fn cpu_view(tui: &Tui) -> Table<'static> {
     let selected_style = Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD);
-    let normal_style = Style::default().bg(Color::Blue).add_modifier(Modifier::BOLD);
-    let text_style = Style::default().bg(Color::Reset).add_modifier(Modifier::BOLD);
+    let normal_style = Style::default().bg(tui.theme.header).add_modifier(Modifier::BOLD);
+    let text_style = Style::default().fg(tui.theme.text).bg(tui.theme.background).add_modifier(Modifier::BOLD);
     let header_cells = ["Register", "Value"]
         .iter()
-        .map(|h| Cell::from(*h).style(Style::default().fg(Color::Black).add_modifier(Modifier::BOLD)));
+        .map(|h| Cell::from(*h).style(Style::default().fg(tui.theme.contrast).add_modifier(Modifier::BOLD)));
     let header = Row::new(header_cells)
         .style(normal_style)
         .height(1)
         .bottom_margin(0);
 
-    // Take 16 at a time
     let mut rows = Vec::new();
-    rows.push(Row::new(vec![Cell::from("PC:"), Cell::from(format!("{:X}", tui.cpu.pc))]).bottom_margin(1).style(text_style));
-    rows.push(Row::new(vec![Cell::from("I:"), Cell::from(format!("{:X}", tui.cpu.ir))]).bottom_margin(1).style(text_style));
-    rows.push(Row::new(vec![Cell::from("SP:"), Cell::from(format!("{:X}", tui.cpu.sp))]).bottom_margin(1).style(text_style));
-    rows.push(Row::new(vec![Cell::from("DT:"), Cell::from(format!("{:X}", tui.cpu.dt))]).bottom_margin(1).style(text_style));
-    rows.push(Row::new(vec![Cell::from("ST:"), Cell::from(format!("{:X}", tui.cpu.st))]).bottom_margin(1).style(text_style));
+    for reg in CPU_REGISTERS {
+        let cells = vec![Cell::from(format!("{}:", reg.name())), Cell::from(format!("{:X}", reg.read(&tui.cpu)))];
+        rows.push(Row::new(cells).bottom_margin(1).style(text_style));
+    }
     if tui.executing {
         rows.push(Row::new(vec![Cell::from("Running:"), Cell::from("")]).bottom_margin(1).style(text_style));
     } else {
         rows.push(Row::new(vec![Cell::from("Paused:"), Cell::from("")]).bottom_margin(1).style(text_style));
     }
 
+    let border_style = match tui.current_window {
+        Window::Cpu => Style::default().fg(tui.theme.focus),
+        _ => Style::default(),
+    };
+
 
     let t = Table::new(rows)
         .header(header)
-        .block(Block::default().borders(Borders::ALL).title("CPU"))
+        .block(Block::default().borders(Borders::ALL).title("CPU").border_style(border_style))
         .highlight_style(selected_style)
         .highlight_symbol(">> ")
         .widths(&[
@@ -278,11 +1188,11 @@ fn cpu_view(tui: &Tui) -> Table<'static> {
 fn register_view(tui: &Tui) -> Table<'static> {
     let registers = tui.cpu.get_registers();
     let selected_style = Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD);
-    let normal_style = Style::default().bg(Color::Blue).add_modifier(Modifier::BOLD);
-    let text_style = Style::default().bg(Color::Reset).add_modifier(Modifier::BOLD);
+    let normal_style = Style::default().bg(tui.theme.header).add_modifier(Modifier::BOLD);
+    let text_style = Style::default().fg(tui.theme.text).bg(tui.theme.background).add_modifier(Modifier::BOLD);
     let header_cells = ["Register", "Value"]
         .iter()
-        .map(|h| Cell::from(*h).style(Style::default().fg(Color::Black).add_modifier(Modifier::BOLD)));
+        .map(|h| Cell::from(*h).style(Style::default().fg(tui.theme.contrast).add_modifier(Modifier::BOLD)));
     let header = Row::new(header_cells)
         .style(normal_style)
         .height(1)
@@ -295,7 +1205,7 @@ fn register_view(tui: &Tui) -> Table<'static> {
     });
 
     let border_style = match tui.current_window {
-        Window::Registers => Style::default().fg(Color::Yellow),
+        Window::Registers => Style::default().fg(tui.theme.focus),
         _ => Style::default(),
     };
 
@@ -314,46 +1224,70 @@ fn register_view(tui: &Tui) -> Table<'static> {
 
 fn memory_view(tui: &Tui) -> Table<'static> {
     let memory = tui.cpu.get_memory();
-    let selected_style = Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD);
-    let normal_style = Style::default().bg(Color::Blue).add_modifier(Modifier::BOLD);
-    let text_style = Style::default().bg(Color::Reset).add_modifier(Modifier::BOLD);
+    let selected_style = Style::default().add_modifier(Modifier::BOLD);
+    let normal_style = Style::default().bg(tui.theme.header).add_modifier(Modifier::BOLD);
+    let text_style = Style::default().fg(tui.theme.text).bg(tui.theme.background).add_modifier(Modifier::BOLD);
 
     let header_cells = ["Address", "0x00", "0x01", "0x02", "0x03", "0x04", "0x05", "0x06",
                         "0x07", "0x08", "0x09", "0x0A", "0x0B", "0x0C", "0x0D", "0x0E", "0x0F"]
         .iter()
-        .map(|h| Cell::from(*h).style(Style::default().fg(Color::Black).add_modifier(Modifier::BOLD)));
+        .map(|h| Cell::from(*h).style(Style::default().fg(tui.theme.contrast).add_modifier(Modifier::BOLD)));
     let header = Row::new(header_cells)
         .style(normal_style)
         .height(1)
         .bottom_margin(0);
 
+    let pc = tui.cpu.pc as usize;
+    let ir = tui.cpu.ir as usize;
+    let editing = tui.mem_edit.is_some();
+
     // Take 16 at a time
     let mut rows = Vec::new();
     let mut cells = Vec::new();
     for (idx, x) in memory.into_iter().enumerate() {
         if idx % 16 == 0 {
             if idx != 0 {
-                let height = x.to_string().chars().filter(|c| *c == '\n').count() + 1;
-                rows.push(Row::new(cells).height(height as u16).bottom_margin(0).style(text_style));
+                rows.push(Row::new(cells).height(1).bottom_margin(0).style(text_style));
                 cells = Vec::new();
             }
             cells.push(Cell::from(format!("{:X}", idx))); // Address
         }
 
-        cells.push(Cell::from(format!("{:01$x}", x, 2))); // Value in address
+        // Highlight cursor, bytes of instruction at pc and byte at I
+        let style = if idx == tui.mem_cursor as usize && matches!(tui.current_window, Window::Memory) {
+            let bg = if editing { tui.theme.focus } else { tui.theme.cursor };
+            Style::default().fg(tui.theme.contrast).bg(bg)
+        } else if idx == pc || idx == pc + 1 {
+            Style::default().fg(tui.theme.contrast).bg(tui.theme.header)
+        } else if idx == ir {
+            Style::default().fg(tui.theme.contrast).bg(tui.theme.highlight)
+        } else {
+            Style::default()
+        };
+        let text = match (idx == tui.mem_cursor as usize, tui.mem_nibble) {
+            (true, Some(high)) => format!("{:x}_", high), // Half typed byte
+            _ => format!("{:01$x}", x, 2),
+        };
+        cells.push(Cell::from(text).style(style)); // Value in address
     }
     // Push last row
     rows.push(Row::new(cells).height(1).bottom_margin(1));
 
     let border_style = match tui.current_window {
-        Window::Memory => Style::default().fg(Color::Yellow),
+        Window::Memory => Style::default().fg(tui.theme.focus),
         _ => Style::default(),
     };
 
 
+    let title = match tui.mem_edit {
+        Some(EditMode::Hex) => format!("RAM - editing {:03X} (hex)", tui.mem_cursor),
+        Some(EditMode::Ascii) => format!("RAM - editing {:03X} (ascii)", tui.mem_cursor),
+        None => "RAM".to_string(),
+    };
+
     let t = Table::new(rows)
         .header(header)
-        .block(Block::default().borders(Borders::ALL).title("RAM").border_style(border_style))
+        .block(Block::default().borders(Borders::ALL).title(title).border_style(border_style))
         .highlight_style(selected_style)
         .highlight_symbol(">> ")
         .widths(&[
@@ -382,30 +1316,38 @@ fn memory_view(tui: &Tui) -> Table<'static> {
 fn instruction_view(tui: &Tui) -> List<'static> {
     let mut items: Vec<ListItem> = Vec::new();
     let next_inst = tui.cpu.fetch_no_increment();
+    let next_text = match next_inst {
+        Some(ins) => format!("{:04x} | {}", ins, decode(ins)),
+        None => "outside memory".to_string(),
+    };
+    let (label, bg) = match tui.break_hit {
+        Some(addr) if addr == tui.cpu.pc => ("Break:", tui.theme.breakpoint),
+        _ => ("Next: ", tui.theme.header),
+    };
     let next_line = Spans::from(Span::styled(
-        format!("Next:  {:6X} | {}", next_inst, decode(next_inst)),
+        format!("{}{}{:03X}  {}", label, breakpoint_marker(tui, tui.cpu.pc), tui.cpu.pc, next_text),
         Style::default().add_modifier(Modifier::BOLD),
     ));
-    let next_item = ListItem::new(next_line).style(Style::default().fg(Color::Black).bg(Color::Blue));
+    let next_item = ListItem::new(next_line).style(Style::default().fg(tui.theme.contrast).bg(bg));
     items.push(next_item);
 
     let history: Vec<ListItem> = tui.cpu.get_history()
                                         .iter()
                                         .enumerate()
                                         .rev()
-                                        .map(|(idx, i)| {
+                                        .map(|(idx, (addr, i))| {
                                             let hex = format!("{:01$x}", i,4);
                                             let line =  Spans::from(Span::styled(
-                                                format!("{:5}  {} | {}", idx, hex, decode(*i)),
+                                                format!("{:5}{}{:03X}  {} | {}", idx, breakpoint_marker(tui, *addr), addr, hex, decode(*i)),
                                                 Style::default().add_modifier(Modifier::BOLD),
                                             ));
-                                            ListItem::new(line).style(Style::default().fg(Color::White).bg(Color::Reset))
+                                            ListItem::new(line).style(Style::default().fg(tui.theme.text).bg(tui.theme.background))
                                         })
                                         .collect();
 
     items.extend(history);
     let border_style = match tui.current_window {
-        Window::Instructions => Style::default().fg(Color::Yellow),
+        Window::Instructions => Style::default().fg(tui.theme.focus),
         _ => Style::default(),
     };
 
@@ -415,13 +1357,230 @@ fn instruction_view(tui: &Tui) -> List<'static> {
         .block(Block::default().borders(Borders::ALL).title("List").border_style(border_style))
         .highlight_style(
             Style::default()
-                .bg(Color::LightGreen)
+                .bg(tui.theme.highlight)
                 .add_modifier(Modifier::BOLD),
         )
         .highlight_symbol(">> ");
     return list;
 }
 
+// Disassemble memory around the cursor, one row per two bytes
+fn disassembly_view(tui: &Tui, height: u16) -> List<'static> {
+    let memory = tui.cpu.get_memory();
+    let cursor = tui.disasm_address();
+    let rows = height.saturating_sub(2) as usize;
+
+    // Start half a screen above cursor, keeping the cursor's alignment
+    let start = cursor.saturating_sub((rows / 2) as u16 * 2);
+    let start = start - (start % 2) + (cursor % 2);
+
+    let mut items: Vec<ListItem> = Vec::new();
+    let mut address = start as usize;
+    while items.len() < rows && address + 1 < memory.len() {
+        if let Some(name) = tui.symbols.get(address as u16) {
+            items.push(ListItem::new(format!("{}:", name)).style(Style::default().fg(tui.theme.label)));
+        }
+
+        let ins = (memory[address] as u16) << 8 | memory[address + 1] as u16;
+        let mut text = format!("{}{:03X}  {:02X} {:02X}  {}", breakpoint_marker(tui, address as u16), address, memory[address], memory[address + 1], decode(ins));
+        if let Some(name) = branch_target(ins).and_then(|target| tui.symbols.get(target)) {
+            text.push_str(&format!("  ; {}", name));
+        }
+
+        let mut style = Style::default();
+        if address as u16 == tui.cpu.pc {
+            let bg = if tui.break_hit.is_some() { tui.theme.breakpoint } else { tui.theme.header };
+            style = style.fg(tui.theme.contrast).bg(bg).add_modifier(Modifier::BOLD);
+        }
+        if address as u16 == cursor && !tui.disasm_follow {
+            style = style.add_modifier(Modifier::REVERSED);
+        }
+        items.push(ListItem::new(text).style(style));
+        address += 2;
+    }
+
+    let border_style = match tui.current_window {
+        Window::Disassembly => Style::default().fg(tui.theme.focus),
+        _ => Style::default(),
+    };
+    let title = if tui.disasm_follow { "Disassembly (following PC)" } else { "Disassembly" };
+    return List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title).border_style(border_style));
+}
+
+fn breakpoint_marker(tui: &Tui, address: u16) -> &'static str {
+    if tui.debugger.has_breakpoint(address) {
+        " ● "
+    } else {
+        "   "
+    }
+}
+
+fn breakpoint_view(tui: &Tui) -> List<'static> {
+    let mut items: Vec<ListItem> = tui.debugger.get_breakpoints()
+        .iter()
+        .map(|bp| {
+            let symbol = if bp.is_logpoint() { "◆" } else { "●" };
+            let (marker, style) = match (bp.enabled, bp.triggered && tui.break_hit.is_some()) {
+                (_, true) => (symbol, Style::default().fg(tui.theme.contrast).bg(tui.theme.breakpoint)),
+                (true, false) => (symbol, Style::default().fg(tui.theme.breakpoint)),
+                (false, false) => ("○", Style::default().fg(tui.theme.muted)),
+            };
+            let mut text = match bp.address {
+                Some(addr) => format!("{} {:03X}", marker, addr),
+                None => format!("{}   *", marker),
+            };
+            if let Some(condition) = &bp.condition {
+                text.push_str(&format!(" if {}", condition.source));
+            }
+            match bp.hit_target {
+                Some(target) => text.push_str(&format!(" [{}/{}]", bp.hits, target)),
+                None => text.push_str(&format!(" [{}]", bp.hits)),
+            }
+            if let Some(template) = &bp.log_message {
+                text.push_str(&format!(" log \"{}\"", template.source));
+            }
+            ListItem::new(text).style(style)
+        })
+        .collect();
+
+    let watchpoints = tui.debugger.get_watchpoints().iter().map(|wp| {
+        let style = match (wp.enabled, wp.triggered && !tui.executing) {
+            (_, true) => Style::default().fg(tui.theme.contrast).bg(tui.theme.watchpoint),
+            (true, false) => Style::default().fg(tui.theme.watchpoint),
+            (false, false) => Style::default().fg(tui.theme.muted),
+        };
+        let range = match wp.start == wp.end {
+            true => format!("{:03X}", wp.start),
+            false => format!("{:03X}-{:03X}", wp.start, wp.end),
+        };
+        let marker = if wp.enabled { "◉" } else { "○" };
+        ListItem::new(format!("{} {} {} [{}]", marker, range, wp.kind.name(), wp.hits)).style(style)
+    });
+    items.extend(watchpoints);
+
+    let events = tui.debugger.events.names();
+    if !events.is_empty() {
+        items.push(ListItem::new(format!("⚑ on {}", events.join(", "))).style(Style::default().fg(tui.theme.label)));
+    }
+
+    let border_style = match tui.current_window {
+        Window::Breakpoints => Style::default().fg(tui.theme.focus),
+        _ => Style::default(),
+    };
+
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Breakpoints").border_style(border_style))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD))
+        .highlight_symbol(">> ");
+    return list;
+}
+
+// Label for address, nearest preceding symbol with offset if not exact
+fn symbol_label(tui: &Tui, address: u16) -> String {
+    match tui.symbols.containing(address) {
+        Some((start, name)) if start == address => name.to_string(),
+        Some((start, name)) => format!("{}+{:X}", name, address - start),
+        None => String::new(),
+    }
+}
+
+fn stack_view(tui: &Tui) -> List<'static> {
+    let memory = tui.cpu.get_memory();
+    let mut items = vec![
+        ListItem::new(format!("#0 {:03X}  {}", tui.cpu.pc, symbol_label(tui, tui.cpu.pc)))
+            .style(Style::default().fg(tui.theme.contrast).bg(tui.theme.header)),
+    ];
+
+    // Innermost call first
+    for (idx, ret) in tui.cpu.get_stack().iter().rev().enumerate() {
+        let site = ret.wrapping_sub(2) as usize & 0x0fff;
+        let ins = (memory[site] as u16) << 8 | memory[(site + 1) & 0x0fff] as u16;
+        let callee = match ins & 0xf000 {
+            0x2000 => symbol_label(tui, ins & 0x0fff),
+            _ => String::new(),
+        };
+        items.push(ListItem::new(format!("#{} ret {:03X}  {:03X}: {}  {}", idx + 1, ret, site, decode(ins), callee)));
+    }
+
+    let border_style = match tui.current_window {
+        Window::Stack => Style::default().fg(tui.theme.focus),
+        _ => Style::default(),
+    };
+    return List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(format!("Call stack (SP {:X})", tui.cpu.sp)).border_style(border_style))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD))
+        .highlight_symbol(">> ");
+}
+
+fn log_view(tui: &Tui, height: u16) -> Paragraph<'static> {
+    // Show newest messages that fit the panel
+    let log = tui.debugger.get_log();
+    let lines: Vec<Spans> = log.iter()
+        .skip(log.len().saturating_sub(height.saturating_sub(2) as usize))
+        .map(|line| Spans::from(line.clone()))
+        .collect();
+    return Paragraph::new(lines)
+        .style(Style::default().bg(tui.theme.background).fg(tui.theme.text))
+        .block(Block::default().borders(Borders::ALL).title("Log"));
+}
+
+fn help_view(tui: &Tui) -> Paragraph<'static> {
+    let first_line = match (&tui.prompt, &tui.message) {
+        (Some(prompt), _) => {
+            let label = match prompt.kind {
+                PromptKind::Breakpoint => "Breakpoint <address> [if <condition>]",
+                PromptKind::Watchpoint => "Watchpoint <start>[-<end>] [r|w|rw]",
+                PromptKind::Goto => "Go to address",
+                PromptKind::Register(_) => "New value",
+                PromptKind::Sprite => "Sprite rows (e.g. #..#.... .####...)",
+                PromptKind::Events => "Break on (draw collision keywait sound clear smc stack)",
+                PromptKind::Condition(_) => "Condition",
+                PromptKind::HitCount(_) => "Break after hits",
+                PromptKind::LogMessage(_) => "Log message (empty to break)",
+                PromptKind::ExportImage => "Export <file> [all|<start>-<end>|<start>+<length>], .hex for Intel HEX",
+                PromptKind::ImportImage => "Import <file> [address], raw images at cursor by default",
+            };
+            Spans::from(Span::styled(format!("{}: {}_", label, prompt.input), Style::default().fg(tui.theme.focus)))
+        }
+        (None, Some(message)) => Spans::from(Span::styled(message.clone(), Style::default().fg(tui.theme.focus))),
+        (None, None) => Spans::from(""),
+    };
+    if tui.input_mode == InputMode::Game {
+        let keys: Vec<String> = (0..16u8)
+            .filter_map(|key| tui.keymap.char_for(key).map(|c| format!("{:X}={}", key, c)))
+            .collect();
+        let text = vec![
+            first_line,
+            Spans::from(Span::styled("Game input: keys go to the CHIP-8 keypad", Style::default().fg(tui.theme.highlight))),
+            Spans::from(match tui.key_releases {
+                true => "Key releases reported by terminal".to_string(),
+                false => format!("No key release events, keys held for {} ms", tui.key_hold.as_millis()),
+            }),
+            Spans::from("<F2>/<Esc> Back to debugger hotkeys"),
+            Spans::from(keys.join("  ")),
+        ];
+        return Paragraph::new(text)
+            .style(Style::default().bg(tui.theme.background).fg(tui.theme.text))
+            .block(Block::default().borders(Borders::ALL).title("Help - game input"))
+            .alignment(Alignment::Left)
+            .wrap(Wrap { trim: true });
+    }
+    let text = vec![
+        first_line,
+        Spans::from("<F2> Game input  <TAB> Switch window  <N> Step  <O> Step over  <U> Step out  <R> Run to cursor  <F> Follow PC  <P> Pause/Run  <M> Render mode  <Q> Quit"),
+        Spans::from("<B> Toggle breakpoint  <Shift-B> Breakpoint at address  <E> Enable/disable breakpoint"),
+        Spans::from("<C> Condition  <H> Hit count  <L> Logpoint message  <Shift-W> Watchpoint  <Shift-E> Event breakpoints"),
+        Spans::from("Registers: <Enter> Set value (while paused)  Call stack: <Enter> Show frame"),
+        Spans::from("RAM: <Enter> Edit (<Tab> hex/ascii, <Esc> done)  <G> Go to address  <Shift-S> Sprite rows  <Z> Undo edit  <Shift-X> Export  <Shift-I> Import"),
+    ];
+    return Paragraph::new(text)
+        .style(Style::default().bg(tui.theme.background).fg(tui.theme.text))
+        .block(Block::default().borders(Borders::ALL).title("Help"))
+        .alignment(Alignment::Left)
+        .wrap(Wrap { trim: true });
+}
+
 fn ui<B: Backend>(f: &mut Frame<B>, tui: &mut Tui) {
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
@@ -430,9 +1589,19 @@ fn ui<B: Backend>(f: &mut Frame<B>, tui: &mut Tui) {
 
     let data_chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Percentage(45), Constraint::Percentage(45), Constraint::Percentage(10)].as_ref())
+        .constraints([Constraint::Percentage(45), Constraint::Percentage(40), Constraint::Percentage(15)].as_ref())
         .split(chunks[0]);
 
+    let side_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(chunks[1]);
+
+    let display_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(75), Constraint::Percentage(25)].as_ref())
+        .split(side_chunks[0]);
+
     let data_chunks_upper = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Percentage(33), Constraint::Percentage(34), Constraint::Percentage(33)].as_ref())
@@ -457,46 +1626,91 @@ fn ui<B: Backend>(f: &mut Frame<B>, tui: &mut Tui) {
     f.render_stateful_widget(memory_view, data_chunks[1], &mut tui.memory_table_state);
 
 
-    // Hep
-    let text = vec![
-        Spans::from("<TAB> Switch window"),
-        Spans::from("<N> Step"),
-        Spans::from("<P> Pause/Run"),
-];
-    let help = Paragraph::new(text.clone())
-        .style(Style::default().bg(Color::Reset).fg(Color::White))
-        .block(Block::default().borders(Borders::ALL).title("Help"))
-        .alignment(Alignment::Left);
+    let side_chunks_lower = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(side_chunks[1]);
+
+    let debug_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(35), Constraint::Percentage(30), Constraint::Percentage(35)].as_ref())
+        .split(side_chunks_lower[1]);
+
+    // Disassembly
+    let disassembly_view = disassembly_view(tui, side_chunks_lower[0].height);
+    f.render_widget(disassembly_view, side_chunks_lower[0]);
+
+    // Breakpoints
+    let breakpoint_view = breakpoint_view(tui);
+    f.render_stateful_widget(breakpoint_view, debug_chunks[0], &mut tui.breakpoint_list_state);
+
+    // Call stack
+    let stack_view = stack_view(tui);
+    f.render_stateful_widget(stack_view, debug_chunks[1], &mut tui.stack_list_state);
+
+    // Log
+    let log_view = log_view(tui, debug_chunks[2].height);
+    f.render_widget(log_view, debug_chunks[2]);
+
+    // Help
+    let help = help_view(tui);
     f.render_widget(help, data_chunks[2]);
 
 
-    // Map cpu vbuf to canvas
-    let mut pixels = Vec::new();
-    for y in 0..32 {
-        for x in 0..64 {
-            if tui.cpu.read_vbuf(x, y) {
-                pixels.push(&true);
-            } else {
-                pixels.push(&false);
-            }
+    // Display as image where supported, characters when it does not fit
+    let inner = Block::default().borders(Borders::ALL).inner(display_chunks[0]);
+    tui.graphics_placement = tui.graphics
+        .and_then(|_| graphics::fit(64, 32, inner.width, inner.height, graphics::cell_size()))
+        .map(|(scale, x, y)| (inner.x + x, inner.y + y, scale));
+    let mut persistence = match tui.phosphor.mode() {
+        Persistence::Off => String::new(),
+        mode => format!(", {}", mode.name()),
+    };
+    // Visual beep when sound can not be heard
+    if tui.cpu.st > 0 && !tui.audio.has_device() {
+        persistence.push_str(" ♪");
+    }
+    // Known ROMs show title and author in place of the panel name
+    let name = match &tui.rom {
+        Some(rom) => rom.label(),
+        None => "Display".to_string(),
+    };
+    match (tui.graphics, tui.graphics_placement) {
+        (Some(graphics), Some(_)) => {
+            let title = format!("{} - {}{}", name, graphics.name(), persistence);
+            f.render_widget(Block::default().borders(Borders::ALL).title(title), display_chunks[0]);
+        }
+        _ => {
+            let title = format!("{} - {}{}", name, tui.render_mode.name(), persistence);
+            let fb = FrameBuffer::new(tui.display_levels(), tui.render_mode, &tui.theme)
+                .block(Block::default().borders(Borders::ALL).title(title));
+            f.render_widget(fb, display_chunks[0]);
         }
     }
-    let fb = FrameBuffer::new(pixels)
-        .block(Block::default().borders(Borders::ALL).title("Display"));
-    f.render_widget(fb, chunks[1]);
+
+    // Keypad
+    let title = match tui.cpu.key_wait {
+        Some(reg) => format!("Keypad - waiting V{:X}", reg),
+        None => "Keypad".to_string(),
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    tui.keypad_area = block.inner(display_chunks[1]);
+    let keypad = Keypad::new(tui.cpu.get_keys(), tui.cpu.key_poll, tui.cpu.key_wait.is_some(), &tui.theme).block(block);
+    f.render_widget(keypad, display_chunks[1]);
 }
 
 // Framebuffer object from CPU
 
-#[derive(Default)]
 struct FrameBuffer<'a> {
-    pixels: Vec<&'a bool>,
+    levels: Vec<f32>,
+    mode: RenderMode,
+    theme: &'a Theme,
     block: Option<Block<'a>>,
 }
 
 impl<'a> FrameBuffer<'a> {
-    fn new(pixels: Vec<&'a bool>) -> Self {
-        Self { pixels, ..Default::default() }
+    fn new(levels: Vec<f32>, mode: RenderMode, theme: &'a Theme) -> Self {
+        Self { levels, mode, theme, block: None }
     }
 
     fn block(mut self, block: Block<'a>) -> FrameBuffer<'a> {
@@ -515,45 +1729,99 @@ impl tui::widgets::Widget for FrameBuffer<'_> {
             None => area,
         };
 
-        let mut resizer = resize::new(
-            64,
-            32,
-            area.width as usize,
-            area.height as usize,
-            RGB8,
-            resize::Type::Point,
-        ).unwrap();
-
-        let mut dst = vec![RGB::new(0, 0, 0); (area.width * area.height) as usize];
-
-        let rgb_white = Color::White;
-
-        // Construct the framebuffer
-        let mut fb: Vec<RGB<u8>> = Vec::new();
-        for j in 0..32 {
-             for i in 0..64 {
-                 if *self.pixels[i as usize + j * 64 as usize] {
-                     fb.push(RGB::new(255, 255, 255));
-                 } else {
-                     fb.push(RGB::new(0, 0, 0));
-                 }
-             }
-         }
-
-        resizer.resize(&fb, &mut dst).unwrap();
-        let mut dst = dst.iter();
-            for j in area.y..area.y + area.height {
-                for i in area.x..area.x + area.width {
-                    let rgb = dst.next().unwrap();
-                    let r = rgb.r;
-                    let g = rgb.g;
-                    let b = rgb.b;
-                    match rgb {
-                        RGB { r: 0, g: 0, b: 0 } => buf.get_mut(i, j).set_bg(Color::Reset),
-                        RGB { r: 255, g: 255, b: 255 } => buf.get_mut(i, j).set_bg(Color::Indexed(15)),
-                        _ => buf.get_mut(i, j).set_bg(Color::Rgb(r, g, b)),
-                    };
+        let color = |level: f32| self.theme.blend(level);
+        let lines = render::render(&self.levels, 64, 32, self.mode, area.width as usize, area.height as usize);
+        for (j, line) in lines.iter().enumerate() {
+            for (i, cell) in line.iter().enumerate() {
+                buf.get_mut(area.x + i as u16, area.y + j as u16)
+                    .set_char(cell.ch)
+                    .set_fg(color(cell.fg))
+                    .set_bg(color(cell.bg));
+            }
+        }
+    }
+}
+
+// COSMAC VIP keypad layout
+const KEYPAD_LAYOUT: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// Area of key at row and column inside keypad area
+fn keypad_cell(area: Rect, row: u16, col: u16) -> Rect {
+    let width = area.width / 4;
+    let height = area.height / 4;
+    return Rect::new(area.x + col * width, area.y + row * height, width, height);
+}
+
+fn keypad_key_at(area: Rect, x: u16, y: u16) -> Option<u8> {
+    for (row, keys) in KEYPAD_LAYOUT.iter().enumerate() {
+        for (col, key) in keys.iter().enumerate() {
+            let cell = keypad_cell(area, row as u16, col as u16);
+            if x >= cell.x && x < cell.x + cell.width && y >= cell.y && y < cell.y + cell.height {
+                return Some(*key);
+            }
+        }
+    }
+    return None;
+}
+
+struct Keypad<'a> {
+    keys: [bool; 16],
+    polled: Option<u8>,
+    waiting: bool,
+    theme: &'a Theme,
+    block: Option<Block<'a>>,
+}
+
+impl<'a> Keypad<'a> {
+    fn new(keys: [bool; 16], polled: Option<u8>, waiting: bool, theme: &'a Theme) -> Self {
+        Self { keys, polled, waiting, theme, block: None }
+    }
+
+    fn block(mut self, block: Block<'a>) -> Keypad<'a> {
+        self.block = Some(block);
+        self
+    }
+}
+
+impl tui::widgets::Widget for Keypad<'_> {
+    fn render(mut self, area: Rect, buf: &mut tui::buffer::Buffer) {
+        let area = match self.block.take() {
+            Some(b) => {
+                let inner_area = b.inner(area);
+                b.render(area, buf);
+                inner_area
+            }
+            None => area,
+        };
+
+        let theme = self.theme;
+        for (row, keys) in KEYPAD_LAYOUT.iter().enumerate() {
+            for (col, key) in keys.iter().enumerate() {
+                let cell = keypad_cell(area, row as u16, col as u16);
+                if cell.width == 0 || cell.height == 0 {
+                    continue;
                 }
+                // Held keys are lit, key polled by the ROM or any key while waiting is outlined
+                let style = if self.keys[*key as usize] {
+                    Style::default().fg(theme.contrast).bg(theme.focus).add_modifier(Modifier::BOLD)
+                } else if self.polled == Some(*key) {
+                    Style::default().fg(theme.contrast).bg(theme.label)
+                } else if self.waiting {
+                    Style::default().fg(theme.contrast).bg(theme.watchpoint)
+                } else {
+                    Style::default().fg(theme.text).bg(theme.muted)
+                };
+                // Leave one column and row gap between keys
+                let face = Rect::new(cell.x, cell.y, cell.width.saturating_sub(1).max(1), cell.height.saturating_sub(1).max(1));
+                buf.set_style(face, style);
+                let label = format!("{:X}", key);
+                buf.set_string(face.x + face.width / 2, face.y + face.height / 2, label, style);
             }
+        }
     }
 }